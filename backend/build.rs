@@ -0,0 +1,29 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Lists the version of every migration embedded into the binary, so the
+// readiness check can tell which of them the database has not run yet.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("Failed to read migrations directory")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("up.sql").exists())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // diesel's version is the name's leading timestamp without separators
+            let timestamp = name.split('_').next()?.to_string();
+            Some(timestamp.chars().filter(|c| c.is_ascii_digit()).collect())
+        })
+        .collect();
+    versions.sort();
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
+    fs::write(
+        Path::new(&out_dir).join("migration_versions.rs"),
+        format!("const MIGRATION_VERSIONS: &[&str] = &{:?};\n", versions),
+    )
+    .expect("Failed to write migration versions");
+}
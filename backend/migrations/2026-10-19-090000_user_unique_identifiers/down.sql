@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX "UQ_user_username";
+DROP INDEX "UQ_user_email";
+CREATE UNIQUE INDEX "UQ_email_username" ON "user" ("email", "username");
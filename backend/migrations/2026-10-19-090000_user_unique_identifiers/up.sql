@@ -0,0 +1,36 @@
+-- Accounts that would collide once emails are normalized have to be merged or
+-- renamed by hand; say which they are rather than fail on the index
+DO $$
+DECLARE
+    duplicates TEXT;
+BEGIN
+    SELECT string_agg(format('email %L (%s accounts)', email, accounts), ', ')
+    INTO duplicates
+    FROM (
+        SELECT lower(trim(email)) AS email, count(*) AS accounts
+        FROM "user"
+        GROUP BY lower(trim(email))
+        HAVING count(*) > 1
+    ) AS emails;
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Duplicate user emails, resolve them before migrating: %', duplicates;
+    END IF;
+
+    SELECT string_agg(format('username %L (%s accounts)', username, accounts), ', ')
+    INTO duplicates
+    FROM (
+        SELECT username, count(*) AS accounts
+        FROM "user"
+        GROUP BY username
+        HAVING count(*) > 1
+    ) AS usernames;
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Duplicate usernames, resolve them before migrating: %', duplicates;
+    END IF;
+END $$;
+
+UPDATE "user" SET email = lower(trim(email));
+
+DROP INDEX "UQ_email_username";
+CREATE UNIQUE INDEX "UQ_user_email" ON "user" ("email");
+CREATE UNIQUE INDEX "UQ_user_username" ON "user" ("username");
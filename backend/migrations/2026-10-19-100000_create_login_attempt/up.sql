@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE "login_attempt" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    identifier TEXT NOT NULL,
+    user_id UUID,
+    ip_address TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX "IX_login_attempt_identifier" ON "login_attempt" ("identifier", "created_at");
+CREATE INDEX "IX_login_attempt_user_id" ON "login_attempt" ("user_id", "created_at");
+CREATE INDEX "IX_login_attempt_ip_address" ON "login_attempt" ("ip_address", "created_at");
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "user"
+    DROP COLUMN email_verification_sent_at,
+    DROP COLUMN email_verification_token,
+    DROP COLUMN pending_email;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE "user"
+    ADD COLUMN pending_email TEXT,
+    ADD COLUMN email_verification_token TEXT,
+    ADD COLUMN email_verification_sent_at TIMESTAMP;
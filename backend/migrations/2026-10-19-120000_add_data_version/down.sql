@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "submission" DROP COLUMN data_version;
+ALTER TABLE "benchmark" DROP COLUMN data_version;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "benchmark" ADD COLUMN data_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE "submission" ADD COLUMN data_version INTEGER NOT NULL DEFAULT 1;
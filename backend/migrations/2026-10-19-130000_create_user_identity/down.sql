@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE "oauth_state";
+DROP TABLE "user_identity";
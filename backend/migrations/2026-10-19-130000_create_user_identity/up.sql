@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE "user_identity" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    email TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE UNIQUE INDEX "UQ_user_identity_provider_subject" ON "user_identity" ("provider", "subject");
+CREATE INDEX "IX_user_identity_user_id" ON "user_identity" ("user_id");
+
+CREATE TABLE "oauth_state" (
+    state TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    user_id UUID REFERENCES "user" (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE "two_factor_challenge";
+DROP TABLE "recovery_code";
+
+ALTER TABLE "user"
+    DROP COLUMN totp_last_used_step,
+    DROP COLUMN totp_enabled,
+    DROP COLUMN totp_secret,
+    DROP COLUMN role;
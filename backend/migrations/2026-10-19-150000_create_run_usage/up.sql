@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE "run_usage" (
+    user_id UUID NOT NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    run_count INTEGER NOT NULL DEFAULT 0,
+    cpu_ms BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, day)
+);
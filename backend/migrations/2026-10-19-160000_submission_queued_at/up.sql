@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "submission" ADD COLUMN queued_at TIMESTAMP;
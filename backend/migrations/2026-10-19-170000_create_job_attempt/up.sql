@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "job_attempt" (
+    id UUID PRIMARY KEY,
+    submission_id UUID NOT NULL REFERENCES "submission" (id) ON DELETE CASCADE,
+    attempt INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    dispatched_at TIMESTAMP NOT NULL,
+    deadline TIMESTAMP NOT NULL,
+    finished_at TIMESTAMP,
+    UNIQUE (submission_id, attempt)
+);
+
+-- What the reaper scans for
+CREATE INDEX job_attempt_open_deadline_idx ON "job_attempt" (deadline) WHERE finished_at IS NULL;
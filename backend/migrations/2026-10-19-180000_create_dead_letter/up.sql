@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE "dead_letter" (
+    id UUID PRIMARY KEY,
+    -- A message dead-lettered twice, e.g. redelivered to the consumer, is kept once
+    message_id TEXT NOT NULL UNIQUE,
+    submission_id UUID,
+    attempt INTEGER,
+    payload TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    retries INTEGER NOT NULL,
+    dead_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "job_attempt" DROP COLUMN priority;
+ALTER TABLE "job_attempt" DROP COLUMN run_class;
+ALTER TABLE "benchmark" DROP COLUMN contest;
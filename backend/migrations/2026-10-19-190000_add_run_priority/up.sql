@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Runs against a contest benchmark go ahead of everything else
+ALTER TABLE "benchmark" ADD COLUMN contest BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Kept with the attempt, so that a retry is queued the same way
+ALTER TABLE "job_attempt" ADD COLUMN run_class TEXT NOT NULL DEFAULT 'practice';
+ALTER TABLE "job_attempt" ADD COLUMN priority SMALLINT NOT NULL DEFAULT 0;
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE "scratch_run" (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    language TEXT NOT NULL,
+    code TEXT NOT NULL,
+    stdin TEXT NOT NULL,
+    status TEXT NOT NULL,
+    stdout TEXT,
+    stderr TEXT,
+    message TEXT,
+    error TEXT,
+    exec_duration INTEGER NOT NULL DEFAULT 0,
+    mem_usage INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    deadline TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX scratch_run_user_id_idx ON "scratch_run" (user_id);
+-- What the reaper purges by
+CREATE INDEX scratch_run_expires_at_idx ON "scratch_run" (expires_at);
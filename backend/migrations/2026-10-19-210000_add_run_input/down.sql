@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "scratch_run" DROP COLUMN env;
+ALTER TABLE "scratch_run" DROP COLUMN args;
+ALTER TABLE "job_attempt" DROP COLUMN env;
+ALTER TABLE "job_attempt" DROP COLUMN args;
+ALTER TABLE "job_attempt" DROP COLUMN stdin;
+ALTER TABLE "submission" DROP COLUMN env;
+ALTER TABLE "submission" DROP COLUMN args;
+ALTER TABLE "submission" DROP COLUMN stdin;
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- What the program is run with: standard input, arguments after the language's
+-- command, and NAME=value environment variables
+ALTER TABLE "submission" ADD COLUMN stdin TEXT NOT NULL DEFAULT '';
+ALTER TABLE "submission" ADD COLUMN args TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE "submission" ADD COLUMN env TEXT[] NOT NULL DEFAULT '{}';
+
+-- Copied onto each attempt as it is dispatched, so a run can be reproduced after
+-- the submission has been edited
+ALTER TABLE "job_attempt" ADD COLUMN stdin TEXT NOT NULL DEFAULT '';
+ALTER TABLE "job_attempt" ADD COLUMN args TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE "job_attempt" ADD COLUMN env TEXT[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE "scratch_run" ADD COLUMN args TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE "scratch_run" ADD COLUMN env TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE "submission_file";
+ALTER TABLE "submission" DROP COLUMN entrypoint;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Set on submissions that are a tree of files rather than the one in `code`
+ALTER TABLE "submission" ADD COLUMN entrypoint TEXT;
+
+CREATE TABLE "submission_file" (
+    submission_id UUID NOT NULL REFERENCES "submission" (id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    content TEXT NOT NULL,
+    executable BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (submission_id, path)
+);
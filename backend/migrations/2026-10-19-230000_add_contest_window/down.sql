@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "benchmark" DROP COLUMN contest_closes_at;
+ALTER TABLE "benchmark" DROP COLUMN contest_opens_at;
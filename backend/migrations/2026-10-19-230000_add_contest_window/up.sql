@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Contest runs only go first while the contest is open
+ALTER TABLE "benchmark" ADD COLUMN contest_opens_at TIMESTAMP;
+ALTER TABLE "benchmark" ADD COLUMN contest_closes_at TIMESTAMP;
@@ -0,0 +1,634 @@
+use crate::api_error::ApiError;
+use crate::config::AmqpConfig;
+use crate::db;
+use crate::jobs;
+use crate::metrics;
+use crate::models::{DeadLetter, JobCancel, JobStatus, SubmissionWorker};
+use crate::request_id;
+use crate::telemetry;
+use chrono::Utc;
+use futures::lock::Mutex;
+use futures::StreamExt;
+use lapin::message::Delivery;
+use lapin::types::{AMQPValue, LongString};
+use lapin::{
+    options::*, publisher_confirm::Confirmation, types::FieldTable, BasicProperties, Channel,
+    Connection, ConnectionProperties, ExchangeKind,
+};
+use lazy_static::lazy_static;
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How many delayed retries a rejected job has been through
+const RETRIES_HEADER: &str = "x-retries";
+// Persistent, so queued jobs survive a broker restart
+const PERSISTENT: u8 = 2;
+
+lazy_static! {
+    // Every publish goes out on this one channel, opened on first use and again
+    // whenever it is found closed
+    static ref PUBLISHER: Mutex<Option<Publisher>> = Mutex::new(None);
+}
+
+struct Publisher {
+    url: String,
+    // Held so the channel's connection stays open
+    _conn: Connection,
+    channel: Channel,
+}
+
+/// Hands a job to the worker pool.
+pub async fn publish_job(config: &AmqpConfig, job: &SubmissionWorker) -> Result<(), ApiError> {
+    let span = tracing::info_span!(
+        "amqp.publish",
+        otel.name = %format!("{} publish", config.jobs_exchange),
+        otel.kind = "producer",
+        otel.status_code = Empty,
+        messaging.system = "rabbitmq",
+        messaging.destination.name = %config.jobs_exchange,
+        messaging.rabbitmq.destination.routing_key = %config.jobs_routing_key,
+        messaging.message.conversation_id = %job.id,
+    );
+
+    let published = publish(config, job).instrument(span.clone()).await;
+    metrics::count_publish(&config.jobs_exchange, published.is_ok());
+    if published.is_err() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    published
+}
+
+async fn publish(config: &AmqpConfig, job: &SubmissionWorker) -> Result<(), ApiError> {
+    let payload = serde_json::to_vec(job)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize job: {}", e)))?;
+
+    send_job(config, &message_id(job), job.priority, &payload).await
+}
+
+/// Tells the workers to drop that attempt's job, or stop its run.
+pub async fn publish_cancel(config: &AmqpConfig, id: Uuid, attempt: i32) -> Result<(), ApiError> {
+    let span = tracing::info_span!(
+        "amqp.publish",
+        otel.name = %format!("{} publish", config.jobs_exchange),
+        otel.kind = "producer",
+        otel.status_code = Empty,
+        messaging.system = "rabbitmq",
+        messaging.destination.name = %config.jobs_exchange,
+        messaging.rabbitmq.destination.routing_key = %config.cancel_routing_key,
+        messaging.message.conversation_id = %id,
+    );
+
+    let payload = serde_json::to_vec(&JobCancel { id, attempt })
+        .map_err(|e| ApiError::internal(format!("Failed to serialize cancellation: {}", e)))?;
+    let published = send(
+        config,
+        &config.cancel_routing_key,
+        &format!("{}.{}.cancel", id, attempt),
+        &payload,
+        BasicProperties::default(),
+    )
+    .instrument(span.clone())
+    .await;
+    metrics::count_publish(&config.jobs_exchange, published.is_ok());
+    if published.is_err() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    published
+}
+
+/// Identifies one dispatch of a run, so that its copies can be told apart from
+/// those of other attempts.
+pub fn message_id(job: &SubmissionWorker) -> String {
+    format!("{}.{}", job.id, job.attempt)
+}
+
+async fn send_job(
+    config: &AmqpConfig,
+    message_id: &str,
+    priority: u8,
+    payload: &[u8],
+) -> Result<(), ApiError> {
+    let properties = BasicProperties::default().with_priority(priority.min(config.max_priority));
+
+    send(
+        config,
+        &config.jobs_routing_key,
+        message_id,
+        payload,
+        properties,
+    )
+    .await
+}
+
+/// The shared publishing channel, connected first if need be. It is in confirm
+/// mode, so a publish completes once the broker has taken the message.
+async fn publisher(config: &AmqpConfig) -> Result<Channel, ApiError> {
+    let mut publisher = PUBLISHER.lock().await;
+
+    if let Some(open) = publisher.as_ref() {
+        if open.url == config.url && open.channel.status().connected() {
+            return Ok(open.channel.clone());
+        }
+    }
+
+    let conn = Connection::connect(&config.url, ConnectionProperties::default())
+        .await
+        .map_err(amqp_error)?;
+    let channel = conn.create_channel().await.map_err(amqp_error)?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await
+        .map_err(amqp_error)?;
+
+    *publisher = Some(Publisher {
+        url: config.url.clone(),
+        _conn: conn,
+        channel: channel.clone(),
+    });
+
+    Ok(channel)
+}
+
+async fn send(
+    config: &AmqpConfig,
+    routing_key: &str,
+    message_id: &str,
+    payload: &[u8],
+    properties: BasicProperties,
+) -> Result<(), ApiError> {
+    let channel = publisher(config).await?;
+
+    // Carried through the worker onto its status updates, see `run_status_consumer`
+    let mut headers = FieldTable::default();
+    telemetry::inject_amqp(&Span::current(), &mut headers);
+    request_id::inject_amqp(&mut headers);
+
+    let confirm = channel
+        .basic_publish(
+            &config.jobs_exchange,
+            routing_key,
+            BasicPublishOptions::default(),
+            payload,
+            properties
+                .with_message_id(message_id.into())
+                .with_delivery_mode(PERSISTENT)
+                .with_headers(headers),
+        )
+        .await
+        .map_err(amqp_error)?
+        .await
+        .map_err(amqp_error)?;
+
+    match confirm {
+        Confirmation::Ack(_) => Ok(()),
+        _ => Err(ApiError::internal(
+            "Message was not accepted by the broker".to_string(),
+        )),
+    }
+}
+
+/// Whether messages can be published, connecting the shared channel if it is not.
+pub async fn check_connection(config: &AmqpConfig) -> Result<(), ApiError> {
+    publisher(config).await?;
+
+    Ok(())
+}
+
+/// Applies the worker's status updates to submissions for as long as the server
+/// runs, reconnecting whenever the broker goes away.
+pub async fn consume_job_status(config: AmqpConfig) {
+    loop {
+        match run_status_consumer(&config).await {
+            Ok(()) => warn!("Job status consumer stopped, reconnecting"),
+            Err(e) => error!("Job status consumer failed: {}", e),
+        }
+        actix_rt::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn run_status_consumer(config: &AmqpConfig) -> Result<(), lapin::Error> {
+    let conn = Connection::connect(&config.url, ConnectionProperties::default()).await?;
+    let channel = conn.create_channel().await?;
+
+    channel
+        // must match the worker's declaration, which is direct and non-durable
+        .exchange_declare(
+            &config.status_exchange,
+            ExchangeKind::Direct,
+            ExchangeDeclareOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_declare(
+            &config.status_queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_bind(
+            &config.status_queue,
+            &config.status_exchange,
+            &config.status_routing_key,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = channel
+        .basic_consume(
+            &config.status_queue,
+            "backend",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    info!("Consuming job status updates from {}", config.status_queue);
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+        let headers = delivery.properties.headers().as_ref();
+
+        let span = tracing::info_span!(
+            "amqp.consume",
+            otel.name = %format!("{} process", config.status_queue),
+            otel.kind = "consumer",
+            messaging.system = "rabbitmq",
+            messaging.destination.name = %config.status_exchange,
+            messaging.rabbitmq.destination.routing_key = %config.status_routing_key,
+            request_id = %request_id::extract_amqp(headers).unwrap_or_default(),
+        );
+        span.set_parent(telemetry::extract_amqp(headers));
+
+        match serde_json::from_slice::<JobStatus>(&delivery.data) {
+            Ok(status) => {
+                let id = status.id;
+                let applied = db::run(move || jobs::apply_status(status))
+                    .instrument(span.clone())
+                    .await;
+                match applied {
+                    Err(e) if e.code.status().is_server_error() => {
+                        // Back on the queue, to be applied once the database is back
+                        span.in_scope(|| {
+                            error!("Failed to apply status for submission {}: {}", id, e)
+                        });
+                        actix_rt::time::sleep(RECONNECT_DELAY).await;
+                        delivery
+                            .nack(BasicNackOptions {
+                                requeue: true,
+                                ..BasicNackOptions::default()
+                            })
+                            .await?;
+                        continue;
+                    }
+                    // Nothing about the update will change on a retry, so drop it
+                    Err(e) => {
+                        span.in_scope(|| warn!("Discarding status for submission {}: {}", id, e))
+                    }
+                    Ok(()) => {}
+                }
+            }
+            // Nothing will ever make a malformed message parse, so drop it
+            Err(e) => warn!("Discarding malformed job status: {}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+/// Retries jobs the worker rejected, with growing delays, and parks them in the
+/// `dead_letter` table once the retries are used up. Runs for as long as the
+/// server does, like `consume_job_status`.
+pub async fn consume_dead_letters(config: AmqpConfig) {
+    loop {
+        match run_dead_letter_consumer(&config).await {
+            Ok(()) => warn!("Dead letter consumer stopped, reconnecting"),
+            Err(e) => error!("Dead letter consumer failed: {}", e),
+        }
+        actix_rt::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn run_dead_letter_consumer(config: &AmqpConfig) -> Result<(), lapin::Error> {
+    let conn = Connection::connect(&config.url, ConnectionProperties::default()).await?;
+    let channel = conn.create_channel().await?;
+    // Each rejected job is only acked once its retry is safely with the broker
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+
+    declare_job_topology(&channel, config).await?;
+
+    let mut consumer = channel
+        .basic_consume(
+            &config.dead_letter_queue,
+            "backend",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    info!("Handling rejected jobs from {}", config.dead_letter_queue);
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+        let headers = delivery.properties.headers().as_ref();
+        let retries = header_int(headers, RETRIES_HEADER).unwrap_or(0);
+
+        let span = tracing::info_span!(
+            "amqp.dead_letter",
+            messaging.system = "rabbitmq",
+            messaging.destination.name = %config.dead_letter_exchange,
+            messaging.message.id = %delivery.properties.message_id().as_ref().map(|id| id.as_str()).unwrap_or_default(),
+            request_id = %request_id::extract_amqp(headers).unwrap_or_default(),
+        );
+        span.set_parent(telemetry::extract_amqp(headers));
+
+        match retry_delay(config, &delivery.data, retries) {
+            Some(delay) => {
+                let mut retry_headers = headers.cloned().unwrap_or_default();
+                retry_headers.insert(RETRIES_HEADER.into(), AMQPValue::LongInt(retries + 1));
+
+                let confirm = channel
+                    .basic_publish(
+                        &config.retry_exchange,
+                        &retry_queue(config, delay),
+                        BasicPublishOptions::default(),
+                        &delivery.data,
+                        delivery.properties.clone().with_headers(retry_headers),
+                    )
+                    .await?
+                    .await?;
+                if !confirm.is_ack() {
+                    // Back on the queue, to be retried once the broker takes it
+                    span.in_scope(|| {
+                        error!("The broker did not accept the retry of a rejected job")
+                    });
+                    actix_rt::time::sleep(RECONNECT_DELAY).await;
+                    delivery
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            ..BasicNackOptions::default()
+                        })
+                        .await?;
+                    continue;
+                }
+                metrics::count_dead_letter("retried");
+                span.in_scope(|| info!("Retrying rejected job in {}s", delay));
+            }
+            None => {
+                let letter = dead_letter(&delivery, retries);
+                let parked = db::run(move || DeadLetter::record(letter))
+                    .instrument(span.clone())
+                    .await;
+                if let Err(e) = parked {
+                    // Back on the queue, to be parked once the database is back
+                    span.in_scope(|| error!("Failed to park rejected job: {}", e));
+                    actix_rt::time::sleep(RECONNECT_DELAY).await;
+                    delivery
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            ..BasicNackOptions::default()
+                        })
+                        .await?;
+                    continue;
+                }
+                metrics::count_dead_letter("parked");
+                span.in_scope(|| warn!("Parked rejected job after {} retries", retries));
+            }
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+/// Declares the queues and exchanges jobs go through, so that none is published
+/// before its queue exists. The worker declares the jobs queue too, with the
+/// same `AMQP_*` settings, so either may start first.
+pub async fn declare_topology(config: &AmqpConfig) -> Result<(), ApiError> {
+    let channel = publisher(config).await?;
+
+    declare_job_topology(&channel, config)
+        .await
+        .map_err(amqp_error)
+}
+
+/// The jobs queue along with its dead-letter and retry plumbing. Declared again
+/// whenever the dead letter consumer connects, in case the broker lost them.
+async fn declare_job_topology(channel: &Channel, config: &AmqpConfig) -> Result<(), lapin::Error> {
+    let durable_exchange = ExchangeDeclareOptions {
+        durable: true,
+        ..ExchangeDeclareOptions::default()
+    };
+    let durable_queue = QueueDeclareOptions {
+        durable: true,
+        ..QueueDeclareOptions::default()
+    };
+
+    for exchange in [
+        &config.jobs_exchange,
+        &config.dead_letter_exchange,
+        &config.retry_exchange,
+    ] {
+        channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Direct,
+                durable_exchange,
+                FieldTable::default(),
+            )
+            .await?;
+    }
+
+    let mut jobs_arguments = FieldTable::default();
+    jobs_arguments.insert(
+        "x-dead-letter-exchange".into(),
+        long_string(&config.dead_letter_exchange),
+    );
+    jobs_arguments.insert(
+        "x-max-priority".into(),
+        AMQPValue::LongInt(config.max_priority as i32),
+    );
+    channel
+        .queue_declare(&config.jobs_queue, durable_queue, jobs_arguments)
+        .await?;
+    channel
+        .queue_bind(
+            &config.jobs_queue,
+            &config.jobs_exchange,
+            &config.jobs_routing_key,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    // Dead-lettering keeps the routing key, so the jobs one is what arrives here
+    channel
+        .queue_declare(
+            &config.dead_letter_queue,
+            durable_queue,
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_bind(
+            &config.dead_letter_queue,
+            &config.dead_letter_exchange,
+            &config.jobs_routing_key,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    // One queue per delay; messages wait out its TTL, then expire back onto the
+    // jobs exchange
+    for &delay in &config.retry_delays_seconds {
+        let queue = retry_queue(config, delay);
+        let mut arguments = FieldTable::default();
+        arguments.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongLongInt((delay * 1000) as i64),
+        );
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            long_string(&config.jobs_exchange),
+        );
+        arguments.insert(
+            "x-dead-letter-routing-key".into(),
+            long_string(&config.jobs_routing_key),
+        );
+
+        channel
+            .queue_declare(&queue, durable_queue, arguments)
+            .await?;
+        channel
+            .queue_bind(
+                &queue,
+                &config.retry_exchange,
+                &queue,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// How long a rejected job waits before its next try, or `None` to park it: once
+/// its retries are used up, and straight away when it is not a job at all, as no
+/// retry would make it parse.
+fn retry_delay(config: &AmqpConfig, payload: &[u8], retries: i32) -> Option<u64> {
+    serde_json::from_slice::<SubmissionWorker>(payload).ok()?;
+
+    config.retry_delays_seconds.get(retries as usize).copied()
+}
+
+/// Named after its delay, so changing the delays declares new queues instead of
+/// clashing with the arguments of existing ones.
+fn retry_queue(config: &AmqpConfig, delay: u64) -> String {
+    format!("{}.retry.{}s", config.jobs_queue, delay)
+}
+
+fn dead_letter(delivery: &Delivery, retries: i32) -> DeadLetter {
+    let properties = &delivery.properties;
+    let payload = String::from_utf8_lossy(&delivery.data).into_owned();
+    let job = serde_json::from_str::<SubmissionWorker>(&payload).ok();
+
+    // The broker records each dead-lettering in `x-death`, latest first
+    let reason = properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get("x-death"))
+        .and_then(AMQPValue::as_array)
+        .and_then(|deaths| deaths.as_slice().first())
+        .and_then(AMQPValue::as_field_table)
+        .and_then(|death| death.inner().get("reason"))
+        .and_then(header_string)
+        .unwrap_or_else(|| "unknown".to_string());
+
+    DeadLetter {
+        id: Uuid::new_v4(),
+        message_id: properties
+            .message_id()
+            .as_ref()
+            .map(|id| id.to_string())
+            .or_else(|| job.as_ref().map(message_id))
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        submission_id: job.as_ref().map(|job| job.id),
+        attempt: job.as_ref().map(|job| job.attempt),
+        payload,
+        reason,
+        retries,
+        dead_at: Utc::now().naive_utc(),
+    }
+}
+
+fn header_int(headers: Option<&FieldTable>, name: &str) -> Option<i32> {
+    match headers?.inner().get(name)? {
+        AMQPValue::LongInt(value) => Some(*value),
+        AMQPValue::LongLongInt(value) => i32::try_from(*value).ok(),
+        _ => None,
+    }
+}
+
+fn header_string(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::LongString(value) => String::from_utf8(value.as_bytes().to_vec()).ok(),
+        AMQPValue::ShortString(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn long_string(value: &str) -> AMQPValue {
+    AMQPValue::LongString(LongString::from(value))
+}
+
+fn amqp_error(error: lapin::Error) -> ApiError {
+    ApiError::internal(format!("AMQP error: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rejected_jobs_are_retried_until_the_delays_run_out() {
+        let config = AmqpConfig {
+            retry_delays_seconds: vec![5, 30],
+            ..AmqpConfig::default()
+        };
+        let job = json!({
+            "id": Uuid::new_v4(),
+            "code": "print(1)",
+            "language": "python",
+            "attempt": 1,
+            "priority": 3,
+            "deadline": Utc::now(),
+        })
+        .to_string();
+
+        let delays: Vec<_> = (0..3)
+            .map(|retries| retry_delay(&config, job.as_bytes(), retries))
+            .collect();
+        assert_eq!(delays, [Some(5), Some(30), None]);
+
+        assert_eq!(retry_delay(&config, b"{\"id\": 42}", 0), None);
+    }
+}
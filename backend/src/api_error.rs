@@ -1,21 +1,206 @@
-use actix_web::http::StatusCode;
+use crate::request_id;
+use actix_web::http::{header, StatusCode};
 use actix_web::{HttpResponse, ResponseError};
-use diesel::result::Error as DieselError;
-use serde::Deserialize;
-use serde_json::json;
+use diesel::result::{DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fmt;
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize)]
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// What went wrong, as a stable identifier clients can branch on. The wire
+/// names are part of the API: add new codes rather than renaming existing ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidToken,
+    Unauthenticated,
+    InvalidCredentials,
+    InvalidTwoFactorCode,
+    OauthDenied,
+    Forbidden,
+    TwoFactorRequired,
+    NotFound,
+    Conflict,
+    UniqueViolation,
+    ForeignKeyViolation,
+    PreconditionFailed,
+    ValidationFailed,
+    CheckViolation,
+    EmailNotVerified,
+    RateLimited,
+    QuotaExceeded,
+    InternalError,
+    UpstreamError,
+    ServiceUnavailable,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::InvalidToken => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthenticated
+            | ErrorCode::InvalidCredentials
+            | ErrorCode::InvalidTwoFactorCode
+            | ErrorCode::OauthDenied => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::TwoFactorRequired => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict | ErrorCode::UniqueViolation | ErrorCode::ForeignKeyViolation => {
+                StatusCode::CONFLICT
+            }
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::ValidationFailed
+            | ErrorCode::CheckViolation
+            | ErrorCode::EmailNotVerified => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::RateLimited | ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// The serialized name, for logging.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::InvalidToken => "invalid_token",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::InvalidCredentials => "invalid_credentials",
+            ErrorCode::InvalidTwoFactorCode => "invalid_two_factor_code",
+            ErrorCode::OauthDenied => "oauth_denied",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::TwoFactorRequired => "two_factor_required",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::UniqueViolation => "unique_violation",
+            ErrorCode::ForeignKeyViolation => "foreign_key_violation",
+            ErrorCode::PreconditionFailed => "precondition_failed",
+            ErrorCode::ValidationFailed => "validation_failed",
+            ErrorCode::CheckViolation => "check_violation",
+            ErrorCode::EmailNotVerified => "email_not_verified",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::UpstreamError => "upstream_error",
+            ErrorCode::ServiceUnavailable => "service_unavailable",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The body of every error response: an RFC 7807 problem document, extended
+/// with the error code, any field errors and the request ID.
+#[derive(Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug)]
 pub struct ApiError {
-    pub status_code: u16,
+    pub code: ErrorCode,
     pub message: String,
+    /// Problems with individual request fields, keyed by field name.
+    pub errors: Option<BTreeMap<String, Vec<String>>>,
+    pub retry_after: Option<u64>,
 }
 
 impl ApiError {
-    pub fn new(status_code: u16, message: String) -> ApiError {
+    pub fn new(code: ErrorCode, message: String) -> ApiError {
         ApiError {
-            status_code,
+            code,
             message,
+            errors: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn internal(message: String) -> ApiError {
+        ApiError::new(ErrorCode::InternalError, message)
+    }
+
+    pub fn validation(errors: BTreeMap<String, Vec<String>>) -> ApiError {
+        ApiError {
+            errors: Some(errors),
+            ..ApiError::new(ErrorCode::ValidationFailed, "Validation failed".to_string())
+        }
+    }
+
+    pub fn precondition_failed() -> ApiError {
+        ApiError::new(
+            ErrorCode::PreconditionFailed,
+            "Resource has been modified since it was fetched".to_string(),
+        )
+    }
+
+    pub fn too_many_requests(retry_after: u64, message: String) -> ApiError {
+        ApiError::new(ErrorCode::RateLimited, message).with_retry_after(retry_after)
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> ApiError {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    fn from_database_error(kind: DatabaseErrorKind, info: &dyn DatabaseErrorInformation) -> Self {
+        // Postgres puts the offending values in `details` as well, so only the
+        // column names are passed on
+        let columns = info.details().and_then(key_columns);
+
+        match kind {
+            DatabaseErrorKind::UniqueViolation => ApiError {
+                errors: columns.map(|columns| field_errors(&columns, "is already taken")),
+                ..ApiError::new(
+                    ErrorCode::UniqueViolation,
+                    "A record with the same values already exists".to_string(),
+                )
+            },
+            // Deleting a row that others still point at
+            DatabaseErrorKind::ForeignKeyViolation
+                if info
+                    .details()
+                    .is_some_and(|details| details.contains("is still referenced")) =>
+            {
+                ApiError::new(
+                    ErrorCode::ForeignKeyViolation,
+                    "The record is still referenced by other records".to_string(),
+                )
+            }
+            DatabaseErrorKind::ForeignKeyViolation => ApiError {
+                errors: columns.map(|columns| field_errors(&columns, "refers to a missing record")),
+                ..ApiError::new(
+                    ErrorCode::ForeignKeyViolation,
+                    "A referenced record does not exist".to_string(),
+                )
+            },
+            // diesel 1.x has no kind for CHECK constraints, but of the violations
+            // that name a constraint they are the only ones left over
+            _ if info.constraint_name().is_some() => {
+                let column = info
+                    .constraint_name()
+                    .zip(info.table_name())
+                    .and_then(|(constraint, table)| check_column(constraint, table));
+                ApiError {
+                    errors: column.map(|column| field_errors(&column, "is not an allowed value")),
+                    ..ApiError::new(ErrorCode::CheckViolation, "Validation failed".to_string())
+                }
+            }
+            _ => ApiError::internal(format!("Database error: {}", info.message())),
         }
     }
 }
@@ -29,28 +214,87 @@ impl fmt::Display for ApiError {
 impl From<DieselError> for ApiError {
     fn from(error: DieselError) -> ApiError {
         match error {
-            DieselError::DatabaseError(_, err) => ApiError::new(409, err.message().to_string()),
-            DieselError::NotFound => ApiError::new(404, "Record not found".to_string()),
-            err => ApiError::new(500, format!("Diesel error: {}", err)),
+            DieselError::DatabaseError(kind, info) => {
+                ApiError::from_database_error(kind, info.as_ref())
+            }
+            DieselError::NotFound => {
+                ApiError::new(ErrorCode::NotFound, "Record not found".to_string())
+            }
+            err => ApiError::internal(format!("Diesel error: {}", err)),
         }
     }
 }
 
 impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
     fn error_response(&self) -> HttpResponse {
-        let status_code = match StatusCode::from_u16(self.status_code) {
-            Ok(status_code) => status_code,
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        let status_code = self.code.status();
+        let request_id = request_id::current();
 
-        let message = match status_code.as_u16() < 500 {
-            true => self.message.clone(),
-            false => {
-                error!("{}", self.message);
+        let detail = match status_code.is_server_error() {
+            false => self.message.clone(),
+            true => {
+                tracing::error!(
+                    request_id = request_id.as_deref().unwrap_or_default(),
+                    error.code = %self.code,
+                    http.response.status_code = status_code.as_u16(),
+                    "{}",
+                    self.message
+                );
                 "Internal server error".to_string()
             }
         };
 
-        HttpResponse::build(status_code).json(json!({ "message": message }))
+        let mut response = HttpResponse::build(status_code);
+        response.content_type(PROBLEM_JSON);
+        if let Some(retry_after) = self.retry_after {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(Problem {
+            problem_type: format!("urn:dune:error:{}", self.code),
+            title: status_code
+                .canonical_reason()
+                .unwrap_or("Error")
+                .to_string(),
+            status: status_code.as_u16(),
+            detail,
+            code: self.code,
+            errors: self.errors.clone(),
+            // Quoted by users in bug reports, so the matching log lines can be found
+            request_id,
+        })
     }
 }
+
+fn field_errors(columns: &str, reason: &str) -> BTreeMap<String, Vec<String>> {
+    columns
+        .split(", ")
+        .map(|column| {
+            (
+                column.trim_matches('"').to_string(),
+                vec![reason.to_string()],
+            )
+        })
+        .collect()
+}
+
+/// The column list from Postgres' "Key (a, b)=(...) ..." violation details.
+fn key_columns(details: &str) -> Option<String> {
+    let columns = details.strip_prefix("Key (")?.split(")=(").next()?;
+
+    Some(columns.to_string())
+}
+
+/// Postgres names column check constraints `<table>_<column>_check`.
+fn check_column(constraint: &str, table: &str) -> Option<String> {
+    let column = constraint
+        .strip_prefix(table)?
+        .strip_prefix('_')?
+        .strip_suffix("_check")?;
+
+    Some(column.to_string())
+}
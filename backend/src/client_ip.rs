@@ -0,0 +1,186 @@
+use crate::config::Config;
+use actix_web::http::header::X_FORWARDED_FOR;
+use actix_web::{web, HttpRequest};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A single address, or a CIDR range such as `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{:?} is not an IP address or CIDR range", value);
+        let (network, prefix) = match value.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network = IpAddr::from_str(network.trim())
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(invalid)?,
+            None => max_prefix,
+        };
+
+        Ok(IpRange { network, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The address the request came from, for throttling and logs. That is the
+/// peer's, unless the peer is one of `server.trusted_proxies`: then it is the
+/// nearest address in `X-Forwarded-For` that is not a trusted proxy too. The
+/// header is ignored from anyone else, since a client can put anything there.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let peer = match req.peer_addr() {
+        Some(peer) => peer.ip().to_canonical(),
+        None => return "unknown".to_string(),
+    };
+    let trusted = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.server.trusted_proxies.as_slice())
+        .unwrap_or_default();
+
+    forwarded_for(req, peer, trusted).to_string()
+}
+
+fn forwarded_for(req: &HttpRequest, peer: IpAddr, trusted: &[IpRange]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    // Each proxy appends the address it got the request from, so the entries
+    // are read from the right, where the trusted ones are
+    let hops: Vec<&str> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        match IpAddr::from_str(hop) {
+            Ok(ip) if is_trusted(ip.to_canonical()) => client = ip.to_canonical(),
+            Ok(ip) => return ip.to_canonical(),
+            // Nothing further left can be told apart from what a client wrote
+            Err(_) => break,
+        }
+    }
+
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::net::SocketAddr;
+
+    fn from(peer: &str, forwarded: Option<&str>, trusted: &[&str]) -> String {
+        let mut config = Config::default();
+        config.server.trusted_proxies =
+            trusted.iter().map(|range| range.parse().unwrap()).collect();
+
+        let mut req = TestRequest::default()
+            .peer_addr(SocketAddr::new(peer.parse().unwrap(), 4000))
+            .app_data(web::Data::new(config));
+        if let Some(forwarded) = forwarded {
+            req = req.insert_header((X_FORWARDED_FOR, forwarded));
+        }
+
+        client_ip(&req.to_http_request())
+    }
+
+    #[test]
+    fn forwarding_headers_count_only_from_trusted_proxies() {
+        assert_eq!(
+            from("203.0.113.7", Some("198.51.100.1"), &[]),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            from("203.0.113.7", Some("198.51.100.1"), &["10.0.0.0/8"]),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            from("10.1.2.3", Some("198.51.100.1"), &["10.0.0.0/8"]),
+            "198.51.100.1"
+        );
+
+        // Whatever the client sent ahead of the proxies' own entries is ignored
+        assert_eq!(
+            from(
+                "10.1.2.3",
+                Some("192.0.2.66, 198.51.100.1, 10.9.9.9"),
+                &["10.0.0.0/8"]
+            ),
+            "198.51.100.1"
+        );
+        assert_eq!(
+            from("10.1.2.3", Some("garbage, 10.9.9.9"), &["10.0.0.0/8"]),
+            "10.9.9.9"
+        );
+        assert_eq!(from("10.1.2.3", None, &["10.1.2.3"]), "10.1.2.3");
+        assert_eq!(
+            from("::ffff:10.1.2.3", Some("2001:db8::1"), &["10.1.2.3"]),
+            "2001:db8::1"
+        );
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        for invalid in ["", "10.0.0.0/33", "::/129", "10.0.0/8", "example.com"] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+
+        let range: IpRange = "192.168.0.0/16".parse().unwrap();
+        assert!(range.contains("192.168.40.2".parse().unwrap()));
+        assert!(!range.contains("192.169.0.1".parse().unwrap()));
+        let any: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+        assert!(!any.contains("::1".parse().unwrap()));
+    }
+}
@@ -1,32 +1,252 @@
-use crate::api_error::ApiError;
-use diesel::pg::PgConnection;
-use diesel::r2d2::ConnectionManager;
-use lazy_static::lazy_static;
-use r2d2;
-use std::env;
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::DatabaseConfig;
+use crate::metrics;
+use actix_web::web;
+use diesel::connection::{AnsiTransactionManager, SimpleConnection};
+use diesel::deserialize::{Queryable, QueryableByName};
+use diesel::pg::{Pg, PgConnection, PgQueryBuilder};
+use diesel::prelude::*;
+use diesel::query_builder::{AsQuery, QueryBuilder, QueryFragment, QueryId};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection};
+use diesel::sql_types::{HasSqlType, Int4, Nullable};
+use diesel::ConnectionResult;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::Span;
 
-pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
-pub type DbConnection = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
+pub type Pool = r2d2::Pool<ConnectionManager<InstrumentedConnection>>;
+pub type DbConnection = r2d2::PooledConnection<ConnectionManager<InstrumentedConnection>>;
 
 embed_migrations!();
 
-lazy_static! {
-    static ref POOL: Pool = {
-        let db_url = env::var("DATABASE_URL").expect("Database url not set");
-        let manager = ConnectionManager::<PgConnection>::new(db_url);
-        Pool::new(manager).expect("Failed to create db pool")
-    };
+include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+
+table! {
+    __diesel_schema_migrations (version) {
+        version -> VarChar,
+        run_on -> Timestamp,
+    }
 }
 
-pub fn init() {
+// Lets optimistic-concurrency filters read `data_version = coalesce(expected, data_version)`,
+// which only constrains the row when the client sent a version
+sql_function!(fn coalesce(x: Nullable<Int4>, y: Int4) -> Int4);
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+pub fn init(config: &DatabaseConfig) {
     info!("Initializing DB connection");
 
-    lazy_static::initialize(&POOL);
-    let conn = connection().expect("Failed to get DB connection");
+    // On a connection of its own, so the statement timeout does not apply
+    let conn = InstrumentedConnection::establish(&config.url).expect("Failed to connect to DB");
     embedded_migrations::run(&conn).unwrap();
+
+    let manager = ConnectionManager::<InstrumentedConnection>::new(config.url.as_str());
+    let pool = Pool::builder()
+        .max_size(config.max_connections)
+        .min_idle(config.min_idle)
+        .connection_timeout(Duration::from_secs(config.connection_timeout_seconds))
+        .connection_customizer(Box::new(SessionSettings {
+            statement_timeout: Duration::from_secs(config.statement_timeout_seconds),
+        }))
+        .build(manager)
+        .expect("Failed to create db pool");
+
+    if POOL.set(pool).is_err() {
+        panic!("DB pool initialized twice");
+    }
+}
+
+/// Runs blocking database work on the blocking thread pool instead of the actix
+/// worker, which would otherwise stop serving its other requests until the
+/// queries finish. The caller's span is carried over, so queries and log lines
+/// stay attached to the request.
+pub async fn run<F, T>(f: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = Span::current();
+
+    web::block(move || span.in_scope(f))
+        .await
+        .map_err(|e| ApiError::internal(format!("Blocking task failed: {}", e)))?
 }
 
 pub fn connection() -> Result<DbConnection, ApiError> {
     POOL.get()
-        .map_err(|e| ApiError::new(500, format!("Failed getting db connection: {}", e)))
+        .ok_or_else(|| ApiError::internal("DB pool is not initialized".to_string()))?
+        .get()
+        .map_err(pool_timeout)
+}
+
+/// Like `connection`, but gives up after `timeout` instead of the pool's
+/// configured wait, for callers that must answer quickly.
+pub fn connection_within(timeout: Duration) -> Result<DbConnection, ApiError> {
+    POOL.get()
+        .ok_or_else(|| ApiError::internal("DB pool is not initialized".to_string()))?
+        .get_timeout(timeout)
+        .map_err(pool_timeout)
+}
+
+// r2d2 only fails a checkout once it has waited out the timeout
+fn pool_timeout(error: r2d2::Error) -> ApiError {
+    ApiError::new(
+        ErrorCode::ServiceUnavailable,
+        format!("Failed getting db connection: {}", error),
+    )
+}
+
+/// Applied to every connection the pool opens.
+#[derive(Debug)]
+struct SessionSettings {
+    /// Zero disables the limit.
+    statement_timeout: Duration,
+}
+
+impl CustomizeConnection<InstrumentedConnection, diesel::r2d2::Error> for SessionSettings {
+    fn on_acquire(&self, conn: &mut InstrumentedConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!(
+            "SET statement_timeout = {}",
+            self.statement_timeout.as_millis()
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+pub fn pool_state() -> Option<(r2d2::State, u32)> {
+    POOL.get().map(|pool| (pool.state(), pool.max_size()))
+}
+
+/// Embedded migrations the database has not recorded as run.
+pub fn pending_migrations(conn: &InstrumentedConnection) -> Result<Vec<&'static str>, ApiError> {
+    let applied: HashSet<String> = __diesel_schema_migrations::table
+        .select(__diesel_schema_migrations::version)
+        .load(conn)?
+        .into_iter()
+        .collect();
+
+    Ok(MIGRATION_VERSIONS
+        .iter()
+        .filter(|version| !applied.contains(**version))
+        .copied()
+        .collect())
+}
+
+/// A `PgConnection` that records how long every statement takes. The pool hands
+/// these out, so queries are timed without the models having to do anything.
+pub struct InstrumentedConnection(PgConnection);
+
+impl SimpleConnection for InstrumentedConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        timed(query, || self.0.batch_execute(query))
+    }
+}
+
+impl Connection for InstrumentedConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        PgConnection::establish(database_url).map(InstrumentedConnection)
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        timed(query, || self.0.execute(query))
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    where
+        T: AsQuery,
+        T::Query: QueryFragment<Pg> + QueryId,
+        Pg: HasSqlType<T::SqlType>,
+        U: Queryable<T::SqlType, Pg>,
+    {
+        let query = source.as_query();
+        timed(&sql_of(&query), || self.0.query_by_index(query))
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    where
+        T: QueryFragment<Pg> + QueryId,
+        U: QueryableByName<Pg>,
+    {
+        timed(&sql_of(source), || self.0.query_by_name(source))
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        timed(&sql_of(source), || self.0.execute_returning_count(source))
+    }
+
+    fn transaction_manager(&self) -> &Self::TransactionManager {
+        self.0.transaction_manager()
+    }
+}
+
+fn sql_of<T: QueryFragment<Pg>>(query: &T) -> String {
+    let mut builder = PgQueryBuilder::new();
+    match query.to_sql(&mut builder) {
+        Ok(()) => builder.finish(),
+        Err(_) => String::new(),
+    }
+}
+
+fn timed<T>(sql: &str, run: impl FnOnce() -> T) -> T {
+    let (statement, table) = describe(sql);
+    let span = tracing::info_span!(
+        "db.query",
+        otel.name = %format!("{} {}", statement, table).trim_end(),
+        otel.kind = "client",
+        db.system = "postgresql",
+        db.operation = %statement,
+        db.sql.table = %table,
+        db.statement = %sql,
+    );
+
+    let started = Instant::now();
+    let result = span.in_scope(run);
+    metrics::observe_query(&statement, &table, started.elapsed().as_secs_f64());
+
+    result
+}
+
+/// Reduces a statement to its verb and main table, e.g. `("select", "user")`,
+/// which is as specific as a metric label can get without exploding.
+fn describe(sql: &str) -> (String, String) {
+    let words: Vec<&str> = sql
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("--"))
+        .flat_map(str::split_whitespace)
+        .collect();
+    let statement = words
+        .first()
+        .map(|word| word.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let keyword = match statement.as_str() {
+        "select" | "delete" => "FROM",
+        "insert" => "INTO",
+        "update" => "UPDATE",
+        _ => return (statement, String::new()),
+    };
+
+    let table = words
+        .iter()
+        .position(|word| word.eq_ignore_ascii_case(keyword))
+        .and_then(|index| words.get(index + 1))
+        .map(|word| {
+            word.trim_matches(|c: char| c == '"' || c == '(')
+                .split(['"', '(', '.'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .filter(|table| table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or_default();
+
+    (statement, table)
 }
@@ -0,0 +1,279 @@
+use crate::api_error::{ApiError, ErrorCode, Problem};
+use crate::config::Config;
+use crate::db;
+use crate::handlers::auth_handler::AdminUser;
+use crate::handlers::responses::Deleted;
+use crate::jobs::{self, JobDispatcher};
+use crate::metrics;
+use crate::models::{
+    Benchmark, ContestUpdate, DeadLetter, JobAttempt, RoleUpdate, RunInput, Submission,
+    SubmissionFile, SubmissionWorker, User, ATTEMPT_DISPATCHED, STATUS_FAILED, STATUS_TIMED_OUT,
+};
+use crate::repos::{BenchmarkRepo, UserRepo};
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use uuid::Uuid;
+
+#[utoipa::path(
+    tag = "admin",
+    operation_id = "update_user_role",
+    params(
+        ("id" = String, Path, format = Uuid, description = "User ID"),
+    ),
+    request_body = RoleUpdate,
+    responses(
+        (status = 200, description = "The user with their new role", body = User),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not an administrator, or two-factor authentication is off", body = Problem),
+        (status = 409, description = "Administrators cannot change their own role", body = Problem),
+        (status = 422, description = "Validation failed", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[put("/admin/users/{id}/role/")]
+async fn update_role(
+    id: web::Path<Uuid>,
+    role: web::Json<RoleUpdate>,
+    users: web::Data<dyn UserRepo>,
+    admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if id == admin.0.id {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "Administrators cannot change their own role".to_string(),
+        ));
+    }
+
+    let role = role.into_inner().role;
+    let users = users.into_inner();
+    let user = db::run(move || users.set_role(id, &role)).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Marks a benchmark as a contest, whose runs the worker pool takes first while
+/// it is open, or takes the mark away.
+#[utoipa::path(
+    tag = "admin",
+    operation_id = "update_benchmark_contest",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Benchmark ID"),
+    ),
+    request_body = ContestUpdate,
+    responses(
+        (status = 200, description = "The updated benchmark", body = Benchmark),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not an administrator, or two-factor authentication is off", body = Problem),
+        (status = 404, description = "No such benchmark", body = Problem),
+        (status = 422, description = "A contest without a window, or a window without a contest", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[put("/admin/benchmarks/{id}/contest/")]
+async fn update_contest(
+    id: web::Path<Uuid>,
+    update: web::Json<ContestUpdate>,
+    benchmarks: web::Data<dyn BenchmarkRepo>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let update = update.into_inner();
+    update.validate()?;
+    let benchmarks = benchmarks.into_inner();
+    let benchmark = db::run(move || benchmarks.set_contest(id, update)).await?;
+
+    Ok(HttpResponse::Ok().json(benchmark))
+}
+
+/// Jobs the worker kept rejecting, newest first.
+#[utoipa::path(
+    tag = "admin",
+    operation_id = "list_dead_letters",
+    responses(
+        (status = 200, description = "Parked jobs", body = [DeadLetter]),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not an administrator, or two-factor authentication is off", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[get("/admin/dead-letters/")]
+async fn find_dead_letters(_admin: AdminUser) -> Result<HttpResponse, ApiError> {
+    let letters = db::run(DeadLetter::find_all).await?;
+
+    Ok(HttpResponse::Ok().json(letters))
+}
+
+/// Runs a parked job again as a new attempt, with a fresh deadline, and stops
+/// tracking it. The run is queued again even if it timed out in the meantime,
+/// but not once it has a result, was cancelled or was dispatched again.
+#[utoipa::path(
+    tag = "admin",
+    operation_id = "requeue_dead_letter",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Dead letter ID"),
+    ),
+    responses(
+        (status = 200, description = "The job that was requeued", body = DeadLetter),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not an administrator, or two-factor authentication is off", body = Problem),
+        (status = 404, description = "No such dead letter", body = Problem),
+        (status = 409, description = "The run has moved on since this job was parked", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/admin/dead-letters/{id}/requeue/")]
+async fn requeue_dead_letter(
+    id: web::Path<Uuid>,
+    config: web::Data<Config>,
+    jobs: web::Data<dyn JobDispatcher>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let timeout_seconds = config.jobs.timeout_seconds;
+    let moved_on = || {
+        ApiError::new(
+            ErrorCode::Conflict,
+            "The run has moved on since this job was parked; discard it instead".to_string(),
+        )
+    };
+
+    let (letter, submission, files, next) = db::run(move || {
+        let letter = DeadLetter::find(id)?;
+        let parked = match (letter.submission_id, letter.attempt) {
+            (Some(submission_id), Some(attempt)) => JobAttempt::latest(submission_id)?
+                .filter(|latest| latest.attempt == attempt)
+                .ok_or_else(moved_on)?,
+            _ => return Err(moved_on()),
+        };
+
+        // The worker gave up on the job, so its attempt will not report back
+        let closed = match parked.status.as_str() {
+            ATTEMPT_DISPATCHED => parked.finish(STATUS_FAILED)?,
+            status => status == STATUS_TIMED_OUT,
+        };
+        if !closed {
+            return Err(moved_on());
+        }
+        let submission = Submission::revive(parked.submission_id)?.ok_or_else(moved_on)?;
+        metrics::count_status(&submission.status);
+
+        // Run as it was when it was parked
+        let next = JobAttempt::start(
+            submission.id,
+            timeout_seconds,
+            &parked.run_class,
+            parked.priority,
+            RunInput::from(&parked),
+        )?;
+        let files = SubmissionFile::find_by_submission(submission.id)?;
+
+        Ok((letter, submission, files, next))
+    })
+    .await?;
+
+    if let Err(e) = jobs
+        .dispatch(SubmissionWorker::new(&submission, files, &next))
+        .await
+    {
+        jobs::abandon(next).await?;
+        return Err(e);
+    }
+    db::run(move || DeadLetter::delete(id)).await?;
+
+    Ok(HttpResponse::Ok().json(letter))
+}
+
+#[utoipa::path(
+    tag = "admin",
+    operation_id = "discard_dead_letter",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Dead letter ID"),
+    ),
+    responses(
+        (status = 200, description = "Dead letter discarded", body = Deleted),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not an administrator, or two-factor authentication is off", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[delete("/admin/dead-letters/{id}/")]
+async fn discard_dead_letter(
+    id: web::Path<Uuid>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let num_deleted = db::run(move || DeadLetter::delete(id)).await?;
+
+    Ok(HttpResponse::Ok().json(Deleted {
+        deleted: num_deleted,
+    }))
+}
+
+pub fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(update_role);
+    cfg.service(update_contest);
+    cfg.service(find_dead_letters);
+    cfg.service(requeue_dead_letter);
+    cfg.service(discard_dead_letter);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::admin_routes;
+    use crate::handlers::test_support::{register, session, TestRepos};
+    use crate::handlers::user_routes;
+    use crate::models::ROLE_ADMIN;
+    use crate::repos::UserRepo;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    fn promote(repos: &TestRepos, id: Uuid, totp_enabled: bool) {
+        let mut user = repos.users.find(id).unwrap();
+        user.role = ROLE_ADMIN.to_string();
+        user.totp_enabled = totp_enabled;
+        repos.users.insert(user);
+    }
+
+    #[actix_web::test]
+    async fn role_changes_need_an_admin_with_two_factor() {
+        let repos = TestRepos::default();
+        let app =
+            test::init_service(repos.app().configure(user_routes).configure(admin_routes)).await;
+
+        let mut accounts = Vec::new();
+        for username in ["alice", "bob"] {
+            let res = test::call_service(&app, register(username).to_request()).await;
+            let cookie = session(&res);
+            let user: Value = test::read_body_json(res).await;
+            let id: Uuid = user["id"].as_str().unwrap().parse().unwrap();
+            accounts.push((cookie, id));
+        }
+        let (cookie, admin_id) = accounts[0].clone();
+        let (_, user_id) = accounts[1];
+
+        let update_role = || {
+            TestRequest::put()
+                .uri(&format!("/admin/users/{}/role/", user_id))
+                .cookie(cookie.clone())
+                .set_json(json!({ "role": "admin" }))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, update_role()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["code"], "forbidden");
+
+        promote(&repos, admin_id, false);
+        let res = test::call_service(&app, update_role()).await;
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["code"], "two_factor_required");
+
+        promote(&repos, admin_id, true);
+        let user: Value = test::call_and_read_body_json(&app, update_role()).await;
+        assert_eq!(user["role"], "admin");
+        assert_eq!(repos.users.find(user_id).unwrap().role, ROLE_ADMIN);
+    }
+}
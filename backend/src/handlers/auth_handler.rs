@@ -1,9 +1,14 @@
 use actix_identity::Identity;
-use actix_web::{dev::Payload, FromRequest, HttpRequest};
-use futures::future::{err, ok, Ready};
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest, HttpResponse};
+use futures::future::{err, ok, LocalBoxFuture, Ready};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-use crate::api_error::ApiError;
-use crate::models::AuthUser;
+use crate::api_error::{ApiError, ErrorCode};
+use crate::db;
+use crate::models::{AuthUser, TwoFactorChallenge, User};
+use crate::repos::UserRepo;
 
 pub type LoggedUser = AuthUser;
 
@@ -19,6 +24,89 @@ impl FromRequest for LoggedUser {
                 }
             }
         }
-        err(ApiError::new(401, "Invalid Request".to_string()))
+        err(ApiError::new(
+            ErrorCode::Unauthenticated,
+            "Invalid Request".to_string(),
+        ))
     }
 }
+
+/// A signed-in administrator. The role is read from the database rather than the
+/// session, and admin endpoints stay closed until the account has enabled
+/// two-factor authentication.
+pub struct AdminUser(pub User);
+
+impl FromRequest for AdminUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<AdminUser, ApiError>>;
+
+    fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
+        let identity = LoggedUser::from_request(req, pl).into_inner();
+        let users = req.app_data::<web::Data<dyn UserRepo>>().cloned();
+
+        Box::pin(async move {
+            let identity = identity?;
+            let users = users
+                .ok_or_else(|| ApiError::internal("No user repository registered".to_string()))?
+                .into_inner();
+            let user = db::run(move || users.find(identity.id)).await?;
+
+            if !user.is_admin() {
+                return Err(ApiError::new(ErrorCode::Forbidden, "Forbidden".to_string()));
+            }
+            if !user.totp_enabled {
+                return Err(ApiError::new(
+                    ErrorCode::TwoFactorRequired,
+                    "Two-factor authentication must be enabled for administrators".to_string(),
+                ));
+            }
+            Ok(AdminUser(user))
+        })
+    }
+}
+
+/// What the sign-in endpoints answer with: the signed-in user, or a challenge
+/// while a second factor is still outstanding.
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum SessionResponse {
+    SignedIn(AuthUser),
+    TwoFactorRequired(TwoFactorPending),
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TwoFactorPending {
+    pub two_factor_required: bool,
+    /// Answer it on `/login/2fa/` within a few minutes.
+    #[schema(value_type = String, format = Uuid)]
+    pub challenge: Uuid,
+}
+
+/// Establishes the session for a user whose first factor checked out. Accounts
+/// with two-factor authentication get a challenge to answer on `/login/2fa/`
+/// instead of a session.
+pub async fn start_session(user: User, id: &Identity) -> Result<HttpResponse, ApiError> {
+    if user.totp_enabled {
+        let user_id = user.id;
+        let challenge = db::run(move || TwoFactorChallenge::create(user_id)).await?;
+
+        return Ok(
+            HttpResponse::Ok().json(SessionResponse::TwoFactorRequired(TwoFactorPending {
+                two_factor_required: true,
+                challenge: challenge.id,
+            })),
+        );
+    }
+
+    Ok(remember(user, id))
+}
+
+pub fn remember(user: User, id: &Identity) -> HttpResponse {
+    let response = AuthUser::from(user);
+
+    let user_string = serde_json::to_string(&response).unwrap();
+
+    id.remember(user_string);
+
+    HttpResponse::Ok().json(SessionResponse::SignedIn(response))
+}
@@ -1,50 +1,144 @@
-use crate::api_error::ApiError;
+use crate::api_error::{ApiError, Problem};
+use crate::db;
+use crate::handlers::precondition::{conditional_response, tagged_response, IfMatchVersion};
+use crate::handlers::responses::Deleted;
 use crate::models::{AuthUser, Benchmark, BenchmarkInput};
-use actix_web::{delete, get, post, put, web, HttpResponse};
+use crate::repos::BenchmarkRepo;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
 use uuid::Uuid;
 
-use serde_json::json;
-
+#[utoipa::path(
+    tag = "benchmarks",
+    operation_id = "list_benchmarks",
+    responses(
+        (status = 200, description = "Every benchmark", body = [Benchmark]),
+    ),
+)]
 #[get("/benchmarks/")]
-async fn find_all() -> Result<HttpResponse, ApiError> {
-    let benchmarks = Benchmark::find_all()?;
+async fn find_all(benchmarks: web::Data<dyn BenchmarkRepo>) -> Result<HttpResponse, ApiError> {
+    let benchmarks = benchmarks.into_inner();
+    let benchmarks = db::run(move || benchmarks.find_all()).await?;
 
     Ok(HttpResponse::Ok().json(benchmarks))
 }
 
+#[utoipa::path(
+    tag = "benchmarks",
+    operation_id = "get_benchmark",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Benchmark ID"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a cached copy"),
+    ),
+    responses(
+        (status = 200, description = "The benchmark, with its version in `ETag`", body = Benchmark),
+        (status = 304, description = "The cached copy is current"),
+        (status = 404, description = "No such benchmark", body = Problem),
+    ),
+)]
 #[get("/benchmarks/{id}/")]
-async fn find(id: web::Path<Uuid>) -> Result<HttpResponse, ApiError> {
-    let benchmark = Benchmark::find(id.into_inner())?;
+async fn find(
+    id: web::Path<Uuid>,
+    benchmarks: web::Data<dyn BenchmarkRepo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let benchmarks = benchmarks.into_inner();
+    let benchmark = db::run(move || benchmarks.find(id)).await?;
 
-    Ok(HttpResponse::Ok().json(benchmark))
+    Ok(conditional_response(
+        &req,
+        benchmark.data_version,
+        &benchmark,
+    ))
 }
 
+#[utoipa::path(
+    tag = "benchmarks",
+    operation_id = "create_benchmark",
+    request_body = BenchmarkInput,
+    responses(
+        (status = 200, description = "The new benchmark", body = Benchmark),
+        (status = 401, description = "Not signed in", body = Problem),
+    ),
+    security(("session" = [])),
+)]
 #[post("/benchmarks/")]
 async fn create(
     benchmark: web::Json<BenchmarkInput>,
+    benchmarks: web::Data<dyn BenchmarkRepo>,
     identity: AuthUser,
 ) -> Result<HttpResponse, ApiError> {
-    let benchmark = Benchmark::create(benchmark.into_inner(), identity.id)?;
+    let benchmark = benchmark.into_inner();
+    let benchmarks = benchmarks.into_inner();
+    let benchmark = db::run(move || benchmarks.create(benchmark, identity.id)).await?;
 
     Ok(HttpResponse::Ok().json(benchmark))
 }
 
+#[utoipa::path(
+    tag = "benchmarks",
+    operation_id = "update_benchmark",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Benchmark ID"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified"),
+    ),
+    request_body = BenchmarkInput,
+    responses(
+        (status = 200, description = "The updated benchmark", body = Benchmark),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not the caller's benchmark", body = Problem),
+        (status = 404, description = "No such benchmark", body = Problem),
+        (status = 412, description = "`If-Match` names a stale version", body = Problem),
+    ),
+    security(("session" = [])),
+)]
 #[put("/benchmarks/{id}/")]
 async fn update(
     benchmark: web::Json<BenchmarkInput>,
     id: web::Path<Uuid>,
+    benchmarks: web::Data<dyn BenchmarkRepo>,
     identity: AuthUser,
+    if_match: IfMatchVersion,
 ) -> Result<HttpResponse, ApiError> {
-    let benchmark = Benchmark::update(id.into_inner(), benchmark.into_inner(), identity.id)?;
+    let id = id.into_inner();
+    let benchmark = benchmark.into_inner();
+    let benchmarks = benchmarks.into_inner();
+    let benchmark =
+        db::run(move || benchmarks.update(id, benchmark, identity.id, if_match.0)).await?;
 
-    Ok(HttpResponse::Ok().json(benchmark))
+    Ok(tagged_response(benchmark.data_version, &benchmark))
 }
 
+#[utoipa::path(
+    tag = "benchmarks",
+    operation_id = "delete_benchmark",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Benchmark ID"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified"),
+    ),
+    responses(
+        (status = 200, description = "Benchmark deleted", body = Deleted),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "Not the caller's benchmark", body = Problem),
+        (status = 404, description = "`If-Match` given for a benchmark that does not exist", body = Problem),
+        (status = 412, description = "`If-Match` names a stale version", body = Problem),
+    ),
+    security(("session" = [])),
+)]
 #[delete("/benchmarks/{id}/")]
-async fn delete(id: web::Path<Uuid>) -> Result<HttpResponse, ApiError> {
-    let num_deleted = Benchmark::delete(id.into_inner())?;
+async fn delete(
+    id: web::Path<Uuid>,
+    benchmarks: web::Data<dyn BenchmarkRepo>,
+    identity: AuthUser,
+    if_match: IfMatchVersion,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let benchmarks = benchmarks.into_inner();
+    let num_deleted = db::run(move || benchmarks.delete(id, identity.id, if_match.0)).await?;
 
-    Ok(HttpResponse::Ok().json(json!({ "deleted": num_deleted })))
+    Ok(HttpResponse::Ok().json(Deleted {
+        deleted: num_deleted,
+    }))
 }
 
 pub fn benchmark_routes(cfg: &mut web::ServiceConfig) {
@@ -54,3 +148,115 @@ pub fn benchmark_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(update);
     cfg.service(delete);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::benchmark_routes;
+    use crate::handlers::test_support::{register, session, TestRepos};
+    use crate::handlers::user_routes;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+
+    fn input(title: &str) -> Value {
+        json!({
+            "title": title,
+            "subject": "Sort a list",
+            "difficulty": "easy",
+            "git_url": null,
+            "max_cyclomatic_complex": 10,
+        })
+    }
+
+    #[actix_web::test]
+    async fn created_benchmark_is_cached_by_version() {
+        let repos = TestRepos::default();
+        let app = test::init_service(
+            repos
+                .app()
+                .configure(user_routes)
+                .configure(benchmark_routes),
+        )
+        .await;
+        let cookie = session(&test::call_service(&app, register("alice").to_request()).await);
+
+        let req = TestRequest::post()
+            .uri("/benchmarks/")
+            .cookie(cookie)
+            .set_json(input("Sorting"))
+            .to_request();
+        let benchmark: Value = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/benchmarks/{}/", benchmark["id"].as_str().unwrap());
+
+        let res = test::call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"1\"");
+
+        let req = TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_NONE_MATCH, "\"1\""))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[actix_web::test]
+    async fn only_the_creator_changes_a_benchmark() {
+        let repos = TestRepos::default();
+        let app = test::init_service(
+            repos
+                .app()
+                .configure(user_routes)
+                .configure(benchmark_routes),
+        )
+        .await;
+        let cookie = session(&test::call_service(&app, register("alice").to_request()).await);
+
+        let req = TestRequest::post()
+            .uri("/benchmarks/")
+            .cookie(cookie.clone())
+            .set_json(input("Sorting"))
+            .to_request();
+        let benchmark: Value = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/benchmarks/{}/", benchmark["id"].as_str().unwrap());
+
+        let res = test::call_service(&app, TestRequest::delete().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let bob = session(&test::call_service(&app, register("bob").to_request()).await);
+        let req = TestRequest::put()
+            .uri(&uri)
+            .cookie(bob.clone())
+            .set_json(input("Sorting, by Bob"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::delete().uri(&uri).cookie(bob).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::put()
+            .uri(&uri)
+            .cookie(cookie.clone())
+            .set_json(input("Sorting, again"))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let delete = |version: &str| {
+            TestRequest::delete()
+                .uri(&uri)
+                .cookie(cookie.clone())
+                .insert_header((header::IF_MATCH, format!("\"{}\"", version)))
+                .to_request()
+        };
+        let res = test::call_service(&app, delete("1")).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let body: Value = test::call_and_read_body_json(&app, delete("2")).await;
+        assert_eq!(body["deleted"], 1);
+
+        let res = test::call_service(&app, delete("2")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::api_error::{ApiError, ErrorCode};
+use crate::db;
+use crate::jobs::JobDispatcher;
+use actix_web::{get, web, HttpResponse};
+use diesel::RunQueryDsl;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+// Each dependency gets this long to answer before it is reported as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// A request waiting longer than this for a connection is already held up
+const CHECKOUT_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Serialize)]
+struct Check {
+    status: &'static str,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    details: Value,
+}
+
+impl Check {
+    fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+/// Liveness: the process is running and serving requests.
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Readiness: every dependency a request may need is reachable, and the schema
+/// is up to date. Answers 503 otherwise, so no traffic is routed here.
+#[get("/readyz")]
+async fn readyz(jobs: web::Data<dyn JobDispatcher>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+
+    checks.insert("pool", timed(db::run(check_pool)).await);
+    checks.insert("database", timed(db::run(check_database)).await);
+    checks.insert("migrations", timed(db::run(check_migrations)).await);
+    checks.insert(
+        jobs.name(),
+        timed(async {
+            jobs.check().await?;
+            Ok(Value::Null)
+        })
+        .await,
+    );
+
+    let ready = checks.values().all(Check::is_up);
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "checks": checks,
+    });
+
+    match ready {
+        true => HttpResponse::Ok().json(body),
+        false => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
+
+/// Whether a request would get a working connection without waiting on others.
+fn check_pool() -> Result<Value, ApiError> {
+    // As it was before this check took a connection of its own
+    let details = match db::pool_state() {
+        Some((state, max_size)) => json!({
+            "connections": state.connections,
+            "idle": state.idle_connections,
+            "max_size": max_size,
+        }),
+        None => Value::Null,
+    };
+
+    let conn = db::connection_within(CHECKOUT_TIMEOUT)?;
+    diesel::sql_query("SELECT 1").execute(&conn)?;
+
+    Ok(details)
+}
+
+fn check_database() -> Result<Value, ApiError> {
+    let conn = db::connection_within(CHECK_TIMEOUT)?;
+    diesel::sql_query("SELECT 1").execute(&conn)?;
+
+    Ok(Value::Null)
+}
+
+fn check_migrations() -> Result<Value, ApiError> {
+    let conn = db::connection_within(CHECK_TIMEOUT)?;
+    let pending = db::pending_migrations(&conn)?;
+
+    match pending.is_empty() {
+        true => Ok(Value::Null),
+        false => Err(ApiError::new(
+            ErrorCode::ServiceUnavailable,
+            format!("Pending migrations: {}", pending.join(", ")),
+        )),
+    }
+}
+
+async fn timed(check: impl Future<Output = Result<Value, ApiError>>) -> Check {
+    let started = Instant::now();
+    let result = actix_rt::time::timeout(CHECK_TIMEOUT, check).await;
+    let latency_ms = millis(started);
+
+    let (status, error, details) = match result {
+        Ok(Ok(details)) => ("up", None, details),
+        Ok(Err(e)) => ("down", Some(e.message), Value::Null),
+        Err(_) => ("down", Some("Timed out".to_string()), Value::Null),
+    };
+
+    Check {
+        status,
+        latency_ms,
+        error,
+        details,
+    }
+}
+
+fn millis(started: Instant) -> f64 {
+    started.elapsed().as_micros() as f64 / 1000.0
+}
+
+pub fn health_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz);
+    cfg.service(readyz);
+}
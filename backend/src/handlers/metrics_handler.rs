@@ -0,0 +1,13 @@
+use crate::metrics;
+use actix_web::{get, web, HttpResponse};
+
+#[get("/metrics")]
+async fn render_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+pub fn metrics_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(render_metrics);
+}
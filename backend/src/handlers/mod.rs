@@ -1,9 +1,26 @@
+mod admin_handler;
 mod auth_handler;
 mod benchmark_handler;
+mod health_handler;
+mod metrics_handler;
+mod oauth_handler;
+mod openapi_handler;
+mod precondition;
+mod responses;
+mod scratch_handler;
 mod submission_handler;
+#[cfg(test)]
+mod test_support;
+mod two_factor_handler;
 mod user_handler;
 
-pub use auth_handler::*;
+pub use admin_handler::*;
 pub use benchmark_handler::*;
+pub use health_handler::*;
+pub use metrics_handler::*;
+pub use oauth_handler::*;
+pub use openapi_handler::*;
+pub use scratch_handler::*;
 pub use submission_handler::*;
+pub use two_factor_handler::*;
 pub use user_handler::*;
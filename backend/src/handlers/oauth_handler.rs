@@ -0,0 +1,228 @@
+use crate::api_error::{ApiError, ErrorCode, Problem};
+use crate::config::Config;
+use crate::db;
+use crate::handlers::auth_handler::{remember, start_session, SessionResponse};
+use crate::models::{AuthUser, OAuthState, User, UserIdentity};
+use crate::oauth::{self, ExternalProfile};
+use actix_identity::Identity;
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Starts the authorization-code flow. When the caller is already signed in, the
+/// external account gets linked to them instead of signing in as someone else.
+/// The state also goes into a signed cookie, which the callback insists on.
+#[utoipa::path(
+    tag = "oauth",
+    operation_id = "oauth_login",
+    params(
+        ("provider" = String, Path, description = "Configured provider name"),
+    ),
+    responses(
+        (status = 302, description = "Redirect to the provider; signed-in callers link the account instead"),
+        (status = 404, description = "Unknown provider", body = Problem),
+    ),
+)]
+#[get("/auth/{provider}/login/")]
+async fn login(
+    provider: web::Path<String>,
+    identity: Option<AuthUser>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let provider = oauth::provider(&config.oauth, &provider)
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, "Unknown OAuth provider".to_string()))?;
+
+    let (code_verifier, code_challenge) = oauth::pkce_pair();
+    let redirect_uri = provider.redirect_uri(&req);
+
+    let state = OAuthState {
+        state: oauth::random_token(),
+        provider: provider.name.clone(),
+        code_verifier,
+        redirect_uri,
+        user_id: identity.map(|identity| identity.id),
+        created_at: Utc::now().naive_utc(),
+    };
+    let state = db::run(move || OAuthState::create(state)).await?;
+
+    let url = provider
+        .authorization_url(&state.state, &code_challenge, &state.redirect_uri)
+        .await?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .cookie(oauth::state_cookie(&config.server, &state.state))
+        .finish())
+}
+
+#[utoipa::path(
+    tag = "oauth",
+    operation_id = "oauth_callback",
+    params(
+        ("provider" = String, Path, description = "Configured provider name"),
+        ("code" = Option<String>, Query, description = "Authorization code"),
+        ("state" = Option<String>, Query, description = "State issued by the login redirect"),
+        ("error" = Option<String>, Query, description = "Set when the user declined"),
+    ),
+    responses(
+        (status = 200, description = "Signed in or linked", body = SessionResponse),
+        (status = 400, description = "Missing or expired state, or one this browser did not start", body = Problem),
+        (status = 401, description = "Authorization was not granted", body = Problem),
+        (status = 403, description = "Linking from a session other than the one that started it", body = Problem),
+        (status = 409, description = "The account belongs to another user", body = Problem),
+    ),
+)]
+#[get("/auth/{provider}/callback/")]
+async fn callback(
+    provider: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+    identity: Option<AuthUser>,
+    id: Identity,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let provider = oauth::provider(&config.oauth, &provider)
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, "Unknown OAuth provider".to_string()))?;
+    let query = query.into_inner();
+
+    if let Some(error) = query.error {
+        return Err(ApiError::new(
+            ErrorCode::OauthDenied,
+            format!("Authorization was not granted: {}", error),
+        ));
+    }
+
+    let (code, state) = match (query.code, query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "Missing code or state".to_string(),
+            ))
+        }
+    };
+
+    // Otherwise anyone could be sent a callback URL and end up signed in to the
+    // account of whoever started the flow
+    if !oauth::state_cookie_matches(&config.server, &req, &state) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidToken,
+            "The OAuth state was not issued to this browser".to_string(),
+        ));
+    }
+
+    let provider_name = provider.name.clone();
+    let pending = db::run(move || OAuthState::take(&state, &provider_name))
+        .await?
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::InvalidToken,
+                "Invalid or expired OAuth state".to_string(),
+            )
+        })?;
+
+    if let Some(user_id) = pending.user_id {
+        if identity.map(|identity| identity.id) != Some(user_id) {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "The account can only be linked from the session that started it".to_string(),
+            ));
+        }
+    }
+
+    let access_token = provider
+        .exchange_code(&code, &pending.code_verifier, &pending.redirect_uri)
+        .await?;
+    let profile = provider.fetch_profile(&access_token).await?;
+
+    let linking = pending.user_id.is_some();
+    let provider_name = provider.name.clone();
+    let user = db::run(move || resolve_user(&provider_name, profile, pending.user_id)).await?;
+
+    // Linking happens inside the session checked above, which already passed any
+    // second factor
+    let mut response = match linking {
+        true => remember(user, &id),
+        false => start_session(user, &id).await?,
+    };
+    oauth::clear_state_cookie(&config.server, &mut response);
+
+    Ok(response)
+}
+
+/// Maps an external profile onto a local account: links it to the signed-in user,
+/// signs in the user it is already linked to, or provisions a new account.
+fn resolve_user(
+    provider: &str,
+    profile: ExternalProfile,
+    linking_user_id: Option<Uuid>,
+) -> Result<User, ApiError> {
+    let existing = UserIdentity::find_by_subject(provider, &profile.subject)?;
+
+    match (existing, linking_user_id) {
+        (Some(identity), Some(user_id)) if identity.user_id != user_id => Err(ApiError::new(
+            ErrorCode::Conflict,
+            "This external account is already linked to another user".to_string(),
+        )),
+        (Some(identity), _) => User::find(identity.user_id),
+        (None, Some(user_id)) => {
+            UserIdentity::create(user_id, provider, &profile.subject, profile.email)?;
+            User::find(user_id)
+        }
+        (None, None) => {
+            let email = profile.email.ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::EmailNotVerified,
+                    "The provider did not share a verified email address".to_string(),
+                )
+            })?;
+
+            // Never attach a provider account to an existing user without that user
+            // proving they own it by signing in first.
+            if User::find_by_email(&email)?.is_some() {
+                return Err(ApiError::new(ErrorCode::Conflict,
+                    "An account with this email already exists; sign in and link this provider instead"
+                        .to_string(),
+                ));
+            }
+
+            let user =
+                User::provision(&email, profile.name.as_deref(), profile.username.as_deref())?;
+            UserIdentity::create(user.id, provider, &profile.subject, Some(email))?;
+
+            Ok(user)
+        }
+    }
+}
+
+#[utoipa::path(
+    tag = "oauth",
+    operation_id = "list_identities",
+    responses(
+        (status = 200, description = "External accounts linked to the caller", body = [UserIdentity]),
+        (status = 401, description = "Not signed in", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[get("/users/identities/")]
+async fn find_identities(identity: AuthUser) -> Result<HttpResponse, ApiError> {
+    let identities = db::run(move || UserIdentity::find_user_identities(identity.id)).await?;
+
+    Ok(HttpResponse::Ok().json(identities))
+}
+
+pub fn oauth_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(login);
+    cfg.service(callback);
+    cfg.service(find_identities);
+}
@@ -0,0 +1,199 @@
+use super::{
+    admin_handler, benchmark_handler, oauth_handler, scratch_handler, submission_handler,
+    two_factor_handler, user_handler,
+};
+use actix_web::web;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+/// Everything under the `/api` scope. Paths come from the handlers' own route
+/// attributes, so only the list below has to be kept up to date.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        user_handler::find_all,
+        user_handler::find,
+        user_handler::register,
+        user_handler::sign_in,
+        user_handler::update,
+        user_handler::verify_email,
+        user_handler::change_password,
+        user_handler::delete,
+        two_factor_handler::enroll,
+        two_factor_handler::confirm,
+        two_factor_handler::disable,
+        two_factor_handler::regenerate_recovery_codes,
+        two_factor_handler::sign_in,
+        oauth_handler::login,
+        oauth_handler::callback,
+        oauth_handler::find_identities,
+        benchmark_handler::find_all,
+        benchmark_handler::find,
+        benchmark_handler::create,
+        benchmark_handler::update,
+        benchmark_handler::delete,
+        submission_handler::find_all,
+        submission_handler::find,
+        submission_handler::create,
+        submission_handler::update,
+        submission_handler::delete,
+        submission_handler::user_submissions,
+        submission_handler::run_user_submission,
+        submission_handler::cancel_run,
+        submission_handler::find_attempts,
+        submission_handler::find_files,
+        submission_handler::find_file,
+        submission_handler::put_file,
+        submission_handler::delete_file,
+        submission_handler::user_quota,
+        scratch_handler::create,
+        scratch_handler::find,
+        admin_handler::update_role,
+        admin_handler::update_contest,
+        admin_handler::find_dead_letters,
+        admin_handler::requeue_dead_letter,
+        admin_handler::discard_dead_letter,
+    ),
+    tags(
+        (name = "auth", description = "Registration and sign-in"),
+        (name = "users", description = "Accounts and profiles"),
+        (name = "two-factor", description = "TOTP enrollment and recovery codes"),
+        (name = "oauth", description = "Sign-in through external providers"),
+        (name = "benchmarks", description = "Problems to solve"),
+        (name = "submissions", description = "Solutions and their runs"),
+        (name = "runs", description = "Runs of code that is not saved"),
+        (name = "admin", description = "Administration"),
+    )
+)]
+struct ApiRoutes;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Dune API",
+        description = "Errors are answered with `application/problem+json` (see `Problem`)."
+    ),
+    nest((path = "/api", api = ApiRoutes)),
+    modifiers(&SessionCookie)
+)]
+pub struct ApiDoc;
+
+/// The `auth` cookie set by the sign-in endpoints.
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("auth"))),
+        );
+    }
+}
+
+/// Serves the document at `/api/openapi.json` and Swagger UI at `/api/docs/`.
+/// Must be registered ahead of the `/api` scope, which would otherwise take
+/// these paths.
+pub fn openapi_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(SwaggerUi::new("/api/docs/{_:.*}").url("/api/openapi.json", ApiDoc::openapi()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiDoc;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+    use utoipa::OpenApi;
+
+    // Mounted outside `/api` for infrastructure, not clients
+    const UNDOCUMENTED: &[&str] = &["health_handler.rs", "metrics_handler.rs"];
+    const METHODS: &[&str] = &["get", "post", "put", "delete", "patch"];
+
+    /// Every `#[get("...")]`-style route attribute in the handler modules.
+    fn declared_routes() -> BTreeSet<(String, String)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/handlers");
+        let mut routes = BTreeSet::new();
+
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            if UNDOCUMENTED.contains(&file_name.as_str()) {
+                continue;
+            }
+
+            for line in fs::read_to_string(&path).unwrap().lines() {
+                let line = line.trim();
+                for method in METHODS {
+                    let prefix = format!("#[{}(\"", method);
+                    if let Some(rest) = line.strip_prefix(&prefix) {
+                        let route = rest.split('"').next().unwrap();
+                        // OpenAPI has no notation for `{path:.*}`, so it is listed as `{path}`
+                        let route = route.replace(":.*}", "}");
+                        routes.insert((method.to_string(), format!("/api{}", route)));
+                    }
+                }
+            }
+        }
+
+        routes
+    }
+
+    fn documented_routes() -> BTreeSet<(String, String)> {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut routes = BTreeSet::new();
+
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for method in METHODS {
+                if item.get(*method).is_some() {
+                    routes.insert((method.to_string(), path.clone()));
+                }
+            }
+        }
+
+        routes
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let missing: Vec<_> = declared_routes()
+            .difference(&documented_routes())
+            .cloned()
+            .collect();
+
+        assert!(
+            missing.is_empty(),
+            "routes missing from the OpenAPI document, add them to ApiRoutes: {:?}",
+            missing
+        );
+    }
+
+    #[test]
+    fn every_documented_route_exists() {
+        let stale: Vec<_> = documented_routes()
+            .difference(&declared_routes())
+            .cloned()
+            .collect();
+
+        assert!(
+            stale.is_empty(),
+            "the OpenAPI document lists routes no handler serves: {:?}",
+            stale
+        );
+    }
+
+    #[test]
+    fn operation_ids_are_unique() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut seen = BTreeSet::new();
+
+        for item in spec["paths"].as_object().unwrap().values() {
+            for method in METHODS {
+                if let Some(id) = item[*method]["operationId"].as_str() {
+                    assert!(seen.insert(id.to_string()), "duplicate operationId {}", id);
+                }
+            }
+        }
+    }
+}
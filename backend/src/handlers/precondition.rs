@@ -0,0 +1,69 @@
+use actix_web::http::header::{self, EntityTag, Header, IfMatch, IfNoneMatch};
+use actix_web::{dev::Payload, FromRequest, HttpRequest, HttpResponse};
+use futures::future::{err, ok, Ready};
+use serde::Serialize;
+
+use crate::api_error::ApiError;
+
+/// The `data_version` a client expects to modify, taken from `If-Match`.
+/// `None` when the header is absent or `*`, in which case writes are unconditional.
+pub struct IfMatchVersion(pub Option<i32>);
+
+impl FromRequest for IfMatchVersion {
+    type Error = ApiError;
+    type Future = Ready<Result<IfMatchVersion, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if !req.headers().contains_key(header::IF_MATCH) {
+            return ok(IfMatchVersion(None));
+        }
+
+        // If-Match uses the strong comparison, so weak or foreign tags never match
+        let version = match IfMatch::parse(req) {
+            Ok(IfMatch::Any) => return ok(IfMatchVersion(None)),
+            Ok(IfMatch::Items(tags)) => tags
+                .iter()
+                .filter(|tag| !tag.weak)
+                .find_map(|tag| tag.tag().parse::<i32>().ok()),
+            Err(_) => None,
+        };
+
+        match version {
+            Some(version) => ok(IfMatchVersion(Some(version))),
+            None => err(ApiError::precondition_failed()),
+        }
+    }
+}
+
+pub fn entity_tag(version: i32) -> EntityTag {
+    EntityTag::new_strong(version.to_string())
+}
+
+/// Responds with the resource and its `ETag`, or with `304 Not Modified` when the
+/// client's `If-None-Match` already covers the current version.
+pub fn conditional_response<T: Serialize>(
+    req: &HttpRequest,
+    version: i32,
+    body: &T,
+) -> HttpResponse {
+    let tag = entity_tag(version);
+
+    let not_modified = match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|candidate| candidate.weak_eq(&tag)),
+        Err(_) => false,
+    };
+
+    match not_modified {
+        true => HttpResponse::NotModified()
+            .insert_header(header::ETag(tag))
+            .finish(),
+        false => tagged_response(version, body),
+    }
+}
+
+pub fn tagged_response<T: Serialize>(version: i32, body: &T) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(header::ETag(entity_tag(version)))
+        .json(body)
+}
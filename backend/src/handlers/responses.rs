@@ -0,0 +1,26 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct Deleted {
+    /// Rows removed: 1, or 0 when there was nothing to delete.
+    pub deleted: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RunQueued {
+    /// Always `queued`; follow the submission for the worker's progress.
+    pub status: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RunCancelled {
+    /// `cancelled`, or `cancelling` while the worker has yet to stop the run.
+    pub status: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// Shown once; each code signs in a single time in place of a TOTP code.
+    pub recovery_codes: Vec<String>,
+}
@@ -0,0 +1,130 @@
+use crate::api_error::{ApiError, ErrorCode, Problem};
+use crate::config::Config;
+use crate::db;
+use crate::jobs::{JobDispatcher, RunClass};
+use crate::metrics;
+use crate::models::{AuthUser, RunUsage, ScratchRun, ScratchRunInput, SubmissionWorker};
+use crate::repos::SubmissionRepo;
+use actix_web::http::header;
+use actix_web::{get, post, web, HttpResponse};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// How often a waiting request looks for the result
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Runs code without saving it as a submission. The run counts against the
+/// daily quota like any other, goes behind contest and grading runs, and is
+/// kept for `jobs.scratch.ttl_seconds`.
+#[utoipa::path(
+    tag = "runs",
+    operation_id = "create_scratch_run",
+    request_body = ScratchRunInput,
+    responses(
+        (status = 200, description = "The finished run", body = ScratchRun),
+        (status = 202, description = "Still in progress once the wait was over; poll `Location` for the result", body = ScratchRun),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 422, description = "Validation failed", body = Problem),
+        (status = 429, description = "Rate limit or daily quota exceeded; see `Retry-After`", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/run/")]
+async fn create(
+    input: web::Json<ScratchRunInput>,
+    submissions: web::Data<dyn SubmissionRepo>,
+    jobs: web::Data<dyn JobDispatcher>,
+    identity: AuthUser,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    let input = input.into_inner();
+    input.validate(&config.jobs.allowed_env)?;
+
+    let scratch = &config.jobs.scratch;
+    let wait = Duration::from_secs(
+        input
+            .wait_seconds
+            .unwrap_or(scratch.max_wait_seconds)
+            .min(scratch.max_wait_seconds),
+    );
+    let submissions = submissions.into_inner();
+    let (run, priority) = db::run({
+        let config = config.clone();
+        move || {
+            let in_flight = submissions.count_in_flight(identity.id)?
+                + ScratchRun::count_in_flight(identity.id)?;
+            let priority = RunClass::Practice.priority(in_flight, &config.jobs.priority);
+
+            RunUsage::reserve_run(identity.id, &config.limits)?;
+            let run = ScratchRun::create(
+                input,
+                identity.id,
+                config.jobs.timeout_seconds,
+                config.jobs.scratch.ttl_seconds,
+            )?;
+
+            Ok((run, priority))
+        }
+    })
+    .await?;
+
+    metrics::count_status(&run.status);
+    let id = run.id;
+    if let Err(e) = jobs
+        .dispatch(SubmissionWorker::scratch(&run, priority))
+        .await
+    {
+        db::run(move || {
+            ScratchRun::purge(id)?;
+            RunUsage::release_run(identity.id)
+        })
+        .await?;
+        return Err(e);
+    }
+
+    let deadline = Instant::now() + wait;
+    let mut run = run;
+    while !run.is_finished() && Instant::now() < deadline {
+        actix_rt::time::sleep(POLL_INTERVAL.min(deadline - Instant::now())).await;
+        run = db::run(move || ScratchRun::find(id)).await?;
+    }
+
+    match run.is_finished() {
+        true => Ok(HttpResponse::Ok().json(run)),
+        false => Ok(HttpResponse::Accepted()
+            .insert_header((header::LOCATION, format!("/api/run/{}/", id)))
+            .json(run)),
+    }
+}
+
+/// A scratch run of the caller's, until it expires.
+#[utoipa::path(
+    tag = "runs",
+    operation_id = "get_scratch_run",
+    params(
+        ("id" = String, Path, format = Uuid, description = "Scratch run ID"),
+    ),
+    responses(
+        (status = 200, description = "The run as it stands", body = ScratchRun),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 403, description = "The run belongs to someone else", body = Problem),
+        (status = 404, description = "No such run, or it has expired", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[get("/run/{id}/")]
+async fn find(id: web::Path<Uuid>, identity: AuthUser) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let run = db::run(move || ScratchRun::find(id)).await?;
+
+    if run.user_id != identity.id {
+        return Err(ApiError::new(ErrorCode::Forbidden, "Forbidden".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(run))
+}
+
+pub fn scratch_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(create);
+    cfg.service(find);
+}
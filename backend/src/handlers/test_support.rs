@@ -0,0 +1,77 @@
+//! Setup shared by the handler tests: an app backed by the in-memory
+//! repositories, and shortcuts for getting a session.
+
+use crate::config::Config;
+use crate::mailer::{Mailer, MemoryMailer};
+use crate::repos::memory::{MemoryBenchmarkRepo, MemorySubmissionRepo, MemoryUserRepo};
+use crate::repos::Repos;
+use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_web::body::MessageBody;
+use actix_web::cookie::Cookie;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::test::TestRequest;
+use actix_web::{web, App, Error};
+use serde_json::json;
+use std::sync::Arc;
+
+pub const PASSWORD: &str = "correct horse 42";
+
+#[derive(Default)]
+pub struct TestRepos {
+    pub users: Arc<MemoryUserRepo>,
+    pub submissions: Arc<MemorySubmissionRepo>,
+    pub benchmarks: Arc<MemoryBenchmarkRepo>,
+    pub mailer: Arc<MemoryMailer>,
+}
+
+impl TestRepos {
+    /// An app with the repositories and sessions set up; tests add the routes.
+    pub fn app(
+        &self,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = Error,
+            InitError = (),
+        >,
+    > {
+        let repos = Repos {
+            users: self.users.clone(),
+            submissions: self.submissions.clone(),
+            benchmarks: self.benchmarks.clone(),
+        };
+
+        let mailer: Arc<dyn Mailer> = self.mailer.clone();
+
+        App::new()
+            .app_data(web::Data::new(Config::default()))
+            .app_data(web::Data::from(mailer))
+            .configure(move |cfg| repos.register(cfg))
+            .wrap(IdentityService::new(
+                CookieIdentityPolicy::new(&[0; 32])
+                    .name("auth")
+                    .secure(false),
+            ))
+    }
+}
+
+/// Registration through `/register/`, which also signs the new user in.
+pub fn register(username: &str) -> TestRequest {
+    TestRequest::post().uri("/register/").set_json(json!({
+        "email": format!("{}@example.com", username),
+        "password": PASSWORD,
+        "name": username,
+        "username": username,
+    }))
+}
+
+/// The session cookie a sign-in response set.
+pub fn session<B>(res: &ServiceResponse<B>) -> Cookie<'static> {
+    res.response()
+        .cookies()
+        .find(|cookie| cookie.name() == "auth")
+        .expect("response did not set a session")
+        .into_owned()
+}
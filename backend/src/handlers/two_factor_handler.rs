@@ -0,0 +1,214 @@
+use crate::api_error::{ApiError, ErrorCode, Problem};
+use crate::client_ip::client_ip;
+use crate::db;
+use crate::handlers::auth_handler::{remember, SessionResponse};
+use crate::handlers::responses::RecoveryCodes;
+use crate::models::{
+    AuthUser, LoginAttempt, RecoveryCode, TotpEnrollment, TwoFactorChallenge, User,
+    OUTCOME_FAILURE, OUTCOME_SUCCESS,
+};
+use actix_identity::Identity;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Deserialize, ToSchema)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TwoFactorLogin {
+    #[schema(value_type = String, format = Uuid)]
+    pub challenge: Uuid,
+    /// A code from the authenticator app or one of the recovery codes.
+    pub code: String,
+}
+
+#[utoipa::path(
+    tag = "two-factor",
+    operation_id = "enroll_totp",
+    responses(
+        (status = 200, description = "A new secret to confirm with `/users/2fa/confirm/`", body = TotpEnrollment),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 409, description = "Already enabled", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/2fa/enroll/")]
+async fn enroll(identity: AuthUser) -> Result<HttpResponse, ApiError> {
+    let enrollment = db::run(move || User::find(identity.id)?.begin_totp_enrollment()).await?;
+
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+#[utoipa::path(
+    tag = "two-factor",
+    operation_id = "confirm_totp",
+    request_body = TwoFactorCode,
+    responses(
+        (status = 200, description = "Enabled; the recovery codes are shown only this once", body = RecoveryCodes),
+        (status = 401, description = "Not signed in, or the code is wrong", body = Problem),
+        (status = 429, description = "Too many failed attempts", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/2fa/confirm/")]
+async fn confirm(
+    body: web::Json<TwoFactorCode>,
+    identity: AuthUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let code = body.into_inner().code;
+    let ip_address = client_ip(&req);
+    let recovery_codes = db::run(move || {
+        let user = User::find(identity.id)?;
+        throttled(&user, &ip_address, || user.confirm_totp(&code))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+#[utoipa::path(
+    tag = "two-factor",
+    operation_id = "disable_totp",
+    request_body = TwoFactorCode,
+    responses(
+        (status = 200, description = "Disabled", body = User),
+        (status = 401, description = "Not signed in, or the code is wrong", body = Problem),
+        (status = 403, description = "Administrators must keep it enabled", body = Problem),
+        (status = 429, description = "Too many failed attempts", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/2fa/disable/")]
+async fn disable(
+    body: web::Json<TwoFactorCode>,
+    identity: AuthUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let code = body.into_inner().code;
+    let ip_address = client_ip(&req);
+    let user = db::run(move || {
+        let user = User::find(identity.id)?;
+        throttled(&user, &ip_address, || user.disable_totp(&code))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[utoipa::path(
+    tag = "two-factor",
+    operation_id = "regenerate_recovery_codes",
+    request_body = TwoFactorCode,
+    responses(
+        (status = 200, description = "New recovery codes; the old ones stop working", body = RecoveryCodes),
+        (status = 401, description = "Not signed in, or the code is wrong", body = Problem),
+        (status = 429, description = "Too many failed attempts", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/2fa/recovery-codes/")]
+async fn regenerate_recovery_codes(
+    body: web::Json<TwoFactorCode>,
+    identity: AuthUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let code = body.into_inner().code;
+    let ip_address = client_ip(&req);
+    let recovery_codes = db::run(move || {
+        let user = User::find(identity.id)?;
+        throttled(&user, &ip_address, || {
+            if !user.verify_second_factor(&code)? {
+                return Err(ApiError::new(
+                    ErrorCode::InvalidTwoFactorCode,
+                    "Invalid two-factor code".to_string(),
+                ));
+            }
+
+            RecoveryCode::regenerate(user.id)
+        })
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+/// Second step of `/login/` for accounts with two-factor authentication.
+#[utoipa::path(
+    tag = "auth",
+    operation_id = "login_two_factor",
+    request_body = TwoFactorLogin,
+    responses(
+        (status = 200, description = "Signed in", body = SessionResponse),
+        (status = 401, description = "Wrong code, or the challenge expired", body = Problem),
+        (status = 429, description = "Too many failed attempts", body = Problem),
+    ),
+)]
+#[post("/login/2fa/")]
+async fn sign_in(
+    body: web::Json<TwoFactorLogin>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let login = body.into_inner();
+    let ip_address = client_ip(&req);
+
+    let user = db::run(move || {
+        let challenge = TwoFactorChallenge::find_valid(login.challenge)?.ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::Unauthenticated,
+                "Invalid or expired two-factor challenge".to_string(),
+            )
+        })?;
+        let user = User::find(challenge.user_id)?;
+
+        throttled(&user, &ip_address, || {
+            if !user.verify_second_factor(&login.code)? {
+                TwoFactorChallenge::record_failure(challenge.id)?;
+                return Err(ApiError::new(
+                    ErrorCode::InvalidTwoFactorCode,
+                    "Invalid two-factor code".to_string(),
+                ));
+            }
+
+            TwoFactorChallenge::delete(challenge.id)
+        })?;
+
+        Ok(user)
+    })
+    .await?;
+
+    Ok(remember(user, &id))
+}
+
+/// Runs `check`, which takes a two-factor code from `user`, under the throttling
+/// of the login's second step: a wrong code counts as a failed login, so
+/// guessing is slowed down the same on every endpoint that takes one.
+fn throttled<T>(
+    user: &User,
+    ip_address: &str,
+    check: impl FnOnce() -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let attempt = LoginAttempt::begin(&user.username, Some(user.id), ip_address)?;
+
+    let outcome = check();
+    match &outcome {
+        Ok(_) => attempt.finish(OUTCOME_SUCCESS)?,
+        Err(e) if e.code == ErrorCode::InvalidTwoFactorCode => attempt.finish(OUTCOME_FAILURE)?,
+        Err(_) => attempt.discard()?,
+    }
+
+    outcome
+}
+
+pub fn two_factor_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(enroll);
+    cfg.service(confirm);
+    cfg.service(disable);
+    cfg.service(regenerate_recovery_codes);
+    cfg.service(sign_in);
+}
@@ -1,27 +1,77 @@
-use crate::api_error::ApiError;
-use crate::models::{AuthUser, LoginRequest, User, UserMessage};
+use crate::api_error::{ApiError, ErrorCode, Problem};
+use crate::client_ip::client_ip;
+use crate::db;
+use crate::handlers::auth_handler::{start_session, SessionResponse};
+use crate::handlers::precondition::{conditional_response, tagged_response, IfMatchVersion};
+use crate::handlers::responses::Deleted;
+use crate::mailer::{Email, Mailer};
+use crate::models::{
+    AuthUser, EmailVerification, LoginAttempt, LoginRequest, PasswordChange, ProfileUpdate, User,
+    UserMessage, OUTCOME_FAILURE, OUTCOME_SECOND_FACTOR_REQUIRED, OUTCOME_SUCCESS,
+};
+use crate::repos::UserRepo;
 use actix_identity::Identity;
-use actix_web::{delete, get, post, put, web, HttpResponse};
-
-use serde_json::json;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
 
 use uuid::Uuid;
 
+#[utoipa::path(
+    tag = "users",
+    operation_id = "list_users",
+    responses(
+        (status = 200, description = "Every user", body = [User]),
+    ),
+)]
 #[get("/users/")]
-async fn find_all() -> Result<HttpResponse, ApiError> {
-    let users = User::find_all()?;
+async fn find_all(users: web::Data<dyn UserRepo>) -> Result<HttpResponse, ApiError> {
+    let users = users.into_inner();
+    let users = db::run(move || users.find_all()).await?;
     Ok(HttpResponse::Ok().json(users))
 }
 
+#[utoipa::path(
+    tag = "users",
+    operation_id = "get_user",
+    params(
+        ("id" = String, Path, format = Uuid, description = "User ID"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a cached copy"),
+    ),
+    responses(
+        (status = 200, description = "The user, with its version in `ETag`", body = User),
+        (status = 304, description = "The cached copy is current"),
+        (status = 404, description = "No such user", body = Problem),
+    ),
+)]
 #[get("/users/{id}/")]
-async fn find(id: web::Path<Uuid>) -> Result<HttpResponse, ApiError> {
-    let user = User::find(id.into_inner())?;
-    Ok(HttpResponse::Ok().json(user))
+async fn find(
+    id: web::Path<Uuid>,
+    users: web::Data<dyn UserRepo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let users = users.into_inner();
+    let user = db::run(move || users.find(id)).await?;
+    Ok(conditional_response(&req, user.data_version, &user))
 }
 
+#[utoipa::path(
+    tag = "auth",
+    operation_id = "register",
+    request_body = UserMessage,
+    responses(
+        (status = 200, description = "Account created and signed in", body = AuthUser),
+        (status = 422, description = "Validation failed", body = Problem),
+    ),
+)]
 #[post("/register/")]
-async fn register(user: web::Json<UserMessage>, id: Identity) -> Result<HttpResponse, ApiError> {
-    let user = User::create(user.into_inner())?;
+async fn register(
+    user: web::Json<UserMessage>,
+    users: web::Data<dyn UserRepo>,
+    id: Identity,
+) -> Result<HttpResponse, ApiError> {
+    let user = user.into_inner();
+    let users = users.into_inner();
+    let user = db::run(move || users.create(user)).await?;
 
     let response = AuthUser {
         email: user.email,
@@ -37,49 +87,233 @@ async fn register(user: web::Json<UserMessage>, id: Identity) -> Result<HttpResp
     Ok(HttpResponse::Ok().json(response))
 }
 
+#[utoipa::path(
+    tag = "auth",
+    operation_id = "login",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Signed in, or a challenge to answer on `/login/2fa/`", body = SessionResponse),
+        (status = 401, description = "Wrong identifier or password", body = Problem),
+        (status = 429, description = "Too many failed attempts", body = Problem),
+    ),
+)]
 #[post("/login/")]
 async fn sign_in(
     credentials: web::Json<LoginRequest>,
+    users: web::Data<dyn UserRepo>,
     id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
     let credentials = credentials.into_inner();
+    credentials.validate()?;
 
-    let user = User::find_by_email_or_username(credentials.email, credentials.username)?;
+    let identifier = LoginAttempt::normalize_identifier(&credentials.identifier);
+    let ip_address = client_ip(&req);
 
-    let is_valid = User::verify_password(&user, credentials.password.as_bytes())?;
+    // The password hash is as slow to check as the queries around it, so the
+    // whole exchange runs off the worker
+    let users = users.into_inner();
+    let user = db::run(move || {
+        authenticate(
+            users.as_ref(),
+            &identifier,
+            &credentials.password,
+            &ip_address,
+        )
+    })
+    .await?;
 
-    if is_valid {
-        let response = AuthUser {
-            email: user.email,
-            id: user.id,
-            name: user.name,
-            username: user.username,
-        };
+    start_session(user, &id).await
+}
 
-        let user_string = serde_json::to_string(&response).unwrap();
+fn authenticate(
+    users: &dyn UserRepo,
+    identifier: &str,
+    password: &str,
+    ip_address: &str,
+) -> Result<User, ApiError> {
+    let user = users.find_by_identifier(identifier)?;
+    let user_id = user.as_ref().map(|user| user.id);
 
-        id.remember(user_string);
+    let attempt = LoginAttempt::begin(identifier, user_id, ip_address)?;
 
-        Ok(HttpResponse::Ok().json(response))
-    } else {
-        Err(ApiError::new(401, "Invalid Credentials".to_string()))
+    let is_valid = match &user {
+        Some(user) => User::verify_password(user, password.as_bytes())?,
+        None => {
+            User::verify_dummy_password(password.as_bytes());
+            false
+        }
+    };
+
+    match (user, is_valid) {
+        (Some(user), true) => {
+            // A correct password alone must not clear earlier failures while the
+            // second factor is still outstanding
+            let outcome = match user.totp_enabled {
+                true => OUTCOME_SECOND_FACTOR_REQUIRED,
+                false => OUTCOME_SUCCESS,
+            };
+            attempt.finish(outcome)?;
+
+            Ok(user)
+        }
+        _ => {
+            attempt.finish(OUTCOME_FAILURE)?;
+            info!(
+                "Failed login attempt for {} from {}",
+                identifier, ip_address
+            );
+            Err(ApiError::new(
+                ErrorCode::InvalidCredentials,
+                "Invalid Credentials".to_string(),
+            ))
+        }
     }
 }
 
+#[utoipa::path(
+    tag = "users",
+    operation_id = "update_profile",
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified"),
+    ),
+    request_body = ProfileUpdate,
+    responses(
+        (status = 200, description = "The updated user", body = User),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 412, description = "`If-Match` names a stale version", body = Problem),
+        (status = 422, description = "Validation failed", body = Problem),
+        (status = 503, description = "The verification token could not be sent; save again to resend it", body = Problem),
+    ),
+    security(("session" = [])),
+)]
 #[put("/users/")]
 async fn update(
-    user: web::Json<UserMessage>,
+    profile: web::Json<ProfileUpdate>,
+    users: web::Data<dyn UserRepo>,
+    mailer: web::Data<dyn Mailer>,
+    identity: AuthUser,
+    id: Identity,
+    if_match: IfMatchVersion,
+) -> Result<HttpResponse, ApiError> {
+    let profile = profile.into_inner();
+    let users = users.into_inner();
+    let (user, token) = db::run(move || users.update(identity.id, profile, if_match.0)).await?;
+
+    if let (Some(pending_email), Some(token)) = (&user.pending_email, token) {
+        mailer
+            .send(Email::verification(pending_email, &token))
+            .await?;
+    }
+
+    let user_string = serde_json::to_string(&AuthUser::from(user.clone())).unwrap();
+    id.remember(user_string);
+
+    Ok(tagged_response(user.data_version, &user))
+}
+
+#[utoipa::path(
+    tag = "users",
+    operation_id = "verify_email",
+    request_body = EmailVerification,
+    responses(
+        (status = 200, description = "The pending email address is now the account's", body = User),
+        (status = 400, description = "Invalid or expired token", body = Problem),
+        (status = 401, description = "Not signed in", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/email/verify/")]
+async fn verify_email(
+    verification: web::Json<EmailVerification>,
+    users: web::Data<dyn UserRepo>,
     identity: AuthUser,
+    id: Identity,
 ) -> Result<HttpResponse, ApiError> {
-    let user = User::update(identity.id, user.into_inner())?;
-    Ok(HttpResponse::Ok().json(user))
+    let token = verification.into_inner().token;
+    let users = users.into_inner();
+    let user = db::run(move || users.verify_email(identity.id, &token)).await?;
+
+    // The session carries the email, so refresh it with the confirmed address
+    let user_string = serde_json::to_string(&AuthUser::from(user.clone())).unwrap();
+    id.remember(user_string);
+
+    Ok(tagged_response(user.data_version, &user))
+}
+
+#[utoipa::path(
+    tag = "users",
+    operation_id = "change_password",
+    request_body = PasswordChange,
+    responses(
+        (status = 200, description = "Password changed", body = User),
+        (status = 401, description = "Not signed in, or the current password is wrong", body = Problem),
+        (status = 422, description = "Validation failed", body = Problem),
+        (status = 429, description = "Too many failed attempts", body = Problem),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/password/")]
+async fn change_password(
+    change: web::Json<PasswordChange>,
+    users: web::Data<dyn UserRepo>,
+    identity: AuthUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let change = change.into_inner();
+    let ip_address = client_ip(&req);
+    let users = users.into_inner();
+
+    let user = db::run(move || {
+        let user = users.find(identity.id)?;
+
+        let attempt = LoginAttempt::begin(&user.username, Some(user.id), &ip_address)?;
+
+        match users.change_password(&user, &change)? {
+            Some(user) => {
+                attempt.finish(OUTCOME_SUCCESS)?;
+                Ok(user)
+            }
+            None => {
+                attempt.finish(OUTCOME_FAILURE)?;
+                Err(ApiError::new(
+                    ErrorCode::InvalidCredentials,
+                    "Current password is incorrect".to_string(),
+                ))
+            }
+        }
+    })
+    .await?;
+
+    Ok(tagged_response(user.data_version, &user))
 }
 
+#[utoipa::path(
+    tag = "users",
+    operation_id = "delete_account",
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified"),
+    ),
+    responses(
+        (status = 200, description = "Account deleted and signed out", body = Deleted),
+        (status = 401, description = "Not signed in", body = Problem),
+        (status = 412, description = "`If-Match` names a stale version", body = Problem),
+    ),
+    security(("session" = [])),
+)]
 #[delete("/users/")]
-async fn delete(identity: AuthUser, id: Identity) -> Result<HttpResponse, ApiError> {
-    let num_deleted = User::delete(identity.id)?;
+async fn delete(
+    users: web::Data<dyn UserRepo>,
+    identity: AuthUser,
+    id: Identity,
+    if_match: IfMatchVersion,
+) -> Result<HttpResponse, ApiError> {
+    let users = users.into_inner();
+    let num_deleted = db::run(move || users.delete(identity.id, if_match.0)).await?;
     id.forget();
-    Ok(HttpResponse::Ok().json(json!({ "deleted": num_deleted })))
+    Ok(HttpResponse::Ok().json(Deleted {
+        deleted: num_deleted,
+    }))
 }
 
 pub fn user_routes(cfg: &mut web::ServiceConfig) {
@@ -88,5 +322,126 @@ pub fn user_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(register);
     cfg.service(sign_in);
     cfg.service(update);
+    cfg.service(verify_email);
+    cfg.service(change_password);
     cfg.service(delete);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::user_routes;
+    use crate::handlers::test_support::{register, session, TestRepos};
+    use crate::repos::UserRepo;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn registering_signs_in() {
+        let repos = TestRepos::default();
+        let app = test::init_service(repos.app().configure(user_routes)).await;
+
+        let res = test::call_service(&app, register("alice").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = session(&res);
+        let body: Value = test::read_body_json(res).await;
+
+        let req = TestRequest::get()
+            .uri(&format!("/users/{}/", body["id"].as_str().unwrap()))
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"1\"");
+        let user: Value = test::read_body_json(res).await;
+        assert_eq!(user["username"], "alice");
+        assert!(user.get("password").is_none());
+    }
+
+    #[actix_web::test]
+    async fn taken_username_is_a_validation_error() {
+        let repos = TestRepos::default();
+        let app = test::init_service(repos.app().configure(user_routes)).await;
+
+        test::call_service(&app, register("alice").to_request()).await;
+        let req = TestRequest::post()
+            .uri("/register/")
+            .set_json(json!({
+                "email": "other@example.com",
+                "password": "correct horse 42",
+                "name": "Alice",
+                "username": "alice",
+            }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(problem["errors"]["username"][0], "is already taken");
+        assert_eq!(repos.users.find_all().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn stale_update_is_rejected() {
+        let repos = TestRepos::default();
+        let app = test::init_service(repos.app().configure(user_routes)).await;
+        let cookie = session(&test::call_service(&app, register("alice").to_request()).await);
+
+        let update = |name: &str| {
+            TestRequest::put()
+                .uri("/users/")
+                .cookie(cookie.clone())
+                .insert_header((header::IF_MATCH, "\"1\""))
+                .set_json(json!({
+                    "email": "alice@example.com",
+                    "name": name,
+                    "username": "alice",
+                }))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, update("Alice")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"2\"");
+
+        let res = test::call_service(&app, update("Alicia")).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let users = repos.users.find_all().unwrap();
+        assert_eq!(users[0].name, "Alice");
+    }
+
+    #[actix_web::test]
+    async fn changing_email_leaves_it_pending() {
+        let repos = TestRepos::default();
+        let app = test::init_service(repos.app().configure(user_routes)).await;
+        let cookie = session(&test::call_service(&app, register("alice").to_request()).await);
+
+        let req = TestRequest::put()
+            .uri("/users/")
+            .cookie(cookie)
+            .set_json(json!({
+                "email": "New@Example.com",
+                "name": "alice",
+                "username": "alice",
+            }))
+            .to_request();
+        let user: Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(user["email"], "alice@example.com");
+        assert_eq!(user["pending_email"], "new@example.com");
+        let mail = repos.mailer.take().expect("no verification was sent");
+        assert_eq!(mail.to, "new@example.com");
+    }
+
+    #[actix_web::test]
+    async fn profile_requires_a_session() {
+        let repos = TestRepos::default();
+        let app = test::init_service(repos.app().configure(user_routes)).await;
+
+        let req = TestRequest::delete().uri("/users/").to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,426 @@
+use super::{apply_status, JobDispatcher};
+use crate::api_error::ApiError;
+use crate::config::LocalRunnerConfig;
+use crate::db;
+use crate::models::{
+    is_valid_path, JobStatus, ProjectFile, SubmissionWorker, STATUS_CANCELLED, STATUS_DONE,
+    STATUS_FAILED, STATUS_RUNNING,
+};
+use actix_web::web;
+use futures::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Cancellation flags of the runs in progress by submission and attempt,
+/// checked while waiting on them.
+type Running = Arc<Mutex<HashMap<(Uuid, i32), Arc<AtomicBool>>>>;
+
+/// Runs jobs as subprocesses on this machine, under the resource limits of
+/// `[jobs.local]`, and applies their results directly.
+pub struct LocalDispatcher {
+    config: LocalRunnerConfig,
+    running: Running,
+}
+
+impl LocalDispatcher {
+    pub fn new(config: LocalRunnerConfig) -> Self {
+        LocalDispatcher {
+            config,
+            running: Arc::default(),
+        }
+    }
+
+    fn work_dir(&self) -> PathBuf {
+        self.config
+            .work_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+impl JobDispatcher for LocalDispatcher {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn dispatch(&self, job: SubmissionWorker) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        let config = self.config.clone();
+        let dir = self.work_dir().join(format!("dune-{}", Uuid::new_v4()));
+        let running = self.running.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let key = (job.id, job.attempt);
+        running.lock().unwrap().insert(key, cancelled.clone());
+
+        // Answers as soon as the job is accepted, like a publish would
+        actix_rt::spawn(
+            async move {
+                if let Err(e) = execute(config, dir, job, cancelled.clone()).await {
+                    error!("Local run of {} could not be recorded: {}", key.0, e);
+                }
+
+                let mut running = running.lock().unwrap();
+                // Unless a newer run of the submission took its place
+                if running
+                    .get(&key)
+                    .is_some_and(|flag| Arc::ptr_eq(flag, &cancelled))
+                {
+                    running.remove(&key);
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        Box::pin(futures::future::ok(()))
+    }
+
+    fn cancel(&self, id: Uuid, attempt: i32) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        // The run's wait loop does the killing, while its process is sure to
+        // still be around
+        if let Some(cancelled) = self.running.lock().unwrap().get(&(id, attempt)) {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+
+        Box::pin(futures::future::ok(()))
+    }
+
+    fn check(&self) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        let work_dir = self.work_dir();
+
+        Box::pin(async move {
+            match work_dir.is_dir() {
+                true => Ok(()),
+                false => Err(ApiError::internal(format!(
+                    "Work directory {} does not exist",
+                    work_dir.display()
+                ))),
+            }
+        })
+    }
+}
+
+async fn execute(
+    config: LocalRunnerConfig,
+    dir: PathBuf,
+    job: SubmissionWorker,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), ApiError> {
+    let (id, attempt) = (job.id, Some(job.attempt));
+    db::run(move || {
+        apply_status(JobStatus {
+            attempt,
+            ..JobStatus::new(id, STATUS_RUNNING)
+        })
+    })
+    .await?;
+
+    let span = Span::current();
+    let outcome = web::block(move || {
+        span.in_scope(|| {
+            let outcome = run(&config, &dir, &job, &cancelled);
+            // Missing when the job was turned down before anything was written
+            if let Err(e) = fs::remove_dir_all(&dir).or_else(ignore_missing) {
+                warn!("Failed to remove {}: {}", dir.display(), e);
+            }
+            outcome
+        })
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Blocking task failed: {}", e)))?;
+
+    let result = match outcome {
+        Ok(result) => result,
+        Err(e) => JobStatus {
+            attempt,
+            error: e,
+            ..JobStatus::new(id, STATUS_FAILED)
+        },
+    };
+    db::run(move || apply_status(result)).await
+}
+
+/// Lays out a project's files under `dir`. Their paths were checked when saved,
+/// and are again here, where a stray `..` would write outside the run's directory.
+fn write_project(dir: &Path, files: &[ProjectFile]) -> Result<(), String> {
+    for file in files {
+        if !is_valid_path(&file.path) {
+            return Err(format!("Invalid file path {:?}", file.path));
+        }
+
+        let path = dir.join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        fs::write(&path, &file.content)
+            .map_err(|e| format!("Failed to save {}: {}", file.path, e))?;
+        if file.executable {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+                .map_err(|e| format!("Failed to make {} executable: {}", file.path, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs one job to completion. `Err` means it never got to run.
+fn run(
+    config: &LocalRunnerConfig,
+    dir: &Path,
+    job: &SubmissionWorker,
+    cancelled: &AtomicBool,
+) -> Result<JobStatus, String> {
+    let language = config
+        .languages
+        .get(&job.language)
+        .ok_or_else(|| format!("Language {:?} is not supported", job.language))?;
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let mut argv = language.command.clone();
+    match &job.entrypoint {
+        Some(entrypoint) => {
+            write_project(dir, &job.files)?;
+            // The command names the language's usual file; a project runs its own
+            for arg in argv.iter_mut().filter(|arg| **arg == language.file) {
+                *arg = entrypoint.clone();
+            }
+        }
+        None => fs::write(dir.join(&language.file), &job.code)
+            .map_err(|e| format!("Failed to save the code: {}", e))?,
+    }
+    let stdin_path = dir.join(".stdin");
+    fs::write(&stdin_path, &job.stdin).map_err(|e| format!("Failed to save the input: {}", e))?;
+    let stdin = File::open(&stdin_path).map_err(|e| e.to_string())?;
+    let stdout_path = dir.join(".stdout");
+    let stderr_path = dir.join(".stderr");
+    let stdout = File::create(&stdout_path).map_err(|e| e.to_string())?;
+    let stderr = File::create(&stderr_path).map_err(|e| e.to_string())?;
+
+    let limits = Limits::from(config);
+    let mut command = Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .args(&job.args)
+        .current_dir(dir)
+        .env_clear()
+        // Checked against the allow-list when saved; PATH and HOME stay ours
+        .envs(job.env.iter().filter_map(|entry| entry.split_once('=')))
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .env("HOME", dir)
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr);
+    // Only async-signal-safe calls from here on: this runs in the forked child
+    unsafe {
+        command.pre_exec(move || limits.apply());
+    }
+
+    let started = Instant::now();
+    let child = command
+        .spawn()
+        .map_err(|e| format!("Failed to start {}: {}", argv[0], e))?;
+    let (exit, usage) = wait(child.id() as libc::pid_t, config.timeout_seconds, cancelled)
+        .map_err(|e| format!("Failed to wait for the run: {}", e))?;
+    let elapsed = started.elapsed();
+
+    let output_limit = config.output_kb * 1024;
+    let mut result = JobStatus {
+        stdout: read_output(&stdout_path, output_limit),
+        stderr: read_output(&stderr_path, output_limit),
+        exec_duration: elapsed.as_millis().min(i32::MAX as u128) as i32,
+        // Kilobytes on Linux
+        mem_usage: usage.ru_maxrss,
+        attempt: Some(job.attempt),
+        ..JobStatus::new(job.id, STATUS_DONE)
+    };
+
+    match exit {
+        Exit::Code(0) => {}
+        Exit::Code(code) => {
+            result.status = STATUS_FAILED.to_string();
+            result.error = format!("Exited with status {}", code);
+        }
+        Exit::Signal(libc::SIGXCPU) => {
+            result.status = STATUS_FAILED.to_string();
+            result.error = format!("CPU time limit of {}s exceeded", config.cpu_seconds);
+        }
+        Exit::Signal(libc::SIGXFSZ) => {
+            result.status = STATUS_FAILED.to_string();
+            result.error = format!("Output limit of {} KB exceeded", config.output_kb);
+        }
+        Exit::Signal(signal) => {
+            result.status = STATUS_FAILED.to_string();
+            result.error = format!("Killed by signal {}", signal);
+        }
+        Exit::TimedOut => {
+            result.status = STATUS_FAILED.to_string();
+            result.error = format!("Timed out after {}s", config.timeout_seconds);
+        }
+        Exit::Cancelled => {
+            result.status = STATUS_CANCELLED.to_string();
+            result.message = "Cancelled".to_string();
+        }
+    }
+
+    Ok(result)
+}
+
+enum Exit {
+    Code(i32),
+    Signal(i32),
+    TimedOut,
+    Cancelled,
+}
+
+/// Reaps the child, killing its whole process group once `timeout_seconds` have
+/// passed or the run is cancelled, and returns what it used.
+fn wait(
+    pid: libc::pid_t,
+    timeout_seconds: u64,
+    cancelled: &AtomicBool,
+) -> io::Result<(Exit, libc::rusage)> {
+    let deadline = Instant::now() + Duration::from_secs(timeout_seconds);
+    let mut killed = None;
+
+    loop {
+        let mut wait_status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let flags = if killed.is_some() { 0 } else { libc::WNOHANG };
+        let reaped = unsafe { libc::wait4(pid, &mut wait_status, flags, &mut usage) };
+
+        if reaped == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if reaped == pid {
+            let exit = if let Some(exit) = killed {
+                exit
+            } else if libc::WIFSIGNALED(wait_status) {
+                Exit::Signal(libc::WTERMSIG(wait_status))
+            } else {
+                Exit::Code(libc::WEXITSTATUS(wait_status))
+            };
+            return Ok((exit, usage));
+        }
+
+        if cancelled.load(Ordering::SeqCst) {
+            killed = Some(Exit::Cancelled);
+        } else if Instant::now() >= deadline {
+            killed = Some(Exit::TimedOut);
+        }
+        if killed.is_some() {
+            // The child leads its own group, so this also gets anything it forked
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        } else {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn ignore_missing(error: io::Error) -> io::Result<()> {
+    match error.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(error),
+    }
+}
+
+fn read_output(path: &Path, limit: u64) -> String {
+    let mut output = Vec::new();
+    if let Ok(file) = File::open(path) {
+        let _ = file.take(limit).read_to_end(&mut output);
+    }
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// What the child is held to, set between fork and exec.
+#[derive(Clone, Copy)]
+struct Limits {
+    cpu_seconds: u64,
+    memory_bytes: u64,
+    output_bytes: u64,
+    isolate_network: bool,
+}
+
+impl From<&LocalRunnerConfig> for Limits {
+    fn from(config: &LocalRunnerConfig) -> Self {
+        Limits {
+            cpu_seconds: config.cpu_seconds,
+            memory_bytes: config.memory_mb * 1024 * 1024,
+            output_bytes: config.output_kb * 1024,
+            isolate_network: config.isolate_network,
+        }
+    }
+}
+
+impl Limits {
+    fn apply(&self) -> io::Result<()> {
+        // Its own process group, so a timeout can kill everything it started
+        check(unsafe { libc::setpgid(0, 0) })?;
+
+        // SIGXCPU at the soft limit, so the run can be told apart from a kill
+        set_limit(libc::RLIMIT_CPU, self.cpu_seconds, self.cpu_seconds + 1)?;
+        // Address space would also count what runtimes such as V8 merely reserve
+        set_limit(libc::RLIMIT_DATA, self.memory_bytes, self.memory_bytes)?;
+        set_limit(libc::RLIMIT_FSIZE, self.output_bytes, self.output_bytes)?;
+        set_limit(libc::RLIMIT_CORE, 0, 0)?;
+
+        if self.isolate_network {
+            isolate_network()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn isolate_network() -> io::Result<()> {
+    // A new user namespace is what lets an unprivileged process have its own
+    // (empty) network namespace
+    check(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn isolate_network() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network isolation is only available on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_os = "linux"))]
+type Resource = libc::c_int;
+
+fn set_limit(resource: Resource, soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+
+    check(unsafe { libc::setrlimit(resource, &limit) })
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,297 @@
+use crate::amqp;
+use crate::api_error::ApiError;
+use crate::config::{AmqpConfig, Config, JobBackend, JobsConfig};
+use crate::db;
+use crate::metrics;
+use crate::models::{
+    JobAttempt, JobStatus, RunInput, RunUsage, ScratchRun, Submission, SubmissionFile,
+    SubmissionWorker, STATUS_CANCELLED, STATUS_CANCELLING, STATUS_FAILED, STATUS_TIMED_OUT,
+};
+use chrono::Utc;
+use futures::future::{ok, LocalBoxFuture};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::Instrument;
+use uuid::Uuid;
+
+mod local;
+mod scheduling;
+
+pub use local::LocalDispatcher;
+pub use scheduling::RunClass;
+
+/// Hands submissions to whatever runs them. Handlers take it as
+/// `web::Data<dyn JobDispatcher>`; results come back through `apply_status`.
+pub trait JobDispatcher: Send + Sync {
+    /// Identifies the backend, e.g. as the key of its readiness check.
+    fn name(&self) -> &'static str;
+
+    fn dispatch(&self, job: SubmissionWorker) -> LocalBoxFuture<'static, Result<(), ApiError>>;
+
+    /// Drops that attempt's job if it has not started, or stops its run. A
+    /// stopped run is expected to report `cancelled`, unless it finished first.
+    fn cancel(&self, id: Uuid, attempt: i32) -> LocalBoxFuture<'static, Result<(), ApiError>>;
+
+    /// Whether jobs can be dispatched right now.
+    fn check(&self) -> LocalBoxFuture<'static, Result<(), ApiError>>;
+}
+
+/// The dispatcher `[jobs] backend` selects.
+pub fn from_config(config: &Config) -> Arc<dyn JobDispatcher> {
+    match config.jobs.backend {
+        JobBackend::Amqp => Arc::new(AmqpDispatcher::new(config.amqp.clone())),
+        JobBackend::Local => Arc::new(LocalDispatcher::new(config.jobs.local.clone())),
+    }
+}
+
+/// Publishes jobs to the worker pool through RabbitMQ. Status updates arrive on
+/// `amqp::consume_job_status`.
+pub struct AmqpDispatcher {
+    config: AmqpConfig,
+}
+
+impl AmqpDispatcher {
+    pub fn new(config: AmqpConfig) -> Self {
+        AmqpDispatcher { config }
+    }
+}
+
+impl JobDispatcher for AmqpDispatcher {
+    fn name(&self) -> &'static str {
+        "amqp"
+    }
+
+    fn dispatch(&self, job: SubmissionWorker) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        let config = self.config.clone();
+        Box::pin(async move { amqp::publish_job(&config, &job).await })
+    }
+
+    fn cancel(&self, id: Uuid, attempt: i32) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        let config = self.config.clone();
+        Box::pin(async move { amqp::publish_cancel(&config, id, attempt).await })
+    }
+
+    fn check(&self) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        let config = self.config.clone();
+        Box::pin(async move { amqp::check_connection(&config).await })
+    }
+}
+
+/// Keeps dispatched jobs in a queue for the caller to play the worker: take a
+/// job, then `report` its status updates. Like the AMQP jobs queue, it hands
+/// out higher priorities first.
+#[derive(Default)]
+pub struct MemoryJobBus {
+    jobs: Mutex<VecDeque<SubmissionWorker>>,
+    cancelled: Mutex<Vec<(Uuid, i32)>>,
+}
+
+impl MemoryJobBus {
+    /// The oldest of the highest priority jobs nobody has taken yet.
+    pub fn take(&self) -> Option<SubmissionWorker> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let highest = jobs.iter().map(|job| job.priority).max()?;
+        let next = jobs.iter().position(|job| job.priority == highest)?;
+
+        jobs.remove(next)
+    }
+
+    /// The attempts a cancellation went out for, in order.
+    pub fn cancelled(&self) -> Vec<(Uuid, i32)> {
+        self.cancelled.lock().unwrap().clone()
+    }
+
+    /// Applies a status update as if the worker had published it.
+    pub async fn report(&self, status: JobStatus) -> Result<(), ApiError> {
+        db::run(move || apply_status(status)).await
+    }
+}
+
+impl JobDispatcher for MemoryJobBus {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn dispatch(&self, job: SubmissionWorker) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        self.jobs.lock().unwrap().push_back(job);
+        Box::pin(ok(()))
+    }
+
+    fn cancel(&self, id: Uuid, attempt: i32) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|job| (job.id, job.attempt) != (id, attempt));
+        self.cancelled.lock().unwrap().push((id, attempt));
+        Box::pin(ok(()))
+    }
+
+    fn check(&self) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        Box::pin(ok(()))
+    }
+}
+
+/// Records a status update from the worker, along with the run's metrics and
+/// CPU time once it has finished.
+pub fn apply_status(status: JobStatus) -> Result<(), ApiError> {
+    // Scratch runs share the job ID space with submissions
+    if let Some(run) = ScratchRun::apply_status(&status)? {
+        metrics::count_status(&run.status);
+        if status.is_terminal() && status.exec_duration > 0 {
+            metrics::observe_run(&run.language, status.exec_duration as f64 / 1000.0);
+            RunUsage::record_cpu(run.user_id, status.exec_duration as i64)?;
+        }
+        return Ok(());
+    }
+
+    // e.g. a cancelled run confirming after the submission was run again
+    if let Some(attempt) = status.attempt {
+        if !JobAttempt::is_open(status.id, attempt)? {
+            info!(
+                "Ignoring {} from closed attempt {} of submission {}",
+                status.status, attempt, status.id
+            );
+            return Ok(());
+        }
+    }
+
+    let submission = match Submission::apply_status(&status)? {
+        Some(submission) => submission,
+        None => return Ok(()),
+    };
+
+    metrics::count_status(&submission.status);
+
+    if status.is_terminal() {
+        JobAttempt::finish_open(submission.id, &submission.status)?;
+        if let Some(queued_at) = submission.queued_at {
+            let waited = Utc::now().naive_utc() - queued_at;
+            metrics::observe_queue_to_done(
+                &submission.language,
+                &submission.status,
+                waited.num_milliseconds().max(0) as f64 / 1000.0,
+            );
+        }
+        if status.exec_duration > 0 {
+            metrics::observe_run(&submission.language, status.exec_duration as f64 / 1000.0);
+            RunUsage::record_cpu(submission.user_id, status.exec_duration as i64)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives up on a run that could not be dispatched and fails it. The reason is
+/// for the logs only, since anyone may read a submission.
+pub async fn abandon(attempt: JobAttempt) -> Result<(), ApiError> {
+    db::run(move || {
+        attempt.finish(STATUS_FAILED)?;
+        apply_status(JobStatus {
+            error: "The run could not be dispatched".to_string(),
+            ..JobStatus::new(attempt.submission_id, STATUS_FAILED)
+        })
+    })
+    .await
+}
+
+/// Looks for overdue runs every `reap_interval_seconds`, for as long as the
+/// server is up.
+pub async fn run_reaper(jobs: Arc<dyn JobDispatcher>, config: JobsConfig) {
+    let mut interval = actix_rt::time::interval(Duration::from_secs(config.reap_interval_seconds));
+
+    loop {
+        interval.tick().await;
+
+        let span = tracing::info_span!("jobs.reap");
+        if let Err(e) = reap(jobs.as_ref(), &config).instrument(span).await {
+            error!("Failed to reap overdue jobs: {}", e);
+        }
+    }
+}
+
+/// Times out every attempt past its deadline. Its run is dispatched again while
+/// `max_attempts` allows, and marked `timed_out` after that. A run still being
+/// cancelled is marked `cancelled` instead, whatever became of it. Overdue
+/// scratch runs are timed out straight away, and expired ones deleted.
+pub async fn reap(jobs: &dyn JobDispatcher, config: &JobsConfig) -> Result<(), ApiError> {
+    let (timed_out, purged) = db::run(|| {
+        Ok((
+            ScratchRun::time_out_overdue()?,
+            ScratchRun::purge_expired()?,
+        ))
+    })
+    .await?;
+    for run in &timed_out {
+        warn!("Scratch run {} timed out", run.id);
+        metrics::count_status(&run.status);
+    }
+    if purged > 0 {
+        info!("Purged {} expired scratch runs", purged);
+    }
+
+    let overdue = db::run(JobAttempt::find_overdue).await?;
+
+    for attempt in overdue {
+        let max_attempts = config.max_attempts;
+        let timeout_seconds = config.timeout_seconds;
+
+        let retry = db::run(move || {
+            if !attempt.finish(STATUS_TIMED_OUT)? {
+                return Ok(None);
+            }
+            warn!(
+                "Attempt {} at running {} timed out",
+                attempt.attempt, attempt.submission_id
+            );
+
+            if Submission::find(attempt.submission_id)?.status == STATUS_CANCELLING {
+                apply_status(JobStatus {
+                    message: "The worker never confirmed the cancellation".to_string(),
+                    ..JobStatus::new(attempt.submission_id, STATUS_CANCELLED)
+                })?;
+                return Ok(None);
+            }
+
+            if attempt.attempt >= max_attempts {
+                apply_status(JobStatus {
+                    error: format!("No result after {} attempts", attempt.attempt),
+                    ..JobStatus::new(attempt.submission_id, STATUS_TIMED_OUT)
+                })?;
+                return Ok(None);
+            }
+
+            let submission = match Submission::requeue(attempt.submission_id)? {
+                Some(submission) => submission,
+                None => return Ok(None),
+            };
+            metrics::count_status(&submission.status);
+            // Queued and run as before: a retry is no reason to jump ahead, or
+            // fall back, nor to take up input edited in the meantime
+            let next = JobAttempt::start(
+                submission.id,
+                timeout_seconds,
+                &attempt.run_class,
+                attempt.priority,
+                RunInput::from(&attempt),
+            )?;
+
+            let files = SubmissionFile::find_by_submission(submission.id)?;
+
+            Ok(Some((submission, files, next)))
+        })
+        .await?;
+
+        if let Some((submission, files, next)) = retry {
+            if let Err(e) = jobs
+                .dispatch(SubmissionWorker::new(&submission, files, &next))
+                .await
+            {
+                error!("Failed to dispatch {} again: {}", submission.id, e);
+                abandon(next).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use crate::config::PriorityConfig;
+use crate::models::Benchmark;
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+/// Why a run is wanted, which decides how soon the worker pool gets to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunClass {
+    /// A submission to a contest benchmark while the contest is open. Anyone
+    /// signed in may enter, so outside the window an administrator set, a
+    /// contest benchmark is no faster than any other.
+    Contest,
+    /// The author of a contest benchmark running a submission of their own
+    /// against it, e.g. to validate the reference solution before the contest
+    /// opens. Authors of other benchmarks get no head start, as anyone can
+    /// create one.
+    Grading,
+    /// Everything else.
+    Practice,
+}
+
+impl RunClass {
+    pub fn classify(benchmark: Option<&Benchmark>, user_id: Uuid, now: NaiveDateTime) -> Self {
+        match benchmark {
+            Some(benchmark) if benchmark.contest && benchmark.creator_id == Some(user_id) => {
+                RunClass::Grading
+            }
+            Some(benchmark) if benchmark.contest_open(now) => RunClass::Contest,
+            _ => RunClass::Practice,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunClass::Contest => "contest",
+            RunClass::Grading => "grading",
+            RunClass::Practice => "practice",
+        }
+    }
+
+    /// The class's priority, lowered a step for each run the user already has
+    /// in flight, up to `fair_share_steps`.
+    pub fn priority(self, in_flight: i64, config: &PriorityConfig) -> u8 {
+        let base = match self {
+            RunClass::Contest => config.contest,
+            RunClass::Grading => config.grading,
+            RunClass::Practice => config.practice,
+        };
+        let steps = in_flight.clamp(0, config.fair_share_steps as i64) as u8;
+
+        base.saturating_sub(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    fn benchmark(creator_id: Uuid, contest: bool) -> Benchmark {
+        let now = Utc::now().naive_utc();
+
+        Benchmark {
+            id: Uuid::new_v4(),
+            title: "Sorting".to_string(),
+            subject: "Sort a list".to_string(),
+            difficulty: "easy".to_string(),
+            creator_id: Some(creator_id),
+            git_url: None,
+            max_cyclomatic_complex: 10,
+            created_at: Utc::now().naive_utc(),
+            updated_at: None,
+            data_version: 1,
+            contest,
+            contest_opens_at: Some(now - Duration::hours(1)),
+            contest_closes_at: Some(now + Duration::hours(1)),
+        }
+    }
+
+    #[test]
+    fn only_contest_authors_grade() {
+        let author = Uuid::new_v4();
+        let contestant = Uuid::new_v4();
+        let now = Utc::now().naive_utc();
+
+        let contest = benchmark(author, true);
+        assert_eq!(
+            RunClass::classify(Some(&contest), author, now),
+            RunClass::Grading
+        );
+        assert_eq!(
+            RunClass::classify(Some(&contest), contestant, now),
+            RunClass::Contest
+        );
+
+        let practice = benchmark(author, false);
+        assert_eq!(
+            RunClass::classify(Some(&practice), author, now),
+            RunClass::Practice
+        );
+        assert_eq!(RunClass::classify(None, author, now), RunClass::Practice);
+    }
+
+    #[test]
+    fn contests_only_go_first_while_open() {
+        let author = Uuid::new_v4();
+        let contestant = Uuid::new_v4();
+        let contest = benchmark(author, true);
+        let opens_at = contest.contest_opens_at.unwrap();
+        let closes_at = contest.contest_closes_at.unwrap();
+
+        for at in [opens_at - Duration::seconds(1), closes_at] {
+            assert_eq!(
+                RunClass::classify(Some(&contest), contestant, at),
+                RunClass::Practice
+            );
+            assert_eq!(
+                RunClass::classify(Some(&contest), author, at),
+                RunClass::Grading
+            );
+        }
+        assert_eq!(
+            RunClass::classify(Some(&contest), contestant, opens_at),
+            RunClass::Contest
+        );
+
+        let unscheduled = Benchmark {
+            contest_opens_at: None,
+            contest_closes_at: None,
+            ..contest
+        };
+        assert_eq!(
+            RunClass::classify(Some(&unscheduled), contestant, opens_at),
+            RunClass::Practice
+        );
+    }
+
+    #[test]
+    fn runs_in_flight_lower_the_priority_within_the_class() {
+        let config = PriorityConfig::default();
+
+        let practice: Vec<_> = (0..5)
+            .map(|in_flight| RunClass::Practice.priority(in_flight, &config))
+            .collect();
+        assert_eq!(practice, [3, 2, 1, 1, 1]);
+
+        // A busy contestant still goes ahead of anyone's practice runs
+        assert!(RunClass::Contest.priority(100, &config) > RunClass::Grading.priority(0, &config));
+        assert!(RunClass::Grading.priority(100, &config) > RunClass::Practice.priority(0, &config));
+    }
+}
@@ -0,0 +1,175 @@
+// diesel 1.x derives expand to impls inside anonymous consts
+#![allow(non_local_definitions)]
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
+use actix_cors::Cors;
+use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, App, Error};
+use api_error::{ApiError, ErrorCode};
+use config::{Config, LimitsConfig, ServerConfig};
+use jobs::JobDispatcher;
+use mailer::Mailer;
+use metrics::HttpMetrics;
+use rate_limit::RateLimiter;
+use repos::Repos;
+use request_id::RequestId;
+use std::sync::Arc;
+use std::time::Duration;
+use telemetry::RequestTracing;
+
+pub mod amqp;
+mod api_error;
+mod client_ip;
+pub mod config;
+pub mod db;
+mod handlers;
+pub mod jobs;
+pub mod mailer;
+pub mod metrics;
+pub mod models;
+mod oauth;
+mod rate_limit;
+pub mod repos;
+mod request_id;
+mod schema;
+pub mod telemetry;
+mod totp;
+mod validation;
+
+/// What every worker's `App` is built from. Created once, so that state such as
+/// the rate limiter's buckets is shared between the workers.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: web::Data<Config>,
+    pub repos: Repos,
+    pub jobs: Arc<dyn JobDispatcher>,
+    pub mailer: Arc<dyn Mailer>,
+    rate_limiter: RateLimiter,
+}
+
+impl AppState {
+    pub fn new(config: Config, jobs: Arc<dyn JobDispatcher>, mailer: Arc<dyn Mailer>) -> Self {
+        // Keys were checked when the config was validated
+        let rate_limiter = config
+            .limits
+            .routes
+            .iter()
+            .filter_map(|(key, limit)| Some((LimitsConfig::route(key)?, limit)))
+            .fold(RateLimiter::new(), |limiter, ((method, pattern), limit)| {
+                limiter.rule(
+                    method,
+                    pattern,
+                    limit.burst,
+                    Duration::from_secs(limit.refill_seconds),
+                )
+            });
+
+        AppState {
+            config: web::Data::new(config),
+            repos: Repos::postgres(),
+            jobs,
+            mailer,
+            rate_limiter,
+        }
+    }
+}
+
+/// The whole API, middleware included.
+pub fn app(
+    state: &AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let config = &state.config;
+    let repos = state.repos.clone();
+
+    App::new()
+        .app_data(config.clone())
+        .app_data(web::Data::from(state.jobs.clone()))
+        .app_data(web::Data::from(state.mailer.clone()))
+        .configure(|cfg| repos.register(cfg))
+        // so malformed input gets the same problem+json body as any other error
+        .app_data(
+            web::JsonConfig::default()
+                .error_handler(|e, _| ApiError::new(ErrorCode::BadRequest, e.to_string()).into()),
+        )
+        .app_data(
+            web::QueryConfig::default()
+                .error_handler(|e, _| ApiError::new(ErrorCode::BadRequest, e.to_string()).into()),
+        )
+        .app_data(
+            web::PathConfig::default()
+                .error_handler(|e, _| ApiError::new(ErrorCode::NotFound, e.to_string()).into()),
+        )
+        // registered first so it runs inside IdentityService and can see the session
+        .wrap(state.rate_limiter.clone())
+        .wrap(HttpMetrics)
+        .wrap(RequestTracing)
+        .wrap(IdentityService::new(
+            CookieIdentityPolicy::new(config.server.secret_token.as_bytes())
+                .name("auth")
+                .path("/")
+                .domain(config.server.domain.as_str())
+                .secure(config.server.secure_cookie),
+        ))
+        // outside everything that may log or fail, so they all see the ID
+        .wrap(RequestId)
+        .wrap(cors(&config.server))
+        .configure(handlers::health_routes)
+        .configure(handlers::metrics_routes)
+        .configure(handlers::openapi_routes)
+        .service(
+            web::scope("/api")
+                // ahead of user_routes so /users/identities/ is not taken for /users/{id}/
+                .configure(handlers::oauth_routes)
+                .configure(handlers::two_factor_routes)
+                .configure(handlers::user_routes)
+                .configure(handlers::submission_routes)
+                .configure(handlers::scratch_routes)
+                .configure(handlers::benchmark_routes)
+                .configure(handlers::admin_routes),
+        )
+}
+
+/// Browsers may only call the API with the session cookie from the configured
+/// origins; without any, cross-origin requests are refused.
+fn cors(config: &ServerConfig) -> Cors {
+    config
+        .cors_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+        .allowed_headers(vec![
+            header::CONTENT_TYPE,
+            header::IF_MATCH,
+            header::IF_NONE_MATCH,
+            header::HeaderName::from_static(request_id::HEADER),
+        ])
+        .expose_headers(vec![
+            "ETag",
+            "Retry-After",
+            "X-RateLimit-Limit",
+            "X-RateLimit-Remaining",
+            "X-Quota-Runs-Remaining",
+            "X-Quota-Cpu-Seconds-Remaining",
+            "X-Quota-Reset",
+            "X-Request-Id",
+        ])
+        .supports_credentials()
+        .max_age(3600)
+}
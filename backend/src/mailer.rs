@@ -0,0 +1,130 @@
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::{MailBackend, MailConfig};
+use futures::future::{ok, LocalBoxFuture};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// A plain-text message to a single recipient.
+#[derive(Clone, Debug, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+impl Email {
+    /// The token that confirms a pending email change, sent to the new address.
+    pub fn verification(to: &str, token: &str) -> Self {
+        Email {
+            to: to.to_string(),
+            subject: "Confirm your new email address".to_string(),
+            text: format!(
+                "To make this your account's email address, verify it with this token:\n\n{}\n",
+                token
+            ),
+        }
+    }
+}
+
+/// Delivers messages to users. Handlers take it as `web::Data<dyn Mailer>`.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: Email) -> LocalBoxFuture<'static, Result<(), ApiError>>;
+}
+
+/// The mailer `[mail] backend` selects.
+pub fn from_config(config: &MailConfig) -> Arc<dyn Mailer> {
+    match config.backend {
+        MailBackend::Http => Arc::new(HttpMailer::new(config.clone())),
+        MailBackend::Log => Arc::new(LogMailer),
+    }
+}
+
+/// Hands messages to a mail relay's HTTP API: each is POSTed to `mail.url` as
+/// JSON, `{"from", "to", "subject", "text"}`, with `mail.api_key` as a bearer
+/// token when it is set.
+pub struct HttpMailer {
+    config: MailConfig,
+}
+
+#[derive(Serialize)]
+struct Outgoing<'a> {
+    from: &'a str,
+    #[serde(flatten)]
+    email: &'a Email,
+}
+
+impl HttpMailer {
+    pub fn new(config: MailConfig) -> Self {
+        HttpMailer { config }
+    }
+}
+
+impl Mailer for HttpMailer {
+    fn send(&self, email: Email) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let mut request = awc::Client::default().post(&config.url);
+            if let Some(api_key) = &config.api_key {
+                request = request.bearer_auth(api_key);
+            }
+
+            let response = request
+                .send_json(&Outgoing {
+                    from: &config.from,
+                    email: &email,
+                })
+                .await
+                .map_err(|e| mail_error(e.to_string()))?;
+            if !response.status().is_success() {
+                return Err(mail_error(format!(
+                    "the relay answered {}",
+                    response.status()
+                )));
+            }
+
+            info!("Sent {:?} to {}", email.subject, email.to);
+            Ok(())
+        })
+    }
+}
+
+/// Writes messages to the debug log instead of sending them. For development
+/// only: anyone who can read the log can use the tokens in them.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, email: Email) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        debug!("Mail to {}: {}\n{}", email.to, email.subject, email.text);
+        Box::pin(ok(()))
+    }
+}
+
+/// Keeps sent messages for the caller to read, in place of delivering them.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<VecDeque<Email>>,
+}
+
+impl MemoryMailer {
+    /// The oldest message nobody has read yet.
+    pub fn take(&self) -> Option<Email> {
+        self.sent.lock().unwrap().pop_front()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: Email) -> LocalBoxFuture<'static, Result<(), ApiError>> {
+        self.sent.lock().unwrap().push_back(email);
+        Box::pin(ok(()))
+    }
+}
+
+fn mail_error(reason: String) -> ApiError {
+    error!("Failed to send mail: {}", reason);
+    ApiError::new(
+        ErrorCode::ServiceUnavailable,
+        "The message could not be sent; try again later".to_string(),
+    )
+}
@@ -1,60 +1,61 @@
-#[macro_use]
-extern crate log;
-#[macro_use]
-extern crate diesel;
-#[macro_use]
-extern crate diesel_migrations;
-
-use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::HttpServer;
+use backend::config::{Config, JobBackend};
+use backend::{amqp, db, jobs, mailer, metrics, telemetry, AppState};
 use dotenv::dotenv;
 use listenfd::ListenFd;
-use std::env;
-mod api_error;
-mod db;
-mod handlers;
-mod models;
-mod schema;
+use log::{error, info};
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    env_logger::init();
 
-    let secret_key = env::var("SECRET_TOKEN").expect("SECRET_TOKEN   NOT SET");
-    let domain: String = std::env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    let config = match Config::load() {
+        Ok(config) => config,
+        // Logging is configured from this too, so it is not set up yet
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let tracer_provider = telemetry::init(&config.telemetry);
 
-    db::init();
+    db::init(&config.database);
+    metrics::init(&config.jobs);
+
+    // Other backends apply their results themselves
+    if config.jobs.backend == JobBackend::Amqp {
+        if let Err(e) = amqp::declare_topology(&config.amqp).await {
+            error!("Failed to declare the job queues: {}", e);
+            std::process::exit(1);
+        }
+        actix_rt::spawn(amqp::consume_job_status(config.amqp.clone()));
+        actix_rt::spawn(amqp::consume_dead_letters(config.amqp.clone()));
+    }
 
     let mut listenfd = ListenFd::from_env();
 
-    let mut server = HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::Logger::default())
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(secret_key.as_bytes())
-                    .name("auth")
-                    .path("/")
-                    .domain(domain.as_str())
-                    .secure(false), // this can only be true if you have https
-            ))
-            .service(
-                web::scope("/api")
-                    .configure(handlers::user_routes)
-                    .configure(handlers::submission_routes)
-                    .configure(handlers::benchmark_routes),
-            )
-    });
+    let server_config = config.server.clone();
+    let jobs = jobs::from_config(&config);
+    actix_rt::spawn(jobs::run_reaper(jobs.clone(), config.jobs.clone()));
+    let mailer = mailer::from_config(&config.mail);
+    let state = AppState::new(config, jobs, mailer);
+
+    let mut server = HttpServer::new(move || backend::app(&state));
     server = match listenfd.take_tcp_listener(0)? {
         Some(listener) => server.listen(listener)?,
-        None => {
-            let host: String = env::var("HOST").expect("Host not set");
-            let port: String = env::var("PORT").expect("Port not set");
-            server.bind(format!("{}:{}", host, port))?
-        }
+        None => server.bind((server_config.host.as_str(), server_config.port))?,
     };
 
     info!("Starting server 🚀");
 
-    server.run().await
+    let result = server.run().await;
+
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            error!("Failed to flush traces: {}", e);
+        }
+    }
+
+    result
 }
@@ -0,0 +1,219 @@
+use crate::config::JobsConfig;
+use crate::db;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// Runs range from a few milliseconds to the worker's timeout of several minutes
+const RUN_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+const QUERY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+// Languages come from clients, so anything not configured shares one label
+const OTHER_LANGUAGE: &str = "other";
+
+static LANGUAGES: OnceLock<Vec<String>> = OnceLock::new();
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests handled, by route pattern and response status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time spent handling HTTP requests",
+        &["method", "route"]
+    )
+    .unwrap();
+    static ref POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_connections",
+        "Connections currently open in the database pool"
+    )
+    .unwrap();
+    static ref POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_idle_connections",
+        "Open connections not checked out of the database pool"
+    )
+    .unwrap();
+    static ref POOL_MAX_SIZE: IntGauge =
+        register_int_gauge!("db_pool_max_size", "Connections the database pool may open").unwrap();
+    static ref QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "db_query_duration_seconds",
+        "Time spent executing SQL statements",
+        &["statement", "table"],
+        QUERY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref AMQP_PUBLISHES: IntCounterVec = register_int_counter_vec!(
+        "amqp_publish_total",
+        "Messages published to the broker",
+        &["exchange", "outcome"]
+    )
+    .unwrap();
+    static ref DEAD_LETTERS: IntCounterVec = register_int_counter_vec!(
+        "amqp_dead_letters_total",
+        "Jobs rejected by the worker, by whether they were retried or parked",
+        &["outcome"]
+    )
+    .unwrap();
+    static ref STATUS_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        "submission_status_transitions_total",
+        "Submissions moved into a status, by the API or a worker update",
+        &["status"]
+    )
+    .unwrap();
+    static ref QUEUE_TO_DONE: HistogramVec = register_histogram_vec!(
+        "submission_queue_to_done_seconds",
+        "Time from queueing a run until the worker reports it finished",
+        &["language", "status"],
+        RUN_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref RUN_DURATION: HistogramVec = register_histogram_vec!(
+        "submission_run_duration_seconds",
+        "Execution time reported by the worker for finished runs",
+        &["language"],
+        RUN_BUCKETS.to_vec()
+    )
+    .unwrap();
+}
+
+/// Sets the languages runs are labelled with, those of `jobs.local.languages`.
+pub fn init(config: &JobsConfig) {
+    let languages = config.local.languages.keys().cloned().collect();
+    if LANGUAGES.set(languages).is_err() {
+        panic!("Metrics initialized twice");
+    }
+}
+
+fn language_label(language: &str) -> &str {
+    let known = LANGUAGES.get().map(Vec::as_slice).unwrap_or_default();
+    if known.iter().any(|known| known == language) {
+        language
+    } else {
+        OTHER_LANGUAGE
+    }
+}
+
+/// Everything registered, in the Prometheus text format.
+pub fn render() -> String {
+    if let Some((state, max_size)) = db::pool_state() {
+        POOL_CONNECTIONS.set(state.connections as i64);
+        POOL_IDLE_CONNECTIONS.set(state.idle_connections as i64);
+        POOL_MAX_SIZE.set(max_size as i64);
+    }
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+pub fn observe_query(statement: &str, table: &str, seconds: f64) {
+    QUERY_DURATION
+        .with_label_values(&[statement, table])
+        .observe(seconds);
+}
+
+pub fn count_publish(exchange: &str, succeeded: bool) {
+    let outcome = if succeeded { "success" } else { "failure" };
+    AMQP_PUBLISHES.with_label_values(&[exchange, outcome]).inc();
+}
+
+pub fn count_dead_letter(outcome: &str) {
+    DEAD_LETTERS.with_label_values(&[outcome]).inc();
+}
+
+pub fn count_status(status: &str) {
+    STATUS_TRANSITIONS.with_label_values(&[status]).inc();
+}
+
+pub fn observe_queue_to_done(language: &str, status: &str, seconds: f64) {
+    QUEUE_TO_DONE
+        .with_label_values(&[language_label(language), status])
+        .observe(seconds);
+}
+
+pub fn observe_run(language: &str, seconds: f64) {
+    RUN_DURATION
+        .with_label_values(&[language_label(language)])
+        .observe(seconds);
+}
+
+/// Counts and times every request. Requests are labelled with the pattern of
+/// the route they matched, never the raw path, to keep the label set bounded.
+pub struct HttpMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for HttpMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = HttpMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HttpMetricsMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            let status = res.status().as_u16().to_string();
+
+            HTTP_REQUESTS
+                .with_label_values(&[&method, &route, &status])
+                .inc();
+            HTTP_DURATION
+                .with_label_values(&[&method, &route])
+                .observe(started.elapsed().as_secs_f64());
+
+            Ok(res)
+        })
+    }
+}
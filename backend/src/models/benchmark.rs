@@ -1,24 +1,34 @@
-use crate::api_error::ApiError;
+use crate::api_error::{ApiError, ErrorCode};
 use crate::db;
 use crate::schema::benchmark;
+use crate::validation::ValidationErrors;
 use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset, Clone, ToSchema)]
 #[table_name = "benchmark"]
 pub struct Benchmark {
+    #[schema(value_type = String, format = Uuid)]
     pub id: Uuid,
     pub title: String,
     pub subject: String,
     pub difficulty: String,
+    #[schema(value_type = Option<String>, format = Uuid)]
     pub creator_id: Option<Uuid>,
     pub git_url: Option<String>,
     pub max_cyclomatic_complex: i32,
     pub created_at: NaiveDateTime,
     pub updated_at: Option<NaiveDateTime>,
+    pub data_version: i32,
+    /// Runs against it while the contest is open are contest runs, which the
+    /// worker pool takes first.
+    pub contest: bool,
+    pub contest_opens_at: Option<NaiveDateTime>,
+    pub contest_closes_at: Option<NaiveDateTime>,
 }
 
 #[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
@@ -28,11 +38,12 @@ pub struct BenchmarkMessage {
     pub subject: String,
     pub difficulty: String,
     pub git_url: Option<String>,
-    pub creator_id: Uuid,
     pub max_cyclomatic_complex: i32,
 }
 
-#[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+/// What authors may set. Whether a benchmark is a contest decides how soon its
+/// runs are taken, so only administrators mark one, through [`ContestUpdate`].
+#[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset, ToSchema)]
 #[table_name = "benchmark"]
 pub struct BenchmarkInput {
     pub title: String,
@@ -42,6 +53,63 @@ pub struct BenchmarkInput {
     pub max_cyclomatic_complex: i32,
 }
 
+/// A contest needs a window, in UTC: entering it is submitting to the benchmark
+/// while it is open, and only then do the runs go first.
+#[derive(Deserialize, ToSchema)]
+pub struct ContestUpdate {
+    pub contest: bool,
+    pub opens_at: Option<NaiveDateTime>,
+    pub closes_at: Option<NaiveDateTime>,
+}
+
+impl ContestUpdate {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        match (self.contest, self.opens_at, self.closes_at) {
+            (true, Some(opens_at), Some(closes_at)) if closes_at <= opens_at => {
+                errors.add("closes_at", "must be after opens_at")
+            }
+            (true, Some(_), Some(_)) | (false, None, None) => {}
+            (true, _, _) => errors.add("opens_at", "and closes_at are required for a contest"),
+            (false, _, _) => errors.add("opens_at", "and closes_at are only for a contest"),
+        }
+
+        errors.into_result()
+    }
+}
+
+impl BenchmarkMessage {
+    pub fn new(benchmark: BenchmarkInput) -> Self {
+        BenchmarkMessage {
+            title: benchmark.title,
+            subject: benchmark.subject,
+            difficulty: benchmark.difficulty,
+            max_cyclomatic_complex: benchmark.max_cyclomatic_complex,
+            git_url: benchmark.git_url,
+        }
+    }
+
+    /// A new benchmark, by `creator_id`.
+    pub fn into_benchmark(self, creator_id: Uuid) -> Benchmark {
+        Benchmark {
+            id: Uuid::new_v4(),
+            title: self.title,
+            subject: self.subject,
+            difficulty: self.difficulty,
+            git_url: self.git_url,
+            max_cyclomatic_complex: self.max_cyclomatic_complex,
+            created_at: Utc::now().naive_utc(),
+            updated_at: Some(Utc::now().naive_utc()),
+            creator_id: Some(creator_id),
+            data_version: 1,
+            contest: false,
+            contest_opens_at: None,
+            contest_closes_at: None,
+        }
+    }
+}
+
 impl Benchmark {
     pub fn find_all() -> Result<Vec<Self>, ApiError> {
         let conn = db::connection()?;
@@ -62,16 +130,7 @@ impl Benchmark {
     pub fn create(benchmark: BenchmarkInput, user_id: Uuid) -> Result<Self, ApiError> {
         let conn = db::connection()?;
 
-        let benchmark = BenchmarkMessage {
-            title: benchmark.title,
-            subject: benchmark.subject,
-            difficulty: benchmark.difficulty,
-            max_cyclomatic_complex: benchmark.max_cyclomatic_complex,
-            git_url: benchmark.git_url,
-            creator_id: user_id,
-        };
-
-        let benchmark = Benchmark::from(benchmark);
+        let benchmark = BenchmarkMessage::new(benchmark).into_benchmark(user_id);
 
         let benchmark = diesel::insert_into(benchmark::table)
             .values(benchmark)
@@ -80,49 +139,100 @@ impl Benchmark {
         Ok(benchmark)
     }
 
-    pub fn update(id: Uuid, benchmark: BenchmarkInput, user_id: Uuid) -> Result<Self, ApiError> {
+    /// Applies the update only to a benchmark `user_id` created, and only while
+    /// the stored version still matches `expected_version`, when one is given.
+    /// Bumps the version.
+    pub fn update(
+        id: Uuid,
+        benchmark: BenchmarkInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Self, ApiError> {
         let conn = db::connection()?;
 
-        let benchmark = BenchmarkMessage {
-            title: benchmark.title,
-            subject: benchmark.subject,
-            difficulty: benchmark.difficulty,
-            max_cyclomatic_complex: benchmark.max_cyclomatic_complex,
-            git_url: benchmark.git_url,
-            creator_id: user_id,
-        };
+        let benchmark = BenchmarkMessage::new(benchmark);
+
+        let benchmark = diesel::update(benchmark::table)
+            .filter(benchmark::id.eq(id))
+            .filter(benchmark::creator_id.eq(user_id))
+            .filter(
+                benchmark::data_version.eq(db::coalesce(expected_version, benchmark::data_version)),
+            )
+            .set((
+                benchmark,
+                benchmark::updated_at.eq(Utc::now().naive_utc()),
+                benchmark::data_version.eq(benchmark::data_version + 1),
+            ))
+            .get_result(&conn)
+            .optional()?;
+
+        match benchmark {
+            Some(benchmark) => Ok(benchmark),
+            None => Err(Benchmark::refusal(id, user_id)?),
+        }
+    }
+
+    /// Whether the benchmark is a contest that is open `at`.
+    pub fn contest_open(&self, at: NaiveDateTime) -> bool {
+        match (self.contest_opens_at, self.contest_closes_at) {
+            (Some(opens_at), Some(closes_at)) => self.contest && opens_at <= at && at < closes_at,
+            _ => false,
+        }
+    }
 
-        let benchmark = Benchmark::from(benchmark);
+    pub fn set_contest(id: Uuid, update: ContestUpdate) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
 
         let benchmark = diesel::update(benchmark::table)
             .filter(benchmark::id.eq(id))
-            .set(benchmark)
+            .set((
+                benchmark::contest.eq(update.contest),
+                benchmark::contest_opens_at.eq(update.opens_at),
+                benchmark::contest_closes_at.eq(update.closes_at),
+                benchmark::updated_at.eq(Utc::now().naive_utc()),
+                benchmark::data_version.eq(benchmark::data_version + 1),
+            ))
             .get_result(&conn)?;
 
         Ok(benchmark)
     }
 
-    pub fn delete(id: Uuid) -> Result<usize, ApiError> {
+    /// Deletes a benchmark `user_id` created. Deleting one that does not exist
+    /// is not an error, unless a version was expected.
+    pub fn delete(
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError> {
         let conn = db::connection()?;
 
-        let res = diesel::delete(benchmark::table.filter(benchmark::id.eq(id))).execute(&conn)?;
+        let res = diesel::delete(
+            benchmark::table
+                .filter(benchmark::id.eq(id))
+                .filter(benchmark::creator_id.eq(user_id))
+                .filter(
+                    benchmark::data_version
+                        .eq(db::coalesce(expected_version, benchmark::data_version)),
+                ),
+        )
+        .execute(&conn)?;
+
+        if res == 0 {
+            match Benchmark::refusal(id, user_id) {
+                Err(e) if e.code == ErrorCode::NotFound && expected_version.is_none() => {}
+                Err(e) | Ok(e) => return Err(e),
+            }
+        }
 
         Ok(res)
     }
-}
 
-impl From<BenchmarkMessage> for Benchmark {
-    fn from(benchmark: BenchmarkMessage) -> Self {
-        Benchmark {
-            id: Uuid::new_v4(),
-            title: benchmark.title,
-            subject: benchmark.subject,
-            difficulty: benchmark.difficulty,
-            git_url: benchmark.git_url,
-            max_cyclomatic_complex: benchmark.max_cyclomatic_complex,
-            created_at: Utc::now().naive_utc(),
-            updated_at: Some(Utc::now().naive_utc()),
-            creator_id: Some(benchmark.creator_id),
-        }
+    /// Why a write limited to a benchmark `user_id` created, at the expected
+    /// version, matched nothing.
+    fn refusal(id: Uuid, user_id: Uuid) -> Result<ApiError, ApiError> {
+        Ok(match Benchmark::find(id)?.creator_id == Some(user_id) {
+            true => ApiError::precondition_failed(),
+            false => ApiError::new(ErrorCode::Forbidden, "Forbidden".to_string()),
+        })
     }
 }
@@ -0,0 +1,74 @@
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::dead_letter;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// A job message the worker could not process even after the retries, parked
+/// for an administrator to requeue or discard.
+#[derive(Serialize, Queryable, Insertable, ToSchema)]
+#[table_name = "dead_letter"]
+pub struct DeadLetter {
+    #[schema(value_type = String, format = Uuid)]
+    pub id: Uuid,
+    /// `<submission id>.<attempt>`, as set when the job was published.
+    pub message_id: String,
+    /// Taken from the payload, when it could be read.
+    #[schema(value_type = Option<String>, format = Uuid)]
+    pub submission_id: Option<Uuid>,
+    pub attempt: Option<i32>,
+    /// The message body as it was published.
+    pub payload: String,
+    /// Why the broker dead-lettered it the last time, e.g. `rejected` or `expired`.
+    pub reason: String,
+    /// Delayed retries it went through before being parked.
+    pub retries: i32,
+    pub dead_at: NaiveDateTime,
+}
+
+impl DeadLetter {
+    pub fn find_all() -> Result<Vec<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let letters = dead_letter::table
+            .order(dead_letter::dead_at.desc())
+            .load(&conn)?;
+
+        Ok(letters)
+    }
+
+    pub fn find(id: Uuid) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let letter = dead_letter::table
+            .filter(dead_letter::id.eq(id))
+            .first(&conn)?;
+
+        Ok(letter)
+    }
+
+    /// Stores the letter, unless one with the same message ID is already parked.
+    pub fn record(letter: DeadLetter) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::insert_into(dead_letter::table)
+            .values(letter)
+            .on_conflict(dead_letter::message_id)
+            .do_nothing()
+            .execute(&conn)?;
+
+        Ok(())
+    }
+
+    pub fn delete(id: Uuid) -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        let res =
+            diesel::delete(dead_letter::table.filter(dead_letter::id.eq(id))).execute(&conn)?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,172 @@
+use super::RunInput;
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::job_attempt;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Status of an attempt that has been handed to the worker and not heard back
+/// from yet. Finished attempts take the submission's terminal status.
+pub const ATTEMPT_DISPATCHED: &str = "dispatched";
+
+// Bounds the work of a single reaper pass
+const OVERDUE_BATCH: i64 = 100;
+
+/// One dispatch of a submission to the worker. A run that times out may be
+/// dispatched again, so a submission can have several.
+#[derive(Serialize, Queryable, Insertable, ToSchema)]
+#[table_name = "job_attempt"]
+pub struct JobAttempt {
+    #[schema(value_type = String, format = Uuid)]
+    pub id: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    pub submission_id: Uuid,
+    /// Counts from 1.
+    pub attempt: i32,
+    pub status: String,
+    pub dispatched_at: NaiveDateTime,
+    /// When the reaper gives up on hearing back.
+    pub deadline: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+    /// `contest`, `grading` or `practice`, see `jobs::scheduling`.
+    pub run_class: String,
+    /// Message priority it was published with; higher goes first.
+    pub priority: i16,
+    /// The submission's `RunInput` as it was dispatched.
+    pub stdin: String,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+}
+
+impl JobAttempt {
+    /// Records a new dispatch of the submission, numbered after its earlier ones.
+    pub fn start(
+        submission_id: Uuid,
+        timeout_seconds: u64,
+        run_class: &str,
+        priority: i16,
+        input: RunInput,
+    ) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let previous: Option<i32> = job_attempt::table
+            .filter(job_attempt::submission_id.eq(submission_id))
+            .select(diesel::dsl::max(job_attempt::attempt))
+            .first(&conn)?;
+
+        let now = Utc::now().naive_utc();
+        let attempt = diesel::insert_into(job_attempt::table)
+            .values(JobAttempt {
+                id: Uuid::new_v4(),
+                submission_id,
+                attempt: previous.unwrap_or(0) + 1,
+                status: ATTEMPT_DISPATCHED.to_string(),
+                dispatched_at: now,
+                deadline: now + Duration::seconds(timeout_seconds as i64),
+                finished_at: None,
+                run_class: run_class.to_string(),
+                priority,
+                stdin: input.stdin,
+                args: input.args,
+                env: input.env,
+            })
+            .get_result(&conn)?;
+
+        Ok(attempt)
+    }
+
+    pub fn find_by_submission(submission_id: Uuid) -> Result<Vec<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let attempts = job_attempt::table
+            .filter(job_attempt::submission_id.eq(submission_id))
+            .order(job_attempt::attempt)
+            .load(&conn)?;
+
+        Ok(attempts)
+    }
+
+    /// The submission's most recent attempt, if it was ever dispatched.
+    pub fn latest(submission_id: Uuid) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let attempt = job_attempt::table
+            .filter(job_attempt::submission_id.eq(submission_id))
+            .order(job_attempt::attempt.desc())
+            .first(&conn)
+            .optional()?;
+
+        Ok(attempt)
+    }
+
+    /// Whether that attempt is still waiting for a result.
+    pub fn is_open(submission_id: Uuid, attempt: i32) -> Result<bool, ApiError> {
+        let conn = db::connection()?;
+
+        let open = diesel::select(diesel::dsl::exists(
+            job_attempt::table
+                .filter(job_attempt::submission_id.eq(submission_id))
+                .filter(job_attempt::attempt.eq(attempt))
+                .filter(job_attempt::finished_at.is_null()),
+        ))
+        .get_result(&conn)?;
+
+        Ok(open)
+    }
+
+    /// Unfinished attempts whose deadline has passed, oldest first.
+    pub fn find_overdue() -> Result<Vec<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let attempts = job_attempt::table
+            .filter(job_attempt::finished_at.is_null())
+            .filter(job_attempt::deadline.lt(Utc::now().naive_utc()))
+            .order(job_attempt::deadline)
+            .limit(OVERDUE_BATCH)
+            .load(&conn)?;
+
+        Ok(attempts)
+    }
+
+    /// Closes this attempt with `status`. Returns false if it was already
+    /// closed, e.g. by a result that arrived in the meantime or by another
+    /// backend instance's reaper.
+    pub fn finish(&self, status: &str) -> Result<bool, ApiError> {
+        let conn = db::connection()?;
+
+        let closed = diesel::update(
+            job_attempt::table
+                .filter(job_attempt::id.eq(self.id))
+                .filter(job_attempt::finished_at.is_null()),
+        )
+        .set((
+            job_attempt::status.eq(status),
+            job_attempt::finished_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(&conn)?;
+
+        Ok(closed == 1)
+    }
+
+    /// Closes whatever attempts of the submission are still open, once a final
+    /// result for it is in.
+    pub fn finish_open(submission_id: Uuid, status: &str) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::update(
+            job_attempt::table
+                .filter(job_attempt::submission_id.eq(submission_id))
+                .filter(job_attempt::finished_at.is_null()),
+        )
+        .set((
+            job_attempt::status.eq(status),
+            job_attempt::finished_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(&conn)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use crate::api_error::ApiError;
+use crate::db::{self, InstrumentedConnection};
+use crate::schema::login_attempt;
+use crate::validation;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Text};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Failures older than this no longer count against an account or address.
+const ATTEMPT_WINDOW_MINUTES: i64 = 15;
+const LOCKOUT_MINUTES: i64 = 15;
+const BACKOFF_BASE_SECONDS: i64 = 1;
+const BACKOFF_MAX_SECONDS: i64 = 300;
+// Upper bound on the rows inspected per subject, well above any lockout threshold
+const ATTEMPT_SCAN_LIMIT: i64 = 200;
+
+pub const OUTCOME_SUCCESS: &str = "success";
+pub const OUTCOME_FAILURE: &str = "failure";
+pub const OUTCOME_THROTTLED: &str = "throttled";
+/// Begun but not finished yet. Counts as a failure, so that attempts made in
+/// parallel are throttled as if they had been made one after another.
+pub const OUTCOME_PENDING: &str = "pending";
+/// The password was right but the second factor is still outstanding.
+pub const OUTCOME_SECOND_FACTOR_REQUIRED: &str = "second_factor_required";
+
+struct ThrottlePolicy {
+    backoff_after: usize,
+    lockout_after: usize,
+}
+
+const ACCOUNT_POLICY: ThrottlePolicy = ThrottlePolicy {
+    backoff_after: 3,
+    lockout_after: 10,
+};
+
+// Many users can share an address behind NAT, so addresses get more headroom
+const IP_POLICY: ThrottlePolicy = ThrottlePolicy {
+    backoff_after: 20,
+    lockout_after: 100,
+};
+
+#[derive(Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "login_attempt"]
+pub struct LoginAttempt {
+    pub id: Uuid,
+    pub identifier: String,
+    pub user_id: Option<Uuid>,
+    pub ip_address: String,
+    pub outcome: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl LoginAttempt {
+    pub fn normalize_identifier(identifier: &str) -> String {
+        match identifier.contains('@') {
+            true => validation::normalize_email(identifier),
+            false => identifier.trim().to_string(),
+        }
+    }
+
+    /// Starts an attempt for this identifier/account and client address, failing
+    /// with 429 while earlier failures make it wait. The check and the record of
+    /// the attempt happen under locks on the identifier, account and address, so
+    /// that parallel attempts cannot all pass the check before any of them
+    /// counts. Unknown identifiers are tracked exactly like real accounts so
+    /// that the answer does not reveal whether an account exists.
+    pub fn begin(
+        identifier: &str,
+        user_id: Option<Uuid>,
+        ip_address: &str,
+    ) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let (attempt, wait) = conn.transaction::<_, ApiError, _>(|| {
+            let mut keys = vec![
+                format!("login identifier {}", identifier),
+                format!("login ip {}", ip_address),
+            ];
+            keys.extend(user_id.map(|user_id| format!("login user {}", user_id)));
+            // Taken in a fixed order, so two attempts never wait on each other
+            diesel::sql_query(
+                "SELECT pg_advisory_xact_lock(key) FROM ( \
+                 SELECT DISTINCT hashtextextended(name, 0) AS key FROM unnest($1) AS name \
+                 ORDER BY key) AS keys",
+            )
+            .bind::<Array<Text>, _>(keys)
+            .execute(&conn)?;
+
+            let wait = wait_time_for(&conn, identifier, user_id, ip_address)?;
+            let outcome = match wait {
+                Some(_) => OUTCOME_THROTTLED,
+                None => OUTCOME_PENDING,
+            };
+            let attempt = LoginAttempt::insert(&conn, identifier, user_id, ip_address, outcome)?;
+
+            Ok((attempt, wait))
+        })?;
+
+        match wait {
+            Some(wait) => {
+                warn!(
+                    "Throttled login attempt for {} from {}",
+                    identifier, ip_address
+                );
+                Err(ApiError::too_many_requests(
+                    wait.num_seconds().max(1) as u64,
+                    "Too many failed attempts, try again later".to_string(),
+                ))
+            }
+            None => Ok(attempt),
+        }
+    }
+
+    /// Records how the attempt went.
+    pub fn finish(&self, outcome: &str) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::update(login_attempt::table.find(self.id))
+            .set(login_attempt::outcome.eq(outcome))
+            .execute(&conn)?;
+
+        Ok(())
+    }
+
+    /// Forgets an attempt that ended in neither success nor failure, such as one
+    /// at a step the account has not set up.
+    pub fn discard(self) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::delete(login_attempt::table.find(self.id)).execute(&conn)?;
+
+        Ok(())
+    }
+
+    fn insert(
+        conn: &InstrumentedConnection,
+        identifier: &str,
+        user_id: Option<Uuid>,
+        ip_address: &str,
+        outcome: &str,
+    ) -> Result<Self, ApiError> {
+        let attempt = LoginAttempt {
+            id: Uuid::new_v4(),
+            identifier: identifier.to_string(),
+            user_id,
+            ip_address: ip_address.to_string(),
+            outcome: outcome.to_string(),
+            created_at: Utc::now().naive_utc(),
+        };
+
+        let attempt = diesel::insert_into(login_attempt::table)
+            .values(attempt)
+            .get_result(conn)?;
+
+        Ok(attempt)
+    }
+}
+
+/// How long the caller has to wait before another attempt is accepted for this
+/// identifier/account and client address, if at all.
+fn wait_time_for(
+    conn: &InstrumentedConnection,
+    identifier: &str,
+    user_id: Option<Uuid>,
+    ip_address: &str,
+) -> Result<Option<Duration>, ApiError> {
+    let now = Utc::now().naive_utc();
+    let since = now - Duration::minutes(ATTEMPT_WINDOW_MINUTES);
+
+    let mut account_query = login_attempt::table
+        .filter(login_attempt::created_at.gt(since))
+        .into_boxed();
+    account_query = match user_id {
+        Some(user_id) => account_query.filter(
+            login_attempt::identifier
+                .eq(identifier)
+                .or(login_attempt::user_id.eq(user_id)),
+        ),
+        None => account_query.filter(login_attempt::identifier.eq(identifier)),
+    };
+    let account_attempts = account_query
+        .order(login_attempt::created_at.desc())
+        .limit(ATTEMPT_SCAN_LIMIT)
+        .load::<LoginAttempt>(conn)?;
+
+    let ip_attempts = login_attempt::table
+        .filter(login_attempt::created_at.gt(since))
+        .filter(login_attempt::ip_address.eq(ip_address))
+        .order(login_attempt::created_at.desc())
+        .limit(ATTEMPT_SCAN_LIMIT)
+        .load::<LoginAttempt>(conn)?;
+
+    // A successful login clears the account's failures, but not the address's:
+    // otherwise an attacker could reset their own counter with a throwaway account.
+    let account_wait = wait_time(&account_attempts, true, &ACCOUNT_POLICY, now);
+    let ip_wait = wait_time(&ip_attempts, false, &IP_POLICY, now);
+
+    Ok(account_wait.max(ip_wait))
+}
+
+fn wait_time(
+    attempts: &[LoginAttempt],
+    reset_on_success: bool,
+    policy: &ThrottlePolicy,
+    now: NaiveDateTime,
+) -> Option<Duration> {
+    let failures: Vec<&LoginAttempt> = attempts
+        .iter()
+        .take_while(|attempt| !reset_on_success || attempt.outcome != OUTCOME_SUCCESS)
+        .filter(|attempt| [OUTCOME_FAILURE, OUTCOME_PENDING].contains(&attempt.outcome.as_str()))
+        .collect();
+
+    let last_failure = failures.first()?.created_at;
+
+    let delay = if failures.len() >= policy.lockout_after {
+        Duration::minutes(LOCKOUT_MINUTES)
+    } else if failures.len() >= policy.backoff_after {
+        let exponent = (failures.len() - policy.backoff_after).min(16) as u32;
+        Duration::seconds((BACKOFF_BASE_SECONDS << exponent).min(BACKOFF_MAX_SECONDS))
+    } else {
+        return None;
+    };
+
+    let wait = last_failure + delay - now;
+    match wait > Duration::zero() {
+        true => Some(wait),
+        false => None,
+    }
+}
@@ -1,7 +1,29 @@
 mod benchmark;
+mod dead_letter;
+mod job_attempt;
+mod login_attempt;
+mod oauth_state;
+mod recovery_code;
+mod run_input;
+mod run_usage;
+mod scratch_run;
 mod submission;
+mod submission_file;
+mod two_factor_challenge;
 mod user;
+mod user_identity;
 
 pub use benchmark::*;
+pub use dead_letter::*;
+pub use job_attempt::*;
+pub use login_attempt::*;
+pub use oauth_state::*;
+pub use recovery_code::*;
+pub use run_input::*;
+pub use run_usage::*;
+pub use scratch_run::*;
 pub use submission::*;
+pub use submission_file::*;
+pub use two_factor_challenge::*;
 pub use user::*;
+pub use user_identity::*;
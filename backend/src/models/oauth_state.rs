@@ -0,0 +1,54 @@
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::oauth_state;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// How long a user has to complete the provider's consent screen.
+pub const OAUTH_STATE_TTL_MINUTES: i64 = 10;
+
+/// An authorization request in flight, keyed by the `state` parameter sent to the
+/// provider. Holds the PKCE verifier and, when linking, the signed-in user.
+#[derive(Queryable, Insertable)]
+#[table_name = "oauth_state"]
+pub struct OAuthState {
+    pub state: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub redirect_uri: String,
+    pub user_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+impl OAuthState {
+    pub fn create(state: OAuthState) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let state = diesel::insert_into(oauth_state::table)
+            .values(state)
+            .get_result(&conn)?;
+
+        Ok(state)
+    }
+
+    /// Consumes the state so that it cannot be replayed. Returns `None` for unknown,
+    /// expired or mismatched states.
+    pub fn take(state: &str, provider: &str) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let expired_before = Utc::now().naive_utc() - Duration::minutes(OAUTH_STATE_TTL_MINUTES);
+        diesel::delete(oauth_state::table.filter(oauth_state::created_at.lt(expired_before)))
+            .execute(&conn)?;
+
+        let state: Option<OAuthState> = diesel::delete(
+            oauth_state::table
+                .filter(oauth_state::state.eq(state))
+                .filter(oauth_state::provider.eq(provider)),
+        )
+        .get_result(&conn)
+        .optional()?;
+
+        Ok(state)
+    }
+}
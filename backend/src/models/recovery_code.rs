@@ -0,0 +1,103 @@
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::recovery_code;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// A single-use fallback for the authenticator app. Only a digest is stored; the
+/// codes themselves are shown to the user once, when generated.
+#[derive(Queryable, Insertable)]
+#[table_name = "recovery_code"]
+pub struct RecoveryCode {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl RecoveryCode {
+    /// Replaces all of the user's recovery codes and returns the new ones.
+    pub fn regenerate(user_id: Uuid) -> Result<Vec<String>, ApiError> {
+        let conn = db::connection()?;
+
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_code()).collect();
+        let rows: Vec<RecoveryCode> = codes
+            .iter()
+            .map(|code| RecoveryCode {
+                id: Uuid::new_v4(),
+                user_id,
+                code_hash: hash_code(user_id, code),
+                used_at: None,
+                created_at: Utc::now().naive_utc(),
+            })
+            .collect();
+
+        conn.transaction::<_, ApiError, _>(|| {
+            diesel::delete(recovery_code::table.filter(recovery_code::user_id.eq(user_id)))
+                .execute(&conn)?;
+            diesel::insert_into(recovery_code::table)
+                .values(rows)
+                .execute(&conn)?;
+            Ok(())
+        })?;
+
+        Ok(codes)
+    }
+
+    /// Marks the code as used if it is one of the user's unused codes.
+    pub fn consume(user_id: Uuid, code: &str) -> Result<bool, ApiError> {
+        let conn = db::connection()?;
+
+        let updated = diesel::update(
+            recovery_code::table
+                .filter(recovery_code::user_id.eq(user_id))
+                .filter(recovery_code::code_hash.eq(hash_code(user_id, code)))
+                .filter(recovery_code::used_at.is_null()),
+        )
+        .set(recovery_code::used_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+
+        Ok(updated == 1)
+    }
+
+    pub fn delete_all(user_id: Uuid) -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        let res = diesel::delete(recovery_code::table.filter(recovery_code::user_id.eq(user_id)))
+            .execute(&conn)?;
+
+        Ok(res)
+    }
+}
+
+fn generate_code() -> String {
+    // 80 bits, enough for a plain digest to resist guessing
+    let bytes: [u8; 10] = rand::thread_rng().gen();
+    let code = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase();
+
+    code.as_bytes()
+        .chunks(4)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn hash_code(user_id: Uuid, code: &str) -> String {
+    // Users may type the code without dashes or in a different case
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    let mut hasher = Sha256::new();
+    hasher.update(user_id.as_bytes());
+    hasher.update(normalized.as_bytes());
+    base64::encode(hasher.finalize())
+}
@@ -0,0 +1,87 @@
+use super::{JobAttempt, Submission};
+use crate::validation::ValidationErrors;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+pub const STDIN_MAX_BYTES: usize = 64 * 1024;
+pub const ARGS_MAX_COUNT: usize = 32;
+pub const ENV_MAX_COUNT: usize = 32;
+/// Of a single argument, or a single `NAME=value` entry.
+pub const ARG_MAX_BYTES: usize = 1024;
+
+/// What a program is run with besides its code.
+#[derive(Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct RunInput {
+    /// Fed to the program's standard input.
+    #[serde(default)]
+    pub stdin: String,
+    /// Passed after the language's own command, e.g. `python3 main.py <args>`.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// `NAME=value` entries. Only names on the server's allow-list are taken.
+    #[serde(default)]
+    pub env: Vec<String>,
+}
+
+impl RunInput {
+    pub fn validate(&self, errors: &mut ValidationErrors, allowed_env: &[String]) {
+        if self.stdin.len() > STDIN_MAX_BYTES {
+            errors.add("stdin", "is too long");
+        }
+
+        if self.args.len() > ARGS_MAX_COUNT {
+            errors.add(
+                "args",
+                &format!("must have at most {} entries", ARGS_MAX_COUNT),
+            );
+        }
+        if self.args.iter().any(|arg| arg.len() > ARG_MAX_BYTES) {
+            errors.add("args", "has an entry that is too long");
+        }
+        // Nothing past a NUL would reach the program
+        if self.args.iter().any(|arg| arg.contains('\0')) {
+            errors.add("args", "must not contain NUL characters");
+        }
+
+        if self.env.len() > ENV_MAX_COUNT {
+            errors.add(
+                "env",
+                &format!("must have at most {} entries", ENV_MAX_COUNT),
+            );
+        }
+        for entry in &self.env {
+            if entry.len() > ARG_MAX_BYTES {
+                errors.add("env", "has an entry that is too long");
+                continue;
+            }
+            match entry.split_once('=') {
+                Some((name, value)) if !value.contains('\0') => {
+                    if !allowed_env.iter().any(|allowed| allowed == name) {
+                        errors.add("env", &format!("{:?} may not be set", name));
+                    }
+                }
+                _ => errors.add("env", "entries must look like NAME=value"),
+            }
+        }
+    }
+}
+
+impl From<&Submission> for RunInput {
+    fn from(submission: &Submission) -> Self {
+        RunInput {
+            stdin: submission.stdin.clone(),
+            args: submission.args.clone(),
+            env: submission.env.clone(),
+        }
+    }
+}
+
+impl From<&JobAttempt> for RunInput {
+    fn from(attempt: &JobAttempt) -> Self {
+        RunInput {
+            stdin: attempt.stdin.clone(),
+            args: attempt.args.clone(),
+            env: attempt.env.clone(),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::LimitsConfig;
+use crate::db;
+use crate::schema::run_usage;
+use chrono::{Duration, NaiveDate, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Date, Int4, Int8};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// A user's executions for one UTC day. `cpu_ms` accumulates the `exec_duration`
+/// (in milliseconds) reported by the worker for each finished run.
+#[derive(Serialize, Deserialize, Queryable, QueryableByName, Insertable)]
+#[table_name = "run_usage"]
+pub struct RunUsage {
+    pub user_id: Uuid,
+    pub day: NaiveDate,
+    pub run_count: i32,
+    pub cpu_ms: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Quota {
+    pub runs_limit: i32,
+    pub runs_remaining: i32,
+    pub cpu_seconds_limit: i64,
+    pub cpu_seconds_remaining: i64,
+    pub resets_in_seconds: i64,
+}
+
+impl RunUsage {
+    pub fn today(user_id: Uuid) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let usage = run_usage::table
+            .filter(run_usage::user_id.eq(user_id))
+            .filter(run_usage::day.eq(today()))
+            .first(&conn)
+            .optional()?;
+
+        Ok(usage.unwrap_or(RunUsage {
+            user_id,
+            day: today(),
+            run_count: 0,
+            cpu_ms: 0,
+        }))
+    }
+
+    pub fn quota(user_id: Uuid, limits: &LimitsConfig) -> Result<Quota, ApiError> {
+        Ok(RunUsage::today(user_id)?.remaining(limits))
+    }
+
+    /// Counts a run against today's quota, failing with 429 once either the run
+    /// count or the CPU time is used up. The check and the increment happen in a
+    /// single statement so that concurrent requests cannot overshoot the limit.
+    /// The day's first run is checked against an empty day's usage, so a limit
+    /// of zero refuses it too.
+    pub fn reserve_run(user_id: Uuid, limits: &LimitsConfig) -> Result<Quota, ApiError> {
+        let conn = db::connection()?;
+
+        let usage: Option<RunUsage> = diesel::sql_query(
+            "INSERT INTO run_usage (user_id, day, run_count, cpu_ms) \
+             SELECT $1, $2, 1, 0 WHERE 0 < $3 AND 0 < $4 \
+             ON CONFLICT (user_id, day) DO UPDATE SET run_count = run_usage.run_count + 1 \
+             WHERE run_usage.run_count < $3 AND run_usage.cpu_ms < $4 \
+             RETURNING *",
+        )
+        .bind::<diesel::sql_types::Uuid, _>(user_id)
+        .bind::<Date, _>(today())
+        .bind::<Int4, _>(limits.daily_runs)
+        .bind::<Int8, _>(limits.daily_cpu_seconds * 1000)
+        .get_result(&conn)
+        .optional()?;
+
+        match usage {
+            Some(usage) => Ok(usage.remaining(limits)),
+            None => Err(ApiError::new(
+                ErrorCode::QuotaExceeded,
+                "Daily execution quota exceeded".to_string(),
+            )
+            .with_retry_after(seconds_until_reset() as u64)),
+        }
+    }
+
+    /// Gives back a reserved run that never reached the worker.
+    pub fn release_run(user_id: Uuid) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::update(
+            run_usage::table
+                .filter(run_usage::user_id.eq(user_id))
+                .filter(run_usage::day.eq(today()))
+                .filter(run_usage::run_count.gt(0)),
+        )
+        .set(run_usage::run_count.eq(run_usage::run_count - 1))
+        .execute(&conn)?;
+
+        Ok(())
+    }
+
+    pub fn record_cpu(user_id: Uuid, exec_ms: i64) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::insert_into(run_usage::table)
+            .values(RunUsage {
+                user_id,
+                day: today(),
+                run_count: 0,
+                cpu_ms: exec_ms.max(0),
+            })
+            .on_conflict((run_usage::user_id, run_usage::day))
+            .do_update()
+            .set(run_usage::cpu_ms.eq(run_usage::cpu_ms + exec_ms.max(0)))
+            .execute(&conn)?;
+
+        Ok(())
+    }
+
+    fn remaining(&self, limits: &LimitsConfig) -> Quota {
+        let cpu_seconds_used = self.cpu_ms / 1000;
+
+        Quota {
+            runs_limit: limits.daily_runs,
+            runs_remaining: (limits.daily_runs - self.run_count).max(0),
+            cpu_seconds_limit: limits.daily_cpu_seconds,
+            cpu_seconds_remaining: (limits.daily_cpu_seconds - cpu_seconds_used).max(0),
+            resets_in_seconds: seconds_until_reset(),
+        }
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+fn seconds_until_reset() -> i64 {
+    let now = Utc::now().naive_utc();
+    let midnight = (today() + Duration::days(1)).and_hms_opt(0, 0, 0).unwrap();
+    (midnight - now).num_seconds().max(1)
+}
@@ -0,0 +1,226 @@
+use super::submission::{non_empty, TERMINAL_STATUSES};
+use super::{JobStatus, RunInput, IN_FLIGHT_STATUSES, STATUS_QUEUED, STATUS_TIMED_OUT};
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::scratch_run;
+use crate::validation::ValidationErrors;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const SCRATCH_CODE_MAX_BYTES: usize = 64 * 1024;
+
+/// A one-off run of code that is not saved as a submission. It goes through the
+/// worker like any run, and is deleted once `expires_at` has passed.
+#[derive(Serialize, Queryable, Insertable, ToSchema)]
+#[table_name = "scratch_run"]
+pub struct ScratchRun {
+    #[schema(value_type = String, format = Uuid)]
+    pub id: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    pub user_id: Uuid,
+    pub language: String,
+    pub code: String,
+    pub stdin: String,
+    pub status: String,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub message: Option<String>,
+    pub error: Option<String>,
+    pub exec_duration: i32,
+    pub mem_usage: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// When the reaper gives up on hearing back and marks it `timed_out`.
+    pub deadline: NaiveDateTime,
+    /// Gone from then on.
+    pub expires_at: NaiveDateTime,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ScratchRunInput {
+    pub language: String,
+    pub code: String,
+    #[serde(default)]
+    pub stdin: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// How long to wait for the result before answering with the run as it
+    /// stands; 0 answers at once. Capped at the server's maximum, which is
+    /// also the default.
+    pub wait_seconds: Option<u64>,
+}
+
+impl ScratchRunInput {
+    /// Checks the input, `env` against the names in `allowed_env`.
+    pub fn validate(&self, allowed_env: &[String]) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        if self.language.trim().is_empty() {
+            errors.add("language", "is required");
+        }
+        if self.code.trim().is_empty() {
+            errors.add("code", "is required");
+        }
+        if self.code.len() > SCRATCH_CODE_MAX_BYTES {
+            errors.add("code", "is too long");
+        }
+        RunInput {
+            stdin: self.stdin.clone(),
+            args: self.args.clone(),
+            env: self.env.clone(),
+        }
+        .validate(&mut errors, allowed_env);
+
+        errors.into_result()
+    }
+}
+
+impl ScratchRun {
+    pub fn create(
+        input: ScratchRunInput,
+        user_id: Uuid,
+        timeout_seconds: u64,
+        ttl_seconds: u64,
+    ) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let now = Utc::now().naive_utc();
+        let run = diesel::insert_into(scratch_run::table)
+            .values(ScratchRun {
+                id: Uuid::new_v4(),
+                user_id,
+                language: input.language,
+                code: input.code,
+                stdin: input.stdin,
+                status: STATUS_QUEUED.to_string(),
+                stdout: None,
+                stderr: None,
+                message: None,
+                error: None,
+                exec_duration: 0,
+                mem_usage: 0,
+                created_at: now,
+                updated_at: now,
+                deadline: now + Duration::seconds(timeout_seconds as i64),
+                expires_at: now + Duration::seconds(ttl_seconds as i64),
+                args: input.args,
+                env: input.env,
+            })
+            .get_result(&conn)?;
+
+        Ok(run)
+    }
+
+    /// The run, unless it has expired.
+    pub fn find(id: Uuid) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let run = scratch_run::table
+            .filter(scratch_run::id.eq(id))
+            .filter(scratch_run::expires_at.gt(Utc::now().naive_utc()))
+            .first(&conn)?;
+
+        Ok(run)
+    }
+
+    /// The user's runs that have yet to finish.
+    pub fn count_in_flight(user_id: Uuid) -> Result<i64, ApiError> {
+        let conn = db::connection()?;
+
+        let count = scratch_run::table
+            .filter(scratch_run::user_id.eq(user_id))
+            .filter(scratch_run::status.eq_any(IN_FLIGHT_STATUSES))
+            .count()
+            .get_result(&conn)?;
+
+        Ok(count)
+    }
+
+    /// Records a worker status update, the way `Submission::apply_status` does.
+    /// `None` when there is no such run, or it has finished already.
+    pub fn apply_status(status: &JobStatus) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let target = scratch_run::table
+            .filter(scratch_run::id.eq(status.id))
+            .filter(scratch_run::status.ne_all(TERMINAL_STATUSES));
+        let stamp = (
+            scratch_run::status.eq(&status.status),
+            scratch_run::updated_at.eq(Utc::now().naive_utc()),
+        );
+
+        let run = match status.is_terminal() {
+            true => diesel::update(target)
+                .set((
+                    stamp,
+                    scratch_run::message.eq(non_empty(&status.message)),
+                    scratch_run::error.eq(non_empty(&status.error)),
+                    scratch_run::stdout.eq(non_empty(&status.stdout)),
+                    scratch_run::stderr.eq(non_empty(&status.stderr)),
+                    scratch_run::exec_duration.eq(status.exec_duration),
+                    scratch_run::mem_usage.eq(status.mem_usage.clamp(0, i32::MAX as i64) as i32),
+                ))
+                .get_result(&conn)
+                .optional()?,
+            false => diesel::update(target)
+                .set(stamp)
+                .get_result(&conn)
+                .optional()?,
+        };
+
+        Ok(run)
+    }
+
+    /// Marks unfinished runs past their deadline `timed_out`. Scratch runs are
+    /// not worth a retry.
+    pub fn time_out_overdue() -> Result<Vec<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let now = Utc::now().naive_utc();
+        let runs = diesel::update(
+            scratch_run::table
+                .filter(scratch_run::status.ne_all(TERMINAL_STATUSES))
+                .filter(scratch_run::deadline.lt(now)),
+        )
+        .set((
+            scratch_run::status.eq(STATUS_TIMED_OUT),
+            scratch_run::error.eq("No result in time"),
+            scratch_run::updated_at.eq(now),
+        ))
+        .get_results(&conn)?;
+
+        Ok(runs)
+    }
+
+    /// Deletes a run that never got to the worker.
+    pub fn purge(id: Uuid) -> Result<(), ApiError> {
+        let conn = db::connection()?;
+
+        diesel::delete(scratch_run::table.filter(scratch_run::id.eq(id))).execute(&conn)?;
+
+        Ok(())
+    }
+
+    /// Deletes the runs that have expired, and returns how many there were.
+    pub fn purge_expired() -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        let purged = diesel::delete(
+            scratch_run::table.filter(scratch_run::expires_at.le(Utc::now().naive_utc())),
+        )
+        .execute(&conn)?;
+
+        Ok(purged)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        TERMINAL_STATUSES.contains(&self.status.as_str())
+    }
+}
@@ -1,23 +1,56 @@
-use crate::api_error::ApiError;
+use super::{JobAttempt, ProjectFile, ProjectInput, RunInput, ScratchRun, SubmissionFile};
+use crate::api_error::{ApiError, ErrorCode};
 use crate::db;
 use crate::schema::submission;
-use base64;
-use chrono::{NaiveDateTime, Utc};
+use crate::validation::ValidationErrors;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Queryable, Insertable)]
+/// Set when a run is dispatched; the worker then reports received/running/done/failed.
+pub const STATUS_QUEUED: &str = "queued";
+pub const STATUS_RECEIVED: &str = "received";
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_DONE: &str = "done";
+pub const STATUS_FAILED: &str = "failed";
+/// Set by the reaper when no attempt at the run reported back in time.
+pub const STATUS_TIMED_OUT: &str = "timed_out";
+/// A cancelled run the worker had already picked up, until it confirms it has
+/// stopped, or reports a result it got to first.
+pub const STATUS_CANCELLING: &str = "cancelling";
+pub const STATUS_CANCELLED: &str = "cancelled";
+
+/// A run in any of these has yet to finish.
+pub const IN_FLIGHT_STATUSES: [&str; 4] = [
+    STATUS_QUEUED,
+    STATUS_RECEIVED,
+    STATUS_RUNNING,
+    STATUS_CANCELLING,
+];
+
+pub(super) const TERMINAL_STATUSES: [&str; 4] = [
+    STATUS_DONE,
+    STATUS_FAILED,
+    STATUS_TIMED_OUT,
+    STATUS_CANCELLED,
+];
+
+#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, ToSchema)]
 #[table_name = "submission"]
 pub struct Submission {
+    #[schema(value_type = String, format = Uuid)]
     pub id: Uuid,
     pub language: String,
     pub code: String,
     pub created_at: NaiveDateTime,
     pub updated_at: Option<NaiveDateTime>,
+    #[schema(value_type = String, format = Uuid)]
     pub user_id: Uuid,
     pub status: String,
+    #[schema(value_type = Option<String>, format = Uuid)]
     pub benchmark_id: Option<Uuid>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
@@ -29,6 +62,16 @@ pub struct Submission {
     pub mem_usage: i32,
     pub code_hash: Option<String>,
     pub cyclomatic_complexity: i32,
+    pub data_version: i32,
+    /// When the latest run was handed to the worker.
+    pub queued_at: Option<NaiveDateTime>,
+    /// What it is run with, see `RunInput`.
+    pub stdin: String,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    /// Set on a project: the file its run starts from. Its files are listed at
+    /// `/submissions/{id}/files/`, and `code` is not used.
+    pub entrypoint: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, AsChangeset)]
@@ -36,7 +79,6 @@ pub struct Submission {
 pub struct SubmissionMessage {
     pub language: String,
     pub code: String,
-    pub user_id: Uuid,
     pub status: String,
     pub benchmark_id: Option<Uuid>,
     pub stdout: Option<String>,
@@ -49,14 +91,18 @@ pub struct SubmissionMessage {
     pub mem_usage: i32,
     pub code_hash: Option<String>,
     pub cyclomatic_complexity: i32,
+    pub stdin: String,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub entrypoint: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, AsChangeset)]
-#[table_name = "submission"]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct SubmissionInput {
     pub language: String,
     pub code: String,
     pub status: String,
+    #[schema(value_type = Option<String>, format = Uuid)]
     pub benchmark_id: Option<Uuid>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
@@ -67,45 +113,139 @@ pub struct SubmissionInput {
     pub quality_score: Option<i32>,
     pub mem_usage: i32,
     pub cyclomatic_complexity: i32,
+    /// Fed to the program's standard input.
+    #[serde(default)]
+    pub stdin: String,
+    /// Passed after the language's own command.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// `NAME=value` entries; only names on the server's allow-list are taken.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Makes the submission a project of several files, replacing any it had.
+    /// Left out, the files stay as they are.
+    #[serde(default)]
+    pub project: Option<ProjectInput>,
 }
 
-#[derive(Serialize, Deserialize, AsChangeset)]
-#[table_name = "submission"]
+impl SubmissionInput {
+    /// Checks what the submission is to be run with, `env` against the names in
+    /// `allowed_env`.
+    pub fn validate(&self, allowed_env: &[String]) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        RunInput {
+            stdin: self.stdin.clone(),
+            args: self.args.clone(),
+            env: self.env.clone(),
+        }
+        .validate(&mut errors, allowed_env);
+        if let Some(project) = &self.project {
+            project.validate(&mut errors);
+        }
+
+        errors.into_result()
+    }
+
+    /// The project's files, which are stored apart from the rest; its entrypoint
+    /// stays behind to be saved with the submission.
+    pub fn take_files(&mut self) -> Option<Vec<ProjectFile>> {
+        self.project
+            .as_mut()
+            .map(|project| std::mem::take(&mut project.files))
+    }
+}
+
+/// The job published to the worker.
+#[derive(Serialize, Deserialize)]
 pub struct SubmissionWorker {
     pub id: Uuid,
     pub code: String,
     pub language: String,
+    pub attempt: i32,
+    /// Also set as the message priority; carried here so that a job published
+    /// again from its payload keeps it.
+    pub priority: u8,
+    /// Fed to the program's standard input.
+    #[serde(default)]
+    pub stdin: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// A project's files, which are run instead of `code`.
+    #[serde(default)]
+    pub files: Vec<ProjectFile>,
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    /// After this the run is presumed lost and may be dispatched again; a worker
+    /// should not bother starting it any later.
+    pub deadline: DateTime<Utc>,
 }
 
-impl Submission {
-    pub fn find_all() -> Result<Vec<Self>, ApiError> {
-        let conn = db::connection()?;
+/// Asks the workers to drop a job, or stop it if one has already started it.
+/// Published on `cancel_routing_key`. Only meant for that attempt, so that it
+/// cannot catch a later run of the same submission.
+#[derive(Serialize, Deserialize)]
+pub struct JobCancel {
+    pub id: Uuid,
+    pub attempt: i32,
+}
 
-        let submissions = submission::table.load::<Submission>(&conn)?;
+/// A status update published by the worker on `jobs_status_ex`.
+#[derive(Debug, Deserialize)]
+pub struct JobStatus {
+    pub id: Uuid,
+    /// The attempt the update is about. Updates about any but the submission's
+    /// open attempt are stale and ignored; ones without are always applied.
+    #[serde(default)]
+    pub attempt: Option<i32>,
+    pub status: String,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub error: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    #[serde(default)]
+    pub exec_duration: i32,
+    #[serde(default)]
+    pub mem_usage: i64,
+}
 
-        Ok(submissions)
+impl JobStatus {
+    /// An update with nothing to report but the status.
+    pub fn new(id: Uuid, status: &str) -> Self {
+        JobStatus {
+            id,
+            attempt: None,
+            status: status.to_string(),
+            message: String::new(),
+            error: String::new(),
+            stdout: String::new(),
+            stderr: String::new(),
+            exec_duration: 0,
+            mem_usage: 0,
+        }
     }
 
-    pub fn find(id: Uuid) -> Result<Self, ApiError> {
-        let conn = db::connection()?;
-
-        let submission = submission::table
-            .filter(submission::id.eq(id))
-            .first(&conn)?;
-
-        Ok(submission)
+    pub fn is_terminal(&self) -> bool {
+        TERMINAL_STATUSES.contains(&self.status.as_str())
     }
+}
 
-    pub fn create(submission: SubmissionInput, user_id: Uuid) -> Result<Self, ApiError> {
+impl SubmissionMessage {
+    pub fn new(submission: SubmissionInput) -> Self {
         let mut hasher = Sha256::new();
         hasher.update(submission.code.as_bytes());
         let result = hasher.finalize();
-        let encoded = base64::encode(&result);
+        let encoded = base64::encode(result);
 
-        let submission = SubmissionMessage {
+        SubmissionMessage {
             language: submission.language,
             code: submission.code,
-            user_id: user_id,
             status: submission.status,
             benchmark_id: submission.benchmark_id,
             stdout: submission.stdout,
@@ -118,60 +258,290 @@ impl Submission {
             mem_usage: submission.mem_usage,
             code_hash: Some(encoded),
             cyclomatic_complexity: submission.cyclomatic_complexity,
-        };
+            stdin: submission.stdin,
+            args: submission.args,
+            env: submission.env,
+            entrypoint: submission.project.map(|project| project.entrypoint),
+        }
+    }
+}
 
+impl Submission {
+    pub fn find_all() -> Result<Vec<Self>, ApiError> {
         let conn = db::connection()?;
 
-        let submission = Submission::from(submission);
+        let submissions = submission::table.load::<Submission>(&conn)?;
 
-        let submission = diesel::insert_into(submission::table)
-            .values(submission)
-            .get_result(&conn)?;
+        Ok(submissions)
+    }
+
+    pub fn find(id: Uuid) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let submission = submission::table
+            .filter(submission::id.eq(id))
+            .first(&conn)?;
 
         Ok(submission)
     }
 
-    pub fn update(id: Uuid, submission: SubmissionInput, user_id: Uuid) -> Result<Self, ApiError> {
+    pub fn create(mut submission: SubmissionInput, user_id: Uuid) -> Result<Self, ApiError> {
+        let files = submission.take_files();
+        let submission = SubmissionMessage::new(submission).into_submission(user_id);
+
         let conn = db::connection()?;
-        let mut hasher = Sha256::new();
-        hasher.update(submission.code.as_bytes());
-        let result = hasher.finalize();
-        let encoded = base64::encode(&result);
 
-        let submission = SubmissionMessage {
-            language: submission.language,
-            code: submission.code,
-            user_id: user_id,
-            status: submission.status,
-            benchmark_id: submission.benchmark_id,
-            stdout: submission.stdout,
-            stderr: submission.stderr,
-            exec_duration: submission.exec_duration,
-            message: submission.message,
-            error: submission.error,
-            lint_score: submission.lint_score,
-            quality_score: submission.quality_score,
-            mem_usage: submission.mem_usage,
-            code_hash: Some(encoded),
-            cyclomatic_complexity: submission.cyclomatic_complexity,
-        };
+        conn.transaction::<_, ApiError, _>(|| {
+            let submission: Submission = diesel::insert_into(submission::table)
+                .values(submission)
+                .get_result(&conn)?;
+            if let Some(files) = files {
+                SubmissionFile::replace_all(&conn, submission.id, files)?;
+            }
 
-        let benchmark = diesel::update(submission::table)
-            .filter(submission::id.eq(id))
-            .set(submission)
-            .get_result(&conn)?;
+            Ok(submission)
+        })
+    }
+
+    /// Applies the update only to a submission of `user_id`'s, and only while the
+    /// stored version still matches `expected_version`, when one is given. Bumps
+    /// the version.
+    pub fn update(
+        id: Uuid,
+        mut submission: SubmissionInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let files = submission.take_files();
+        let submission = SubmissionMessage::new(submission);
+
+        let submission = conn.transaction::<_, ApiError, _>(|| {
+            let submission: Option<Submission> = diesel::update(submission::table)
+                .filter(submission::id.eq(id))
+                .filter(submission::user_id.eq(user_id))
+                .filter(
+                    submission::data_version
+                        .eq(db::coalesce(expected_version, submission::data_version)),
+                )
+                .set((
+                    submission,
+                    submission::updated_at.eq(Utc::now().naive_utc()),
+                    submission::data_version.eq(submission::data_version + 1),
+                ))
+                .get_result(&conn)
+                .optional()?;
+            if let (Some(submission), Some(files)) = (&submission, files) {
+                SubmissionFile::replace_all(&conn, submission.id, files)?;
+            }
 
-        Ok(benchmark)
+            Ok(submission)
+        })?;
+
+        match submission {
+            Some(submission) => Ok(submission),
+            None => Err(Submission::refusal(id, user_id)?),
+        }
     }
 
-    pub fn delete(id: Uuid) -> Result<usize, ApiError> {
+    /// Deletes a submission of `user_id`'s. Deleting one that does not exist
+    /// is not an error, unless a version was expected.
+    pub fn delete(
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError> {
         let conn = db::connection()?;
 
-        let res = diesel::delete(submission::table.filter(submission::id.eq(id))).execute(&conn)?;
+        let res = diesel::delete(
+            submission::table
+                .filter(submission::id.eq(id))
+                .filter(submission::user_id.eq(user_id))
+                .filter(
+                    submission::data_version
+                        .eq(db::coalesce(expected_version, submission::data_version)),
+                ),
+        )
+        .execute(&conn)?;
+
+        if res == 0 {
+            match Submission::refusal(id, user_id) {
+                Err(e) if e.code == ErrorCode::NotFound && expected_version.is_none() => {}
+                Err(e) | Ok(e) => return Err(e),
+            }
+        }
 
         Ok(res)
     }
 
+    /// Why a write limited to `user_id`'s submission at the expected version
+    /// matched nothing.
+    fn refusal(id: Uuid, user_id: Uuid) -> Result<ApiError, ApiError> {
+        Ok(match Submission::find(id)?.user_id == user_id {
+            true => ApiError::precondition_failed(),
+            false => ApiError::new(ErrorCode::Forbidden, "Forbidden".to_string()),
+        })
+    }
+
+    pub fn mark_queued(id: Uuid) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let now = Utc::now().naive_utc();
+
+        let submission = diesel::update(submission::table.filter(submission::id.eq(id)))
+            .set((
+                submission::status.eq(STATUS_QUEUED),
+                submission::queued_at.eq(now),
+                submission::updated_at.eq(now),
+                submission::data_version.eq(submission::data_version + 1),
+            ))
+            .get_result(&conn)?;
+
+        Ok(submission)
+    }
+
+    /// Queues the run again after an attempt at it timed out, unless a result
+    /// came in after all.
+    pub fn requeue(id: Uuid) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let submission = diesel::update(
+            submission::table
+                .filter(submission::id.eq(id))
+                .filter(submission::status.ne_all(TERMINAL_STATUSES))
+                .filter(submission::status.ne(STATUS_CANCELLING)),
+        )
+        .set((
+            submission::status.eq(STATUS_QUEUED),
+            submission::updated_at.eq(Utc::now().naive_utc()),
+            submission::data_version.eq(submission::data_version + 1),
+        ))
+        .get_result(&conn)
+        .optional()?;
+
+        Ok(submission)
+    }
+
+    /// Queues the run again for an administrator, after the worker gave up on
+    /// it. Unlike `requeue`, this also brings back a run that has timed out.
+    pub fn revive(id: Uuid) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+        let now = Utc::now().naive_utc();
+
+        let submission = diesel::update(
+            submission::table
+                .filter(submission::id.eq(id))
+                .filter(
+                    submission::status
+                        .ne_all(TERMINAL_STATUSES)
+                        .or(submission::status.eq(STATUS_TIMED_OUT)),
+                )
+                .filter(submission::status.ne(STATUS_CANCELLING)),
+        )
+        .set((
+            submission::status.eq(STATUS_QUEUED),
+            submission::queued_at.eq(now),
+            submission::updated_at.eq(now),
+            submission::data_version.eq(submission::data_version + 1),
+        ))
+        .get_result(&conn)
+        .optional()?;
+
+        Ok(submission)
+    }
+
+    /// Cancels the run in progress. One still waiting in the queue is cancelled
+    /// outright; one the worker has picked up becomes `cancelling`. Returns
+    /// `None` when there is no run to cancel.
+    pub fn cancel(id: Uuid) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+        let now = Utc::now().naive_utc();
+
+        let cancelled = diesel::update(
+            submission::table
+                .filter(submission::id.eq(id))
+                .filter(submission::status.eq(STATUS_QUEUED)),
+        )
+        .set((
+            submission::status.eq(STATUS_CANCELLED),
+            submission::updated_at.eq(now),
+            submission::data_version.eq(submission::data_version + 1),
+        ))
+        .get_result(&conn)
+        .optional()?;
+        if cancelled.is_some() {
+            return Ok(cancelled);
+        }
+
+        // Also catches a run the worker picked up since the first update
+        let cancelling = diesel::update(
+            submission::table
+                .filter(submission::id.eq(id))
+                .filter(submission::status.eq_any([STATUS_RECEIVED, STATUS_RUNNING])),
+        )
+        .set((
+            submission::status.eq(STATUS_CANCELLING),
+            submission::updated_at.eq(now),
+            submission::data_version.eq(submission::data_version + 1),
+        ))
+        .get_result(&conn)
+        .optional()?;
+
+        Ok(cancelling)
+    }
+
+    /// Records a worker status update. Terminal statuses also store the run's
+    /// output. Once a run has finished, further updates for it are ignored and
+    /// `None` is returned, so a redelivered message is not applied twice. Nor
+    /// does progress reported after a cancellation undo it.
+    pub fn apply_status(status: &JobStatus) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let target = submission::table
+            .filter(submission::id.eq(status.id))
+            .filter(submission::status.ne_all(TERMINAL_STATUSES));
+        let stamp = (
+            submission::status.eq(&status.status),
+            submission::updated_at.eq(Utc::now().naive_utc()),
+            submission::data_version.eq(submission::data_version + 1),
+        );
+
+        let submission = match status.is_terminal() {
+            true => diesel::update(target)
+                .set((
+                    stamp,
+                    submission::message.eq(non_empty(&status.message)),
+                    submission::error.eq(non_empty(&status.error)),
+                    submission::stdout.eq(non_empty(&status.stdout)),
+                    submission::stderr.eq(non_empty(&status.stderr)),
+                    submission::exec_duration.eq(status.exec_duration),
+                    submission::mem_usage.eq(status.mem_usage.clamp(0, i32::MAX as i64) as i32),
+                ))
+                .get_result(&conn)
+                .optional()?,
+            false => diesel::update(target.filter(submission::status.ne(STATUS_CANCELLING)))
+                .set(stamp)
+                .get_result(&conn)
+                .optional()?,
+        };
+
+        Ok(submission)
+    }
+
+    /// The user's runs that are queued or running.
+    pub fn count_in_flight(user_id: Uuid) -> Result<i64, ApiError> {
+        let conn = db::connection()?;
+
+        let count = submission::table
+            .filter(submission::user_id.eq(user_id))
+            .filter(submission::status.eq_any(IN_FLIGHT_STATUSES))
+            .count()
+            .get_result(&conn)?;
+
+        Ok(count)
+    }
+
     pub fn find_user_submissions(user_id: Uuid) -> Result<Vec<Self>, ApiError> {
         let conn = db::connection()?;
 
@@ -183,27 +553,79 @@ impl Submission {
     }
 }
 
-impl From<SubmissionMessage> for Submission {
-    fn from(submission: SubmissionMessage) -> Self {
+pub(super) fn non_empty(value: &str) -> Option<String> {
+    match value.is_empty() {
+        true => None,
+        false => Some(value.to_string()),
+    }
+}
+
+impl SubmissionMessage {
+    /// A new submission of `user_id`'s.
+    pub fn into_submission(self, user_id: Uuid) -> Submission {
         Submission {
             id: Uuid::new_v4(),
-            language: submission.language,
-            code: submission.code,
-            user_id: submission.user_id,
-            status: submission.status,
-            benchmark_id: submission.benchmark_id,
-            stdout: submission.stdout,
-            stderr: submission.stderr,
-            exec_duration: submission.exec_duration,
-            message: submission.message,
-            error: submission.error,
-            lint_score: submission.lint_score,
-            quality_score: submission.quality_score,
-            mem_usage: submission.mem_usage,
-            code_hash: submission.code_hash,
-            cyclomatic_complexity: submission.cyclomatic_complexity,
+            language: self.language,
+            code: self.code,
+            user_id,
+            status: self.status,
+            benchmark_id: self.benchmark_id,
+            stdout: self.stdout,
+            stderr: self.stderr,
+            exec_duration: self.exec_duration,
+            message: self.message,
+            error: self.error,
+            lint_score: self.lint_score,
+            quality_score: self.quality_score,
+            mem_usage: self.mem_usage,
+            code_hash: self.code_hash,
+            cyclomatic_complexity: self.cyclomatic_complexity,
             created_at: Utc::now().naive_utc(),
             updated_at: Some(Utc::now().naive_utc()),
+            data_version: 1,
+            queued_at: None,
+            stdin: self.stdin,
+            args: self.args,
+            env: self.env,
+            entrypoint: self.entrypoint,
+        }
+    }
+}
+
+impl SubmissionWorker {
+    /// The job for an attempt at the submission, run with the input copied onto
+    /// the attempt. `files` are the submission's, if it is a project.
+    pub fn new(submission: &Submission, files: Vec<SubmissionFile>, attempt: &JobAttempt) -> Self {
+        SubmissionWorker {
+            id: submission.id,
+            code: submission.code.clone(),
+            language: submission.language.clone(),
+            attempt: attempt.attempt,
+            priority: attempt.priority.clamp(0, u8::MAX as i16) as u8,
+            stdin: attempt.stdin.clone(),
+            args: attempt.args.clone(),
+            env: attempt.env.clone(),
+            files: files.into_iter().map(ProjectFile::from).collect(),
+            entrypoint: submission.entrypoint.clone(),
+            deadline: Utc.from_utc_datetime(&attempt.deadline),
+        }
+    }
+
+    /// The job for a scratch run. Its ID is the run's, and it has no attempts
+    /// but the first.
+    pub fn scratch(run: &ScratchRun, priority: u8) -> Self {
+        SubmissionWorker {
+            id: run.id,
+            code: run.code.clone(),
+            language: run.language.clone(),
+            attempt: 1,
+            priority,
+            stdin: run.stdin.clone(),
+            args: run.args.clone(),
+            env: run.env.clone(),
+            files: Vec::new(),
+            entrypoint: None,
+            deadline: Utc.from_utc_datetime(&run.deadline),
         }
     }
 }
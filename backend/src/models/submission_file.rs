@@ -0,0 +1,306 @@
+use crate::api_error::{ApiError, ErrorCode};
+use crate::db::{self, InstrumentedConnection};
+use crate::schema::{submission, submission_file};
+use crate::validation::ValidationErrors;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const PROJECT_MAX_FILES: usize = 100;
+pub const PROJECT_FILE_MAX_BYTES: usize = 128 * 1024;
+pub const PROJECT_MAX_BYTES: usize = 1024 * 1024;
+pub const PROJECT_PATH_MAX_BYTES: usize = 255;
+
+/// One file of a submission that is a project rather than a single `code`.
+#[derive(Serialize, Queryable, Insertable, Clone, ToSchema)]
+#[table_name = "submission_file"]
+pub struct SubmissionFile {
+    #[schema(value_type = String, format = Uuid)]
+    pub submission_id: Uuid,
+    /// Relative to the project's root, with `/` between directories.
+    pub path: String,
+    pub content: String,
+    pub executable: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A file as sent with a project, and as handed to the worker.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct ProjectFile {
+    pub path: String,
+    pub content: String,
+    #[serde(default)]
+    pub executable: bool,
+}
+
+/// The whole tree of a project. Saving one replaces the files the submission
+/// had before.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ProjectInput {
+    /// Path of the file the language's command runs.
+    pub entrypoint: String,
+    pub files: Vec<ProjectFile>,
+}
+
+/// The body of `PUT /submissions/{id}/files/{path}`.
+#[derive(Deserialize, ToSchema)]
+pub struct FileUpdate {
+    pub content: String,
+    #[serde(default)]
+    pub executable: bool,
+}
+
+impl ProjectInput {
+    pub fn validate(&self, errors: &mut ValidationErrors) {
+        validate_tree(errors, "project.files", &self.files);
+        if !self.files.iter().any(|file| file.path == self.entrypoint) {
+            errors.add("project.entrypoint", "must be one of the files");
+        }
+    }
+}
+
+impl From<SubmissionFile> for ProjectFile {
+    fn from(file: SubmissionFile) -> Self {
+        ProjectFile {
+            path: file.path,
+            content: file.content,
+            executable: file.executable,
+        }
+    }
+}
+
+/// Whether `path` stays inside the project's root: relative, with no empty, `.`
+/// or `..` parts.
+pub fn is_valid_path(path: &str) -> bool {
+    !path.is_empty()
+        && path.len() <= PROJECT_PATH_MAX_BYTES
+        && !path.contains(['\\', '\0'])
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+fn validate_tree(errors: &mut ValidationErrors, field: &str, files: &[ProjectFile]) {
+    if files.len() > PROJECT_MAX_FILES {
+        errors.add(
+            field,
+            &format!("must have at most {} files", PROJECT_MAX_FILES),
+        );
+    }
+    let total: usize = files.iter().map(|file| file.content.len()).sum();
+    if total > PROJECT_MAX_BYTES {
+        errors.add(
+            field,
+            &format!("must add up to at most {} KiB", PROJECT_MAX_BYTES / 1024),
+        );
+    }
+
+    let mut seen = HashSet::new();
+    for file in files {
+        if !is_valid_path(&file.path) {
+            errors.add(
+                field,
+                &format!("{:?} is not a valid relative path", file.path),
+            );
+        } else if !seen.insert(file.path.as_str()) {
+            errors.add(field, &format!("{:?} appears more than once", file.path));
+        }
+        if file.content.len() > PROJECT_FILE_MAX_BYTES {
+            errors.add(field, &format!("{:?} is too long", file.path));
+        }
+    }
+
+    // e.g. `lib` and `lib/util.py`, which cannot both be written out
+    for file in files {
+        let path = file.path.as_str();
+        let directories = path.match_indices('/').map(|(end, _)| &path[..end]);
+        for directory in directories.filter(|directory| seen.contains(directory)) {
+            errors.add(
+                field,
+                &format!("{:?} is both a file and a directory", directory),
+            );
+        }
+    }
+}
+
+/// The tree with the file at `path` added or replaced, if it stays within the
+/// limits.
+pub fn tree_with_file(
+    mut files: Vec<ProjectFile>,
+    path: &str,
+    update: FileUpdate,
+) -> Result<Vec<ProjectFile>, ApiError> {
+    files.retain(|file| file.path != path);
+    files.push(ProjectFile {
+        path: path.to_string(),
+        content: update.content,
+        executable: update.executable,
+    });
+
+    let mut errors = ValidationErrors::new();
+    validate_tree(&mut errors, "files", &files);
+    errors.into_result()?;
+
+    Ok(files)
+}
+
+pub fn not_a_project() -> ApiError {
+    ApiError::new(
+        ErrorCode::Conflict,
+        "The submission is not a project; save it with `project` first".to_string(),
+    )
+}
+
+pub fn entrypoint_in_use() -> ApiError {
+    ApiError::new(
+        ErrorCode::Conflict,
+        "The entrypoint cannot be deleted; save the project with another first".to_string(),
+    )
+}
+
+impl SubmissionFile {
+    pub fn find_by_submission(submission_id: Uuid) -> Result<Vec<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        SubmissionFile::load(&conn, submission_id)
+    }
+
+    pub fn find(submission_id: Uuid, path: &str) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let file = submission_file::table
+            .filter(submission_file::submission_id.eq(submission_id))
+            .filter(submission_file::path.eq(path))
+            .first(&conn)?;
+
+        Ok(file)
+    }
+
+    /// Adds the file to the project, or replaces the one at that path, and bumps
+    /// the submission's version.
+    pub fn put(submission_id: Uuid, path: &str, update: FileUpdate) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        conn.transaction::<_, ApiError, _>(|| {
+            // Locked, so that concurrent writes cannot together exceed the limits
+            let entrypoint: Option<String> = submission::table
+                .filter(submission::id.eq(submission_id))
+                .select(submission::entrypoint)
+                .for_update()
+                .first(&conn)?;
+            if entrypoint.is_none() {
+                return Err(not_a_project());
+            }
+
+            let files = SubmissionFile::load(&conn, submission_id)?
+                .into_iter()
+                .map(ProjectFile::from)
+                .collect();
+            let file = tree_with_file(files, path, update)?
+                .pop()
+                .expect("the file was just added");
+
+            let now = Utc::now().naive_utc();
+            let file = diesel::insert_into(submission_file::table)
+                .values(SubmissionFile {
+                    submission_id,
+                    path: file.path,
+                    content: file.content,
+                    executable: file.executable,
+                    updated_at: now,
+                })
+                .on_conflict((submission_file::submission_id, submission_file::path))
+                .do_update()
+                .set((
+                    submission_file::content
+                        .eq(diesel::pg::upsert::excluded(submission_file::content)),
+                    submission_file::executable
+                        .eq(diesel::pg::upsert::excluded(submission_file::executable)),
+                    submission_file::updated_at.eq(now),
+                ))
+                .get_result(&conn)?;
+            SubmissionFile::touch(&conn, submission_id)?;
+
+            Ok(file)
+        })
+    }
+
+    pub fn delete(submission_id: Uuid, path: &str) -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        conn.transaction::<_, ApiError, _>(|| {
+            let entrypoint: Option<String> = submission::table
+                .filter(submission::id.eq(submission_id))
+                .select(submission::entrypoint)
+                .for_update()
+                .first(&conn)?;
+            if entrypoint.as_deref() == Some(path) {
+                return Err(entrypoint_in_use());
+            }
+
+            let res = diesel::delete(
+                submission_file::table
+                    .filter(submission_file::submission_id.eq(submission_id))
+                    .filter(submission_file::path.eq(path)),
+            )
+            .execute(&conn)?;
+            if res > 0 {
+                SubmissionFile::touch(&conn, submission_id)?;
+            }
+
+            Ok(res)
+        })
+    }
+
+    /// Swaps the submission's files for `files`, as part of saving it.
+    pub(super) fn replace_all(
+        conn: &InstrumentedConnection,
+        submission_id: Uuid,
+        files: Vec<ProjectFile>,
+    ) -> Result<(), ApiError> {
+        diesel::delete(
+            submission_file::table.filter(submission_file::submission_id.eq(submission_id)),
+        )
+        .execute(conn)?;
+
+        let now = Utc::now().naive_utc();
+        let files: Vec<_> = files
+            .into_iter()
+            .map(|file| SubmissionFile {
+                submission_id,
+                path: file.path,
+                content: file.content,
+                executable: file.executable,
+                updated_at: now,
+            })
+            .collect();
+        diesel::insert_into(submission_file::table)
+            .values(&files)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    fn load(conn: &InstrumentedConnection, submission_id: Uuid) -> Result<Vec<Self>, ApiError> {
+        let files = submission_file::table
+            .filter(submission_file::submission_id.eq(submission_id))
+            .order(submission_file::path)
+            .load(conn)?;
+
+        Ok(files)
+    }
+
+    fn touch(conn: &InstrumentedConnection, submission_id: Uuid) -> Result<(), ApiError> {
+        diesel::update(submission::table.filter(submission::id.eq(submission_id)))
+            .set((
+                submission::updated_at.eq(Utc::now().naive_utc()),
+                submission::data_version.eq(submission::data_version + 1),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::two_factor_challenge;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const CHALLENGE_MAX_FAILURES: i32 = 5;
+
+/// Issued after a correct password for an account with two-factor authentication;
+/// the session is only established once the challenge is answered with a code.
+#[derive(Queryable, Insertable)]
+#[table_name = "two_factor_challenge"]
+pub struct TwoFactorChallenge {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub failed_attempts: i32,
+    pub created_at: NaiveDateTime,
+}
+
+impl TwoFactorChallenge {
+    pub fn create(user_id: Uuid) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let challenge = TwoFactorChallenge {
+            id: Uuid::new_v4(),
+            user_id,
+            failed_attempts: 0,
+            created_at: Utc::now().naive_utc(),
+        };
+
+        let challenge = diesel::insert_into(two_factor_challenge::table)
+            .values(challenge)
+            .get_result(&conn)?;
+
+        Ok(challenge)
+    }
+
+    /// Returns the challenge while it is neither expired nor exhausted.
+    pub fn find_valid(id: Uuid) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let expired_before = Utc::now().naive_utc() - Duration::minutes(CHALLENGE_TTL_MINUTES);
+        diesel::delete(
+            two_factor_challenge::table.filter(two_factor_challenge::created_at.lt(expired_before)),
+        )
+        .execute(&conn)?;
+
+        let challenge = two_factor_challenge::table
+            .filter(two_factor_challenge::id.eq(id))
+            .filter(two_factor_challenge::failed_attempts.lt(CHALLENGE_MAX_FAILURES))
+            .first(&conn)
+            .optional()?;
+
+        Ok(challenge)
+    }
+
+    pub fn record_failure(id: Uuid) -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        let res =
+            diesel::update(two_factor_challenge::table.filter(two_factor_challenge::id.eq(id)))
+                .set(
+                    two_factor_challenge::failed_attempts
+                        .eq(two_factor_challenge::failed_attempts + 1),
+                )
+                .execute(&conn)?;
+
+        Ok(res)
+    }
+
+    pub fn delete(id: Uuid) -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        let res =
+            diesel::delete(two_factor_challenge::table.filter(two_factor_challenge::id.eq(id)))
+                .execute(&conn)?;
+
+        Ok(res)
+    }
+}
@@ -1,16 +1,40 @@
-use crate::api_error::ApiError;
+use crate::api_error::{ApiError, ErrorCode};
 use crate::db;
+use crate::models::RecoveryCode;
 use crate::schema::user;
+use crate::totp;
+use crate::validation::{self, ValidationErrors};
 use argon2::Config;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
+use lazy_static::lazy_static;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Queryable, Insertable, Clone)]
+const EMAIL_VERIFICATION_TTL_HOURS: i64 = 24;
+const TOTP_ISSUER: &str = "Dune";
+
+pub const ROLE_USER: &str = "user";
+pub const ROLE_ADMIN: &str = "admin";
+
+lazy_static! {
+    // Verified against when the account does not exist so that unknown
+    // identifiers take as long to reject as wrong passwords.
+    static ref DUMMY_PASSWORD_HASH: String = {
+        let password: [u8; 32] = rand::thread_rng().gen();
+        let salt: [u8; 32] = rand::thread_rng().gen();
+        argon2::hash_encoded(&password, &salt, &Config::default())
+            .expect("Failed to hash dummy password")
+    };
+}
+
+#[derive(Serialize, Deserialize, Queryable, Insertable, Clone, ToSchema)]
 #[table_name = "user"]
 pub struct User {
+    #[schema(value_type = String, format = Uuid)]
     pub id: Uuid,
     pub name: String,
     pub email: String,
@@ -20,23 +44,120 @@ pub struct User {
     pub data_version: i32,
     pub created_at: NaiveDateTime,
     pub updated_at: Option<NaiveDateTime>,
+    pub pending_email: Option<String>,
+    #[serde(skip_serializing)]
+    pub email_verification_token: Option<String>,
+    #[serde(skip_serializing)]
+    pub email_verification_sent_at: Option<NaiveDateTime>,
+    pub role: String,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    #[serde(skip_serializing)]
+    pub totp_last_used_step: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, AsChangeset)]
-#[table_name = "user"]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct UserMessage {
     pub email: String,
     pub password: String,
     pub name: String,
     pub username: String,
 }
-#[derive(Deserialize)]
-pub struct LoginRequest {
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ProfileUpdate {
     pub email: String,
-    pub password: String,
+    pub name: String,
     pub username: String,
 }
 
+#[derive(Deserialize, ToSchema)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct EmailVerification {
+    pub token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RoleUpdate {
+    pub role: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+#[derive(Deserialize, ToSchema)]
+pub struct LoginRequest {
+    /// Either the account's email address or its username.
+    #[serde(alias = "email", alias = "username")]
+    pub identifier: String,
+    pub password: String,
+}
+
+impl UserMessage {
+    pub fn normalize(&mut self) {
+        self.email = validation::normalize_email(&self.email);
+        self.username = self.username.trim().to_string();
+        self.name = self.name.trim().to_string();
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        validation::validate_email(&mut errors, "email", &self.email);
+        validation::validate_username(&mut errors, "username", &self.username);
+        validation::validate_name(&mut errors, "name", &self.name);
+        validation::validate_password(
+            &mut errors,
+            "password",
+            &self.password,
+            &[&self.email, &self.username],
+        );
+
+        errors.into_result()
+    }
+}
+
+impl ProfileUpdate {
+    pub fn normalize(&mut self) {
+        self.email = validation::normalize_email(&self.email);
+        self.username = self.username.trim().to_string();
+        self.name = self.name.trim().to_string();
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        validation::validate_email(&mut errors, "email", &self.email);
+        validation::validate_username(&mut errors, "username", &self.username);
+        validation::validate_name(&mut errors, "name", &self.name);
+
+        errors.into_result()
+    }
+}
+
+impl LoginRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        if self.identifier.trim().is_empty() {
+            errors.add("identifier", "is required");
+        }
+        if self.password.is_empty() {
+            errors.add("password", "is required");
+        }
+
+        errors.into_result()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: Uuid,
@@ -46,8 +167,9 @@ struct Claims {
     exp: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthUser {
+    #[schema(value_type = String, format = Uuid)]
     pub id: Uuid,
     pub email: String,
     pub name: String,
@@ -93,26 +215,59 @@ impl User {
         Ok(user)
     }
 
-    pub fn find_by_email(email: String) -> Result<Self, ApiError> {
+    pub fn find_by_email(email: &str) -> Result<Option<Self>, ApiError> {
         let conn = db::connection()?;
 
-        let user = user::table.filter(user::email.eq(email)).first(&conn)?;
+        let user = user::table
+            .filter(user::email.eq(validation::normalize_email(email)))
+            .first(&conn)
+            .optional()?;
 
         Ok(user)
     }
 
-    pub fn find_by_email_or_username(email: String, username: String) -> Result<Self, ApiError> {
+    pub fn find_by_username(username: &str) -> Result<Option<Self>, ApiError> {
         let conn = db::connection()?;
 
         let user = user::table
-            .filter(user::email.eq(email))
-            .or_filter(user::username.eq(username))
-            .first(&conn)?;
+            .filter(user::username.eq(username.trim()))
+            .first(&conn)
+            .optional()?;
 
         Ok(user)
     }
 
-    pub fn create(user: UserMessage) -> Result<Self, ApiError> {
+    /// Looks an account up by the single identifier accepted on login. Usernames
+    /// cannot contain `@`, so anything that does is treated as an email address.
+    pub fn find_by_identifier(identifier: &str) -> Result<Option<Self>, ApiError> {
+        match identifier.contains('@') {
+            true => User::find_by_email(identifier),
+            false => User::find_by_username(identifier),
+        }
+    }
+
+    fn check_available(email: &str, username: &str, id: Option<Uuid>) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+
+        if let Some(existing) = User::find_by_email(email)? {
+            if Some(existing.id) != id {
+                errors.add("email", "is already registered");
+            }
+        }
+        if let Some(existing) = User::find_by_username(username)? {
+            if Some(existing.id) != id {
+                errors.add("username", "is already taken");
+            }
+        }
+
+        errors.into_result()
+    }
+
+    pub fn create(mut user: UserMessage) -> Result<Self, ApiError> {
+        user.normalize();
+        user.validate()?;
+        User::check_available(&user.email, &user.username, None)?;
+
         let conn = db::connection()?;
 
         let mut user = User::from(user);
@@ -125,37 +280,416 @@ impl User {
         Ok(user)
     }
 
-    pub fn update(id: Uuid, user: UserMessage) -> Result<Self, ApiError> {
+    /// Creates an account for someone signing in through an external provider for the
+    /// first time. The password is random and never disclosed, so the account can
+    /// only be reached through its linked identities.
+    pub fn provision(
+        email: &str,
+        name: Option<&str>,
+        username_hint: Option<&str>,
+    ) -> Result<Self, ApiError> {
+        let email = validation::normalize_email(email);
+        let mut errors = ValidationErrors::new();
+        validation::validate_email(&mut errors, "email", &email);
+        errors.into_result()?;
+
+        let username_hint = username_hint.unwrap_or_else(|| email.split('@').next().unwrap());
+        let username = User::available_username(username_hint)?;
+        let name = name
+            .map(|name| {
+                name.trim()
+                    .chars()
+                    .take(validation::NAME_MAX_LENGTH)
+                    .collect()
+            })
+            .filter(|name: &String| !name.is_empty())
+            .unwrap_or_else(|| username.clone());
+        let password: [u8; 32] = rand::thread_rng().gen();
+
+        let mut user = User::from(UserMessage {
+            email,
+            password: base64::encode(password),
+            name,
+            username,
+        });
+        user.hash_password()?;
+
+        let conn = db::connection()?;
+
+        let user = diesel::insert_into(user::table)
+            .values(user)
+            .get_result(&conn)?;
+
+        Ok(user)
+    }
+
+    fn available_username(hint: &str) -> Result<String, ApiError> {
+        // Leave room for the disambiguating suffix
+        let mut base: String = hint
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+            .take(validation::USERNAME_MAX_LENGTH - 5)
+            .collect();
+        if base.len() < validation::USERNAME_MIN_LENGTH {
+            base = "user".to_string();
+        }
+
+        if User::find_by_username(&base)?.is_none() {
+            return Ok(base);
+        }
+        for _ in 0..10 {
+            let candidate = format!("{}-{:04}", base, rand::thread_rng().gen_range(0..10000));
+            if User::find_by_username(&candidate)?.is_none() {
+                return Ok(candidate);
+            }
+        }
+
+        Err(ApiError::new(
+            ErrorCode::Conflict,
+            "Could not find an available username".to_string(),
+        ))
+    }
+
+    /// Updates the profile. A new email address is not applied until it has been
+    /// confirmed through [`User::verify_email`]; until then it is kept as pending,
+    /// and the token to send to it is returned along with the user. Saving the
+    /// pending address again issues a fresh token, e.g. when the first got lost.
+    /// When `expected_version` is given the update only applies to that version.
+    pub fn update(
+        id: Uuid,
+        mut profile: ProfileUpdate,
+        expected_version: Option<i32>,
+    ) -> Result<(Self, Option<String>), ApiError> {
+        profile.normalize();
+        profile.validate()?;
+        User::check_available(&profile.email, &profile.username, Some(id))?;
+
+        let conn = db::connection()?;
+
+        let current: User = user::table.filter(user::id.eq(id)).first(&conn)?;
+
+        let email_changed = profile.email != current.email;
+        let token = generate_token();
+
+        let user = conn.transaction::<User, ApiError, _>(|| {
+            let user = diesel::update(user::table)
+                .filter(user::id.eq(id))
+                .filter(user::data_version.eq(db::coalesce(expected_version, user::data_version)))
+                .set((
+                    user::name.eq(&profile.name),
+                    user::username.eq(&profile.username),
+                    user::updated_at.eq(Utc::now().naive_utc()),
+                    user::data_version.eq(user::data_version + 1),
+                ))
+                .get_result(&conn)
+                .optional()?
+                .ok_or_else(ApiError::precondition_failed)?;
+
+            if !email_changed {
+                return Ok(user);
+            }
+
+            let user = diesel::update(user::table)
+                .filter(user::id.eq(id))
+                .set((
+                    user::pending_email.eq(&profile.email),
+                    user::email_verification_token.eq(hash_token(&token)),
+                    user::email_verification_sent_at.eq(Utc::now().naive_utc()),
+                ))
+                .get_result(&conn)?;
+
+            Ok(user)
+        })?;
+
+        Ok((user, Some(token).filter(|_| email_changed)))
+    }
+
+    /// Confirms a pending email change with the token sent to the new address.
+    pub fn verify_email(id: Uuid, token: &str) -> Result<Self, ApiError> {
         let conn = db::connection()?;
 
+        let current: User = user::table.filter(user::id.eq(id)).first(&conn)?;
+        let pending_email = current.confirmable_email(token)?;
+
+        User::check_available(&pending_email, &current.username, Some(id))?;
+
         let user = diesel::update(user::table)
             .filter(user::id.eq(id))
-            .set(user)
+            .set((
+                user::email.eq(&pending_email),
+                user::pending_email.eq(None::<String>),
+                user::email_verification_token.eq(None::<String>),
+                user::email_verification_sent_at.eq(None::<NaiveDateTime>),
+                user::updated_at.eq(Utc::now().naive_utc()),
+                user::data_version.eq(user::data_version + 1),
+            ))
             .get_result(&conn)?;
 
         Ok(user)
     }
 
-    pub fn delete(id: Uuid) -> Result<usize, ApiError> {
+    /// The pending email address, provided `token` is the one sent to it and has
+    /// not expired yet.
+    pub fn confirmable_email(&self, token: &str) -> Result<String, ApiError> {
+        match (
+            &self.pending_email,
+            &self.email_verification_token,
+            self.email_verification_sent_at,
+        ) {
+            (Some(pending_email), Some(token_hash), Some(sent_at))
+                if *token_hash == hash_token(token)
+                    && Utc::now().naive_utc() - sent_at
+                        < Duration::hours(EMAIL_VERIFICATION_TTL_HOURS) =>
+            {
+                Ok(pending_email.clone())
+            }
+            _ => Err(ApiError::new(
+                ErrorCode::InvalidToken,
+                "Invalid or expired verification token".to_string(),
+            )),
+        }
+    }
+
+    /// Replaces the password after checking the current one. Callers are expected
+    /// to throttle repeated failures.
+    pub fn change_password(&self, change: &PasswordChange) -> Result<Option<Self>, ApiError> {
+        let user = match self.with_new_password(change)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
         let conn = db::connection()?;
 
-        let res = diesel::delete(user::table.filter(user::id.eq(id))).execute(&conn)?;
+        let user = diesel::update(user::table)
+            .filter(user::id.eq(self.id))
+            .set((
+                user::password.eq(user.password),
+                user::updated_at.eq(Utc::now().naive_utc()),
+                user::data_version.eq(user::data_version + 1),
+            ))
+            .get_result(&conn)?;
+
+        Ok(Some(user))
+    }
+
+    /// A copy of the user with the new password hashed in, or `None` when the
+    /// current password does not match.
+    pub fn with_new_password(&self, change: &PasswordChange) -> Result<Option<Self>, ApiError> {
+        if !self.verify_password(change.current_password.as_bytes())? {
+            return Ok(None);
+        }
+
+        let mut errors = ValidationErrors::new();
+        validation::validate_password(
+            &mut errors,
+            "new_password",
+            &change.new_password,
+            &[&self.email, &self.username],
+        );
+        if change.new_password == change.current_password {
+            errors.add("new_password", "must differ from the current password");
+        }
+        errors.into_result()?;
+
+        let mut user = self.clone();
+        user.password = change.new_password.clone();
+        user.hash_password()?;
+
+        Ok(Some(user))
+    }
+
+    pub fn delete(id: Uuid, expected_version: Option<i32>) -> Result<usize, ApiError> {
+        let conn = db::connection()?;
+
+        let res = diesel::delete(
+            user::table
+                .filter(user::id.eq(id))
+                .filter(user::data_version.eq(db::coalesce(expected_version, user::data_version))),
+        )
+        .execute(&conn)?;
+
+        if res == 0 && expected_version.is_some() {
+            User::find(id)?;
+            return Err(ApiError::precondition_failed());
+        }
 
         Ok(res)
     }
 
+    pub fn validate_role(role: &str) -> Result<(), ApiError> {
+        let mut errors = ValidationErrors::new();
+        if role != ROLE_USER && role != ROLE_ADMIN {
+            errors.add("role", "must be either 'user' or 'admin'");
+        }
+
+        errors.into_result()
+    }
+
+    pub fn set_role(id: Uuid, role: &str) -> Result<Self, ApiError> {
+        User::validate_role(role)?;
+
+        let conn = db::connection()?;
+
+        let user = diesel::update(user::table)
+            .filter(user::id.eq(id))
+            .set((
+                user::role.eq(role),
+                user::updated_at.eq(Utc::now().naive_utc()),
+                user::data_version.eq(user::data_version + 1),
+            ))
+            .get_result(&conn)?;
+
+        Ok(user)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN
+    }
+
+    /// Generates a fresh TOTP secret. It only takes effect once a code from it has
+    /// been confirmed through [`User::confirm_totp`].
+    pub fn begin_totp_enrollment(&self) -> Result<TotpEnrollment, ApiError> {
+        if self.totp_enabled {
+            return Err(ApiError::new(
+                ErrorCode::Conflict,
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+
+        let secret = totp::generate_secret();
+
+        let conn = db::connection()?;
+
+        diesel::update(user::table)
+            .filter(user::id.eq(self.id))
+            .set((
+                user::totp_secret.eq(&secret),
+                user::totp_last_used_step.eq(None::<i64>),
+            ))
+            .execute(&conn)?;
+
+        Ok(TotpEnrollment {
+            provisioning_uri: totp::provisioning_uri(&secret, &self.username, TOTP_ISSUER),
+            secret,
+        })
+    }
+
+    /// Enables two-factor authentication and returns the first set of recovery codes.
+    pub fn confirm_totp(&self, code: &str) -> Result<Vec<String>, ApiError> {
+        let secret = match (&self.totp_secret, self.totp_enabled) {
+            (Some(secret), false) => secret,
+            (_, true) => {
+                return Err(ApiError::new(
+                    ErrorCode::Conflict,
+                    "Two-factor authentication is already enabled".to_string(),
+                ))
+            }
+            (None, false) => {
+                return Err(ApiError::new(
+                    ErrorCode::BadRequest,
+                    "Two-factor enrollment has not been started".to_string(),
+                ))
+            }
+        };
+
+        let step = totp::verify(secret, code, None).ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::InvalidTwoFactorCode,
+                "Invalid two-factor code".to_string(),
+            )
+        })?;
+
+        let conn = db::connection()?;
+
+        diesel::update(user::table)
+            .filter(user::id.eq(self.id))
+            .set((
+                user::totp_enabled.eq(true),
+                user::totp_last_used_step.eq(step),
+                user::updated_at.eq(Utc::now().naive_utc()),
+                user::data_version.eq(user::data_version + 1),
+            ))
+            .execute(&conn)?;
+
+        RecoveryCode::regenerate(self.id)
+    }
+
+    /// Checks a code from the authenticator app, falling back to the recovery codes.
+    /// Either kind of code is only accepted once.
+    pub fn verify_second_factor(&self, code: &str) -> Result<bool, ApiError> {
+        let secret = match (&self.totp_secret, self.totp_enabled) {
+            (Some(secret), true) => secret,
+            _ => return Ok(false),
+        };
+
+        if let Some(step) = totp::verify(secret, code, self.totp_last_used_step) {
+            let conn = db::connection()?;
+
+            // Guarded so that concurrent requests cannot both redeem the same step
+            let updated = diesel::update(user::table)
+                .filter(user::id.eq(self.id))
+                .filter(
+                    user::totp_last_used_step
+                        .is_null()
+                        .or(user::totp_last_used_step.lt(step)),
+                )
+                .set(user::totp_last_used_step.eq(step))
+                .execute(&conn)?;
+
+            return Ok(updated == 1);
+        }
+
+        RecoveryCode::consume(self.id, code)
+    }
+
+    pub fn disable_totp(&self, code: &str) -> Result<Self, ApiError> {
+        if self.is_admin() {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Administrators cannot disable two-factor authentication".to_string(),
+            ));
+        }
+        if !self.verify_second_factor(code)? {
+            return Err(ApiError::new(
+                ErrorCode::InvalidTwoFactorCode,
+                "Invalid two-factor code".to_string(),
+            ));
+        }
+
+        let conn = db::connection()?;
+
+        let user = diesel::update(user::table)
+            .filter(user::id.eq(self.id))
+            .set((
+                user::totp_enabled.eq(false),
+                user::totp_secret.eq(None::<String>),
+                user::totp_last_used_step.eq(None::<i64>),
+                user::updated_at.eq(Utc::now().naive_utc()),
+                user::data_version.eq(user::data_version + 1),
+            ))
+            .get_result(&conn)?;
+
+        RecoveryCode::delete_all(self.id)?;
+
+        Ok(user)
+    }
+
     pub fn hash_password(&mut self) -> Result<(), ApiError> {
         let salt: [u8; 32] = rand::thread_rng().gen();
         let config = Config::default();
 
         self.password = argon2::hash_encoded(self.password.as_bytes(), &salt, &config)
-            .map_err(|e| ApiError::new(500, format!("Failed to hash password: {}", e)))?;
+            .map_err(|e| ApiError::internal(format!("Failed to hash password: {}", e)))?;
 
         Ok(())
     }
     pub fn verify_password(&self, password: &[u8]) -> Result<bool, ApiError> {
         argon2::verify_encoded(&self.password, password)
-            .map_err(|e| ApiError::new(500, format!("Failed to verify password: {}", e)))
+            .map_err(|e| ApiError::internal(format!("Failed to verify password: {}", e)))
+    }
+
+    pub fn verify_dummy_password(password: &[u8]) {
+        let _ = argon2::verify_encoded(&DUMMY_PASSWORD_HASH, password);
     }
 }
 
@@ -170,6 +704,25 @@ impl From<UserMessage> for User {
             created_at: Utc::now().naive_utc(),
             updated_at: None,
             data_version: 1,
+            pending_email: None,
+            email_verification_token: None,
+            email_verification_sent_at: None,
+            role: ROLE_USER.to_string(),
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_used_step: None,
         }
     }
 }
+
+pub fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// Tokens are high-entropy, so a plain digest is enough to keep them out of the table
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    base64::encode(hasher.finalize())
+}
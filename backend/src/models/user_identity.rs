@@ -0,0 +1,71 @@
+use crate::api_error::ApiError;
+use crate::db;
+use crate::schema::user_identity;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// An account at an external OAuth2/OpenID Connect provider linked to a [`User`](super::User).
+#[derive(Serialize, Deserialize, Queryable, Insertable, ToSchema)]
+#[table_name = "user_identity"]
+pub struct UserIdentity {
+    #[schema(value_type = String, format = Uuid)]
+    pub id: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    pub user_id: Uuid,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl UserIdentity {
+    pub fn find_by_subject(provider: &str, subject: &str) -> Result<Option<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let identity = user_identity::table
+            .filter(user_identity::provider.eq(provider))
+            .filter(user_identity::subject.eq(subject))
+            .first(&conn)
+            .optional()?;
+
+        Ok(identity)
+    }
+
+    pub fn find_user_identities(user_id: Uuid) -> Result<Vec<Self>, ApiError> {
+        let conn = db::connection()?;
+
+        let identities = user_identity::table
+            .filter(user_identity::user_id.eq(user_id))
+            .order(user_identity::created_at)
+            .load(&conn)?;
+
+        Ok(identities)
+    }
+
+    pub fn create(
+        user_id: Uuid,
+        provider: &str,
+        subject: &str,
+        email: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let conn = db::connection()?;
+
+        let identity = UserIdentity {
+            id: Uuid::new_v4(),
+            user_id,
+            provider: provider.to_string(),
+            subject: subject.to_string(),
+            email,
+            created_at: Utc::now().naive_utc(),
+        };
+
+        let identity = diesel::insert_into(user_identity::table)
+            .values(identity)
+            .get_result(&conn)?;
+
+        Ok(identity)
+    }
+}
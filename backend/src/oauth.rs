@@ -0,0 +1,440 @@
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::{OAuthConfig, OAuthProviderConfig, ServerConfig};
+use crate::models::OAUTH_STATE_TTL_MINUTES;
+use actix_web::cookie::{time, Cookie, CookieJar, Key, SameSite};
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Ties an authorization request to the browser that started it.
+const STATE_COOKIE: &str = "oauth_state";
+/// Covers both the login and the callback routes of every provider.
+const STATE_COOKIE_PATH: &str = "/api/auth/";
+
+lazy_static! {
+    static ref DISCOVERED: Mutex<HashMap<String, Endpoints>> = Mutex::new(HashMap::new());
+}
+
+/// An OAuth2 / OpenID Connect provider, as configured in `[oauth.providers]` with
+/// the defaults for its name filled in.
+#[derive(Clone, Debug)]
+pub struct OAuthProvider {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub issuer: Option<String>,
+    pub authorization_url: Option<String>,
+    pub token_url: Option<String>,
+    pub userinfo_url: Option<String>,
+    /// GitHub-style endpoint listing the account's addresses, used when the
+    /// profile itself does not carry a (public) email.
+    pub emails_url: Option<String>,
+    pub redirect_url: Option<String>,
+    pub scopes: String,
+    pub subject_claim: String,
+    pub email_claim: String,
+    pub username_claim: String,
+    pub name_claim: String,
+    /// Take the email claim as verified when the profile does not say either way.
+    /// Only for providers that never hand out addresses they have not checked.
+    pub trust_email: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Endpoints {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct ProviderEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// The subset of the provider's profile we use to link or provision an account.
+pub struct ExternalProfile {
+    pub subject: String,
+    /// Only set when the provider flags the address as verified, or is configured
+    /// to be trusted with it.
+    pub email: Option<String>,
+    pub username: Option<String>,
+    pub name: Option<String>,
+}
+
+/// The provider configured under `name`. Providers are checked when the
+/// configuration is loaded, so any configured one is usable.
+pub fn provider(config: &OAuthConfig, name: &str) -> Option<OAuthProvider> {
+    let provider = config.providers.get(name)?;
+    OAuthProvider::from_config(name, provider).ok()
+}
+
+impl OAuthProvider {
+    pub fn from_config(name: &str, config: &OAuthProviderConfig) -> Result<Self, String> {
+        let mut provider = match name {
+            "github" => OAuthProvider {
+                authorization_url: Some("https://github.com/login/oauth/authorize".to_string()),
+                token_url: Some("https://github.com/login/oauth/access_token".to_string()),
+                userinfo_url: Some("https://api.github.com/user".to_string()),
+                emails_url: Some("https://api.github.com/user/emails".to_string()),
+                scopes: "read:user user:email".to_string(),
+                subject_claim: "id".to_string(),
+                username_claim: "login".to_string(),
+                ..OAuthProvider::generic(name)
+            },
+            "gitlab" => OAuthProvider {
+                issuer: Some("https://gitlab.com".to_string()),
+                ..OAuthProvider::generic(name)
+            },
+            _ => OAuthProvider::generic(name),
+        };
+
+        if config.client_id.is_empty() || config.client_secret.is_empty() {
+            return Err("client_id and client_secret must be set".to_string());
+        }
+        provider.client_id = config.client_id.clone();
+        provider.client_secret = config.client_secret.clone();
+        provider.issuer = config.issuer.clone().or(provider.issuer);
+        provider.authorization_url = config
+            .authorization_url
+            .clone()
+            .or(provider.authorization_url);
+        provider.token_url = config.token_url.clone().or(provider.token_url);
+        provider.userinfo_url = config.userinfo_url.clone().or(provider.userinfo_url);
+        provider.emails_url = config.emails_url.clone().or(provider.emails_url);
+        provider.redirect_url = config.redirect_url.clone();
+        provider.scopes = config.scopes.clone().unwrap_or(provider.scopes);
+        provider.subject_claim = config
+            .subject_claim
+            .clone()
+            .unwrap_or(provider.subject_claim);
+        provider.email_claim = config.email_claim.clone().unwrap_or(provider.email_claim);
+        provider.username_claim = config
+            .username_claim
+            .clone()
+            .unwrap_or(provider.username_claim);
+        provider.name_claim = config.name_claim.clone().unwrap_or(provider.name_claim);
+        provider.trust_email = config.trust_email;
+
+        let explicit = provider.authorization_url.is_some()
+            && provider.token_url.is_some()
+            && provider.userinfo_url.is_some();
+        if !explicit && provider.issuer.is_none() {
+            return Err("either issuer or all endpoint URLs must be set".to_string());
+        }
+
+        Ok(provider)
+    }
+
+    fn generic(name: &str) -> Self {
+        OAuthProvider {
+            name: name.to_string(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            issuer: None,
+            authorization_url: None,
+            token_url: None,
+            userinfo_url: None,
+            emails_url: None,
+            redirect_url: None,
+            scopes: "openid profile email".to_string(),
+            subject_claim: "sub".to_string(),
+            email_claim: "email".to_string(),
+            username_claim: "preferred_username".to_string(),
+            name_claim: "name".to_string(),
+            trust_email: false,
+        }
+    }
+
+    /// The callback URL registered with the provider, derived from the incoming
+    /// request unless configured explicitly.
+    pub fn redirect_uri(&self, req: &HttpRequest) -> String {
+        match &self.redirect_url {
+            Some(url) => url.clone(),
+            None => {
+                let info = req.connection_info();
+                format!(
+                    "{}://{}/api/auth/{}/callback/",
+                    info.scheme(),
+                    info.host(),
+                    self.name
+                )
+            }
+        }
+    }
+
+    async fn endpoints(&self) -> Result<Endpoints, ApiError> {
+        if let (Some(authorization_url), Some(token_url), Some(userinfo_url)) =
+            (&self.authorization_url, &self.token_url, &self.userinfo_url)
+        {
+            return Ok(Endpoints {
+                authorization_endpoint: authorization_url.clone(),
+                token_endpoint: token_url.clone(),
+                userinfo_endpoint: userinfo_url.clone(),
+            });
+        }
+
+        if let Some(endpoints) = DISCOVERED.lock().unwrap().get(&self.name) {
+            return Ok(endpoints.clone());
+        }
+
+        let issuer = self.issuer.as_deref().unwrap_or_default();
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let discovered: Endpoints = awc::Client::default()
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| provider_error(&self.name, e))?
+            .json()
+            .await
+            .map_err(|e| provider_error(&self.name, e))?;
+
+        let endpoints = Endpoints {
+            authorization_endpoint: self
+                .authorization_url
+                .clone()
+                .unwrap_or(discovered.authorization_endpoint),
+            token_endpoint: self.token_url.clone().unwrap_or(discovered.token_endpoint),
+            userinfo_endpoint: self
+                .userinfo_url
+                .clone()
+                .unwrap_or(discovered.userinfo_endpoint),
+        };
+
+        DISCOVERED
+            .lock()
+            .unwrap()
+            .insert(self.name.clone(), endpoints.clone());
+
+        Ok(endpoints)
+    }
+
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> Result<String, ApiError> {
+        let endpoints = self.endpoints().await?;
+
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", self.scopes.as_str()),
+            ("state", state),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ])
+        .map_err(|e| ApiError::internal(format!("Failed to encode OAuth request: {}", e)))?;
+
+        let separator = match endpoints.authorization_endpoint.contains('?') {
+            true => '&',
+            false => '?',
+        };
+
+        Ok(format!(
+            "{}{}{}",
+            endpoints.authorization_endpoint, separator, query
+        ))
+    }
+
+    /// Redeems the authorization code for an access token.
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<String, ApiError> {
+        let endpoints = self.endpoints().await?;
+
+        let mut response = awc::Client::default()
+            .post(&endpoints.token_endpoint)
+            .insert_header(("Accept", "application/json"))
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("code_verifier", code_verifier),
+            ])
+            .await
+            .map_err(|e| provider_error(&self.name, e))?;
+
+        if !response.status().is_success() {
+            return Err(ApiError::new(
+                ErrorCode::OauthDenied,
+                "The OAuth provider rejected the authorization code".to_string(),
+            ));
+        }
+
+        let token: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| provider_error(&self.name, e))?;
+
+        Ok(token.access_token)
+    }
+
+    /// Fetches the user's profile from the userinfo endpoint. The ID token is not
+    /// inspected: userinfo is authoritative and fetched directly from the provider.
+    pub async fn fetch_profile(&self, access_token: &str) -> Result<ExternalProfile, ApiError> {
+        let endpoints = self.endpoints().await?;
+
+        let claims: Value = self
+            .get_json(&endpoints.userinfo_endpoint, access_token)
+            .await?;
+
+        let subject = match claims.get(&self.subject_claim) {
+            Some(Value::String(subject)) => subject.clone(),
+            Some(Value::Number(subject)) => subject.to_string(),
+            _ => return Err(provider_error(&self.name, "profile has no subject")),
+        };
+
+        let claim = |name: &str| {
+            claims
+                .get(name)
+                .and_then(Value::as_str)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+
+        let email_verified = claims
+            .get("email_verified")
+            .and_then(Value::as_bool)
+            .unwrap_or(self.trust_email);
+        let mut email = claim(&self.email_claim).filter(|_| email_verified);
+
+        if email.is_none() {
+            if let Some(emails_url) = &self.emails_url {
+                let emails: Vec<ProviderEmail> = self.get_json(emails_url, access_token).await?;
+                email = emails
+                    .into_iter()
+                    .find(|email| email.primary && email.verified)
+                    .map(|email| email.email);
+            }
+        }
+
+        Ok(ExternalProfile {
+            subject,
+            email,
+            username: claim(&self.username_claim),
+            name: claim(&self.name_claim),
+        })
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        access_token: &str,
+    ) -> Result<T, ApiError> {
+        let mut response = awc::Client::default()
+            .get(url)
+            .bearer_auth(access_token)
+            .insert_header(("Accept", "application/json"))
+            // GitHub rejects API requests without a user agent
+            .insert_header(("User-Agent", "dune"))
+            .send()
+            .await
+            .map_err(|e| provider_error(&self.name, e))?;
+
+        if !response.status().is_success() {
+            return Err(provider_error(
+                &self.name,
+                format!("{} returned {}", url, response.status()),
+            ));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| provider_error(&self.name, e))
+    }
+}
+
+fn provider_error(provider: &str, error: impl std::fmt::Display) -> ApiError {
+    ApiError::new(
+        ErrorCode::UpstreamError,
+        format!("OAuth provider {} failed: {}", provider, error),
+    )
+}
+
+pub fn random_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// A signed cookie holding `state`. The callback only accepts a state that comes
+/// with it, so a callback URL cannot be completed in anyone else's browser.
+pub fn state_cookie(config: &ServerConfig, state: &str) -> Cookie<'static> {
+    let cookie = Cookie::build(STATE_COOKIE, state.to_string())
+        .path(STATE_COOKIE_PATH)
+        .domain(config.domain.clone())
+        .http_only(true)
+        .secure(config.secure_cookie)
+        // The provider sends the browser back with a cross-site top-level GET
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(OAUTH_STATE_TTL_MINUTES))
+        .finish();
+
+    let mut jar = CookieJar::new();
+    jar.signed_mut(&cookie_key(config)).add(cookie);
+    jar.delta()
+        .next()
+        .expect("the cookie was just added")
+        .clone()
+}
+
+/// Whether the request carries the cookie `state_cookie` set for `state`.
+pub fn state_cookie_matches(config: &ServerConfig, req: &HttpRequest, state: &str) -> bool {
+    let cookie = match req.cookie(STATE_COOKIE) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    jar.signed(&cookie_key(config))
+        .get(STATE_COOKIE)
+        .is_some_and(|cookie| cookie.value() == state)
+}
+
+pub fn clear_state_cookie(config: &ServerConfig, response: &mut HttpResponse) {
+    let cookie = Cookie::build(STATE_COOKIE, "")
+        .path(STATE_COOKIE_PATH)
+        .domain(config.domain.clone())
+        .finish();
+    // Only fails for a cookie that cannot be a header value, which this is not
+    let _ = response.add_removal_cookie(&cookie);
+}
+
+fn cookie_key(config: &ServerConfig) -> Key {
+    Key::derive_from(config.secret_token.as_bytes())
+}
+
+/// Returns a PKCE `(code_verifier, code_challenge)` pair using the S256 method.
+pub fn pkce_pair() -> (String, String) {
+    let verifier = random_token();
+
+    let mut hasher = Sha256::new();
+    hasher.update(verifier.as_bytes());
+    let challenge = base64::encode_config(hasher.finalize(), base64::URL_SAFE_NO_PAD);
+
+    (verifier, challenge)
+}
@@ -0,0 +1,222 @@
+use crate::api_error::ApiError;
+use crate::client_ip::client_ip;
+use crate::models::AuthUser;
+use actix_identity::RequestIdentity;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{Error, ResponseError};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How often idle buckets are swept out of the shared map
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Rule {
+    method: Method,
+    pattern: String,
+    capacity: u32,
+    refill_every: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Buckets {
+    entries: HashMap<(usize, String), Bucket>,
+    pruned_at: Instant,
+}
+
+struct Decision {
+    limit: u32,
+    remaining: u32,
+    retry_after: Option<u64>,
+}
+
+/// Token buckets per route and caller. Each rule allows `capacity` requests in a
+/// burst and hands back one token every `refill_every`. Callers are told apart by
+/// their session's user id, falling back to the client address.
+///
+/// The state is shared through an `Arc`, so build the limiter once, outside the
+/// `HttpServer` factory, and clone it into every worker.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rules: Arc<Vec<Rule>>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            rules: Arc::new(Vec::new()),
+            buckets: Arc::new(Mutex::new(Buckets {
+                entries: HashMap::new(),
+                pruned_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Limits requests whose matched route is `pattern`, written the way it is
+    /// registered including the scope, e.g. `/api/submissions/run/{id}/`.
+    pub fn rule(
+        mut self,
+        method: Method,
+        pattern: &str,
+        capacity: u32,
+        refill_every: Duration,
+    ) -> Self {
+        Arc::get_mut(&mut self.rules)
+            .expect("rules are only added before the limiter is shared")
+            .push(Rule {
+                method,
+                pattern: pattern.to_string(),
+                capacity: capacity.max(1),
+                refill_every,
+            });
+        self
+    }
+
+    fn check(&self, req: &ServiceRequest) -> Option<Decision> {
+        let pattern = req.match_pattern()?;
+        let (index, rule) = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.method == req.method() && rule.pattern == pattern)?;
+
+        let caller = req
+            .get_identity()
+            .and_then(|identity| serde_json::from_str::<AuthUser>(&identity).ok())
+            .map(|user| format!("user:{}", user.id))
+            .unwrap_or_else(|| format!("ip:{}", client_ip(req.request())));
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if now.duration_since(buckets.pruned_at) >= PRUNE_INTERVAL {
+            let rules = &self.rules;
+            buckets.entries.retain(|(index, _), bucket| {
+                let rule = &rules[*index];
+                refilled(bucket, rule, now) < rule.capacity as f64
+            });
+            buckets.pruned_at = now;
+        }
+
+        let bucket = buckets
+            .entries
+            .entry((index, caller))
+            .or_insert_with(|| Bucket {
+                tokens: rule.capacity as f64,
+                updated_at: now,
+            });
+        bucket.tokens = refilled(bucket, rule, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Some(Decision {
+                limit: rule.capacity,
+                remaining: bucket.tokens.floor() as u32,
+                retry_after: None,
+            });
+        }
+
+        let wait = rule.refill_every.as_secs_f64() * (1.0 - bucket.tokens);
+        Some(Decision {
+            limit: rule.capacity,
+            remaining: 0,
+            retry_after: Some(wait.ceil().max(1.0) as u64),
+        })
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new()
+    }
+}
+
+fn refilled(bucket: &Bucket, rule: &Rule, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+    let refill = elapsed / rule.refill_every.as_secs_f64().max(f64::EPSILON);
+    (bucket.tokens + refill).min(rule.capacity as f64)
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let decision = match self.limiter.check(&req) {
+            Some(decision) => decision,
+            None => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+            }
+        };
+
+        if let Some(retry_after) = decision.retry_after {
+            let mut res = ApiError::too_many_requests(retry_after, "Too many requests".to_string())
+                .error_response();
+            set_limit_headers(res.headers_mut(), &decision);
+            return Box::pin(async move { Ok(req.into_response(res).map_into_right_body()) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            set_limit_headers(res.headers_mut(), &decision);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+fn set_limit_headers(headers: &mut actix_web::http::header::HeaderMap, decision: &Decision) {
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+}
@@ -0,0 +1,582 @@
+//! Repositories that keep everything in a `Vec`, for exercising handlers
+//! without a database. They apply the same validation and version checks as
+//! the Postgres ones, but not the database's foreign keys.
+
+use super::{BenchmarkRepo, SubmissionRepo, UserRepo};
+use crate::api_error::{ApiError, ErrorCode};
+use crate::models::{
+    self, Benchmark, BenchmarkInput, BenchmarkMessage, ContestUpdate, FileUpdate, PasswordChange,
+    ProfileUpdate, ProjectFile, Submission, SubmissionFile, SubmissionInput, SubmissionMessage,
+    User, UserMessage, IN_FLIGHT_STATUSES, STATUS_CANCELLED, STATUS_CANCELLING, STATUS_QUEUED,
+    STATUS_RECEIVED, STATUS_RUNNING,
+};
+use crate::validation::{self, ValidationErrors};
+use chrono::Utc;
+use diesel::result::Error as DieselError;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+// Same error a missing row gives the Postgres repositories
+fn not_found() -> ApiError {
+    ApiError::from(DieselError::NotFound)
+}
+
+// Checked before the version, as the Postgres repositories do
+fn check_owner(owner: bool) -> Result<(), ApiError> {
+    match owner {
+        true => Ok(()),
+        false => Err(ApiError::new(ErrorCode::Forbidden, "Forbidden".to_string())),
+    }
+}
+
+fn check_version(current: i32, expected: Option<i32>) -> Result<(), ApiError> {
+    match expected {
+        Some(expected) if expected != current => Err(ApiError::precondition_failed()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryUserRepo {
+    users: Mutex<Vec<User>>,
+}
+
+impl MemoryUserRepo {
+    /// Stores a user as given, replacing any with the same ID. For setting up
+    /// accounts registration cannot produce, e.g. administrators.
+    pub fn insert(&self, user: User) {
+        let mut users = self.users.lock().unwrap();
+        users.retain(|existing| existing.id != user.id);
+        users.push(user);
+    }
+
+    fn with_user<T>(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(&mut User) -> Result<T, ApiError>,
+    ) -> Result<T, ApiError> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .iter_mut()
+            .find(|user| user.id == id)
+            .ok_or_else(not_found)?;
+
+        f(user)
+    }
+}
+
+fn check_available(
+    users: &[User],
+    email: &str,
+    username: &str,
+    id: Option<Uuid>,
+) -> Result<(), ApiError> {
+    let mut errors = ValidationErrors::new();
+
+    if users
+        .iter()
+        .any(|user| user.email == email && Some(user.id) != id)
+    {
+        errors.add("email", "is already registered");
+    }
+    if users
+        .iter()
+        .any(|user| user.username == username && Some(user.id) != id)
+    {
+        errors.add("username", "is already taken");
+    }
+
+    errors.into_result()
+}
+
+impl UserRepo for MemoryUserRepo {
+    fn find_all(&self) -> Result<Vec<User>, ApiError> {
+        Ok(self.users.lock().unwrap().clone())
+    }
+
+    fn find(&self, id: Uuid) -> Result<User, ApiError> {
+        self.with_user(id, |user| Ok(user.clone()))
+    }
+
+    fn find_by_identifier(&self, identifier: &str) -> Result<Option<User>, ApiError> {
+        let users = self.users.lock().unwrap();
+        let user = match identifier.contains('@') {
+            true => {
+                let email = validation::normalize_email(identifier);
+                users.iter().find(|user| user.email == email)
+            }
+            false => users.iter().find(|user| user.username == identifier.trim()),
+        };
+
+        Ok(user.cloned())
+    }
+
+    fn create(&self, mut user: UserMessage) -> Result<User, ApiError> {
+        user.normalize();
+        user.validate()?;
+        check_available(
+            &self.users.lock().unwrap(),
+            &user.email,
+            &user.username,
+            None,
+        )?;
+
+        let mut user = User::from(user);
+        user.hash_password()?;
+
+        self.insert(user.clone());
+
+        Ok(user)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        mut profile: ProfileUpdate,
+        expected_version: Option<i32>,
+    ) -> Result<(User, Option<String>), ApiError> {
+        profile.normalize();
+        profile.validate()?;
+
+        let mut users = self.users.lock().unwrap();
+        check_available(&users, &profile.email, &profile.username, Some(id))?;
+        let user = users
+            .iter_mut()
+            .find(|user| user.id == id)
+            .ok_or_else(not_found)?;
+        check_version(user.data_version, expected_version)?;
+
+        let now = Utc::now().naive_utc();
+        user.name = profile.name;
+        user.username = profile.username;
+        user.updated_at = Some(now);
+        user.data_version += 1;
+
+        let mut token = None;
+        if profile.email != user.email {
+            let issued = models::generate_token();
+            user.email_verification_token = Some(models::hash_token(&issued));
+            user.email_verification_sent_at = Some(now);
+            user.pending_email = Some(profile.email);
+            token = Some(issued);
+        }
+
+        Ok((user.clone(), token))
+    }
+
+    fn verify_email(&self, id: Uuid, token: &str) -> Result<User, ApiError> {
+        let mut users = self.users.lock().unwrap();
+        let current = users
+            .iter()
+            .find(|user| user.id == id)
+            .ok_or_else(not_found)?;
+        let pending_email = current.confirmable_email(token)?;
+        check_available(&users, &pending_email, &current.username, Some(id))?;
+
+        let user = users.iter_mut().find(|user| user.id == id).unwrap();
+        user.email = pending_email;
+        user.pending_email = None;
+        user.email_verification_token = None;
+        user.email_verification_sent_at = None;
+        user.updated_at = Some(Utc::now().naive_utc());
+        user.data_version += 1;
+
+        Ok(user.clone())
+    }
+
+    fn change_password(
+        &self,
+        user: &User,
+        change: &PasswordChange,
+    ) -> Result<Option<User>, ApiError> {
+        let changed = match user.with_new_password(change)? {
+            Some(changed) => changed,
+            None => return Ok(None),
+        };
+
+        self.with_user(user.id, |user| {
+            user.password = changed.password;
+            user.updated_at = Some(Utc::now().naive_utc());
+            user.data_version += 1;
+
+            Ok(Some(user.clone()))
+        })
+    }
+
+    fn delete(&self, id: Uuid, expected_version: Option<i32>) -> Result<usize, ApiError> {
+        let mut users = self.users.lock().unwrap();
+        let index = match users.iter().position(|user| user.id == id) {
+            Some(index) => index,
+            None if expected_version.is_some() => return Err(not_found()),
+            None => return Ok(0),
+        };
+        check_version(users[index].data_version, expected_version)?;
+
+        users.remove(index);
+
+        Ok(1)
+    }
+
+    fn set_role(&self, id: Uuid, role: &str) -> Result<User, ApiError> {
+        User::validate_role(role)?;
+
+        self.with_user(id, |user| {
+            user.role = role.to_string();
+            user.updated_at = Some(Utc::now().naive_utc());
+            user.data_version += 1;
+
+            Ok(user.clone())
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySubmissionRepo {
+    submissions: Mutex<Vec<Submission>>,
+    files: Mutex<Vec<SubmissionFile>>,
+}
+
+impl MemorySubmissionRepo {
+    fn with_submission<T>(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(&mut Submission) -> Result<T, ApiError>,
+    ) -> Result<T, ApiError> {
+        let mut submissions = self.submissions.lock().unwrap();
+        let submission = submissions
+            .iter_mut()
+            .find(|submission| submission.id == id)
+            .ok_or_else(not_found)?;
+
+        f(submission)
+    }
+
+    fn replace_files(&self, id: Uuid, files: Vec<ProjectFile>) {
+        let now = Utc::now().naive_utc();
+        let mut stored = self.files.lock().unwrap();
+        stored.retain(|file| file.submission_id != id);
+        stored.extend(files.into_iter().map(|file| SubmissionFile {
+            submission_id: id,
+            path: file.path,
+            content: file.content,
+            executable: file.executable,
+            updated_at: now,
+        }));
+    }
+}
+
+impl SubmissionRepo for MemorySubmissionRepo {
+    fn find_all(&self) -> Result<Vec<Submission>, ApiError> {
+        Ok(self.submissions.lock().unwrap().clone())
+    }
+
+    fn find(&self, id: Uuid) -> Result<Submission, ApiError> {
+        self.with_submission(id, |submission| Ok(submission.clone()))
+    }
+
+    fn find_by_user(&self, user_id: Uuid) -> Result<Vec<Submission>, ApiError> {
+        Ok(self
+            .submissions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|submission| submission.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    fn create(
+        &self,
+        mut submission: SubmissionInput,
+        user_id: Uuid,
+    ) -> Result<Submission, ApiError> {
+        let files = submission.take_files();
+        let submission = SubmissionMessage::new(submission).into_submission(user_id);
+
+        self.submissions.lock().unwrap().push(submission.clone());
+        if let Some(files) = files {
+            self.replace_files(submission.id, files);
+        }
+
+        Ok(submission)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        mut submission: SubmissionInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Submission, ApiError> {
+        let files = submission.take_files();
+        let message = SubmissionMessage::new(submission);
+
+        let submission = self.with_submission(id, |submission| {
+            check_owner(submission.user_id == user_id)?;
+            check_version(submission.data_version, expected_version)?;
+
+            *submission = Submission {
+                id: submission.id,
+                created_at: submission.created_at,
+                updated_at: Some(Utc::now().naive_utc()),
+                data_version: submission.data_version + 1,
+                queued_at: submission.queued_at,
+                entrypoint: message
+                    .entrypoint
+                    .clone()
+                    .or_else(|| submission.entrypoint.clone()),
+                ..message.into_submission(user_id)
+            };
+
+            Ok(submission.clone())
+        })?;
+        if let Some(files) = files {
+            self.replace_files(id, files);
+        }
+
+        Ok(submission)
+    }
+
+    fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError> {
+        let mut submissions = self.submissions.lock().unwrap();
+        let index = match submissions
+            .iter()
+            .position(|submission| submission.id == id)
+        {
+            Some(index) => index,
+            None if expected_version.is_some() => return Err(not_found()),
+            None => return Ok(0),
+        };
+        check_owner(submissions[index].user_id == user_id)?;
+        check_version(submissions[index].data_version, expected_version)?;
+
+        submissions.remove(index);
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|file| file.submission_id != id);
+
+        Ok(1)
+    }
+
+    fn mark_queued(&self, id: Uuid) -> Result<Submission, ApiError> {
+        self.with_submission(id, |submission| {
+            let now = Utc::now().naive_utc();
+            submission.status = STATUS_QUEUED.to_string();
+            submission.queued_at = Some(now);
+            submission.updated_at = Some(now);
+            submission.data_version += 1;
+
+            Ok(submission.clone())
+        })
+    }
+
+    fn cancel(&self, id: Uuid) -> Result<Option<Submission>, ApiError> {
+        self.with_submission(id, |submission| {
+            let status = match submission.status.as_str() {
+                STATUS_QUEUED => STATUS_CANCELLED,
+                STATUS_RECEIVED | STATUS_RUNNING => STATUS_CANCELLING,
+                _ => return Ok(None),
+            };
+            submission.status = status.to_string();
+            submission.updated_at = Some(Utc::now().naive_utc());
+            submission.data_version += 1;
+
+            Ok(Some(submission.clone()))
+        })
+    }
+
+    fn count_in_flight(&self, user_id: Uuid) -> Result<i64, ApiError> {
+        Ok(self
+            .submissions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|submission| submission.user_id == user_id)
+            .filter(|submission| IN_FLIGHT_STATUSES.contains(&submission.status.as_str()))
+            .count() as i64)
+    }
+
+    fn find_files(&self, id: Uuid) -> Result<Vec<SubmissionFile>, ApiError> {
+        self.find(id)?;
+
+        let mut files: Vec<_> = self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|file| file.submission_id == id)
+            .cloned()
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(files)
+    }
+
+    fn find_file(&self, id: Uuid, path: &str) -> Result<SubmissionFile, ApiError> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|file| file.submission_id == id && file.path == path)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    fn put_file(&self, id: Uuid, path: &str, file: FileUpdate) -> Result<SubmissionFile, ApiError> {
+        self.with_submission(id, |submission| {
+            if submission.entrypoint.is_none() {
+                return Err(models::not_a_project());
+            }
+
+            let mut files = self.files.lock().unwrap();
+            let tree = files
+                .iter()
+                .filter(|file| file.submission_id == id)
+                .cloned()
+                .map(ProjectFile::from)
+                .collect();
+            let file = models::tree_with_file(tree, path, file)?
+                .pop()
+                .expect("the file was just added");
+            let file = SubmissionFile {
+                submission_id: id,
+                path: file.path,
+                content: file.content,
+                executable: file.executable,
+                updated_at: Utc::now().naive_utc(),
+            };
+            files.retain(|stored| stored.submission_id != id || stored.path != path);
+            files.push(file.clone());
+
+            submission.updated_at = Some(file.updated_at);
+            submission.data_version += 1;
+
+            Ok(file)
+        })
+    }
+
+    fn delete_file(&self, id: Uuid, path: &str) -> Result<usize, ApiError> {
+        self.with_submission(id, |submission| {
+            if submission.entrypoint.as_deref() == Some(path) {
+                return Err(models::entrypoint_in_use());
+            }
+
+            let mut files = self.files.lock().unwrap();
+            let before = files.len();
+            files.retain(|file| file.submission_id != id || file.path != path);
+            let res = before - files.len();
+            if res > 0 {
+                submission.updated_at = Some(Utc::now().naive_utc());
+                submission.data_version += 1;
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBenchmarkRepo {
+    benchmarks: Mutex<Vec<Benchmark>>,
+}
+
+impl MemoryBenchmarkRepo {
+    fn with_benchmark<T>(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(&mut Benchmark) -> Result<T, ApiError>,
+    ) -> Result<T, ApiError> {
+        let mut benchmarks = self.benchmarks.lock().unwrap();
+        let benchmark = benchmarks
+            .iter_mut()
+            .find(|benchmark| benchmark.id == id)
+            .ok_or_else(not_found)?;
+
+        f(benchmark)
+    }
+}
+
+impl BenchmarkRepo for MemoryBenchmarkRepo {
+    fn find_all(&self) -> Result<Vec<Benchmark>, ApiError> {
+        Ok(self.benchmarks.lock().unwrap().clone())
+    }
+
+    fn find(&self, id: Uuid) -> Result<Benchmark, ApiError> {
+        self.with_benchmark(id, |benchmark| Ok(benchmark.clone()))
+    }
+
+    fn create(&self, benchmark: BenchmarkInput, user_id: Uuid) -> Result<Benchmark, ApiError> {
+        let benchmark = BenchmarkMessage::new(benchmark).into_benchmark(user_id);
+
+        self.benchmarks.lock().unwrap().push(benchmark.clone());
+
+        Ok(benchmark)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        benchmark: BenchmarkInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Benchmark, ApiError> {
+        let message = BenchmarkMessage::new(benchmark);
+
+        self.with_benchmark(id, |benchmark| {
+            check_owner(benchmark.creator_id == Some(user_id))?;
+            check_version(benchmark.data_version, expected_version)?;
+
+            *benchmark = Benchmark {
+                id: benchmark.id,
+                created_at: benchmark.created_at,
+                updated_at: Some(Utc::now().naive_utc()),
+                data_version: benchmark.data_version + 1,
+                creator_id: benchmark.creator_id,
+                contest: benchmark.contest,
+                contest_opens_at: benchmark.contest_opens_at,
+                contest_closes_at: benchmark.contest_closes_at,
+                ..message.into_benchmark(user_id)
+            };
+
+            Ok(benchmark.clone())
+        })
+    }
+
+    fn set_contest(&self, id: Uuid, update: ContestUpdate) -> Result<Benchmark, ApiError> {
+        self.with_benchmark(id, |benchmark| {
+            benchmark.contest = update.contest;
+            benchmark.contest_opens_at = update.opens_at;
+            benchmark.contest_closes_at = update.closes_at;
+            benchmark.updated_at = Some(Utc::now().naive_utc());
+            benchmark.data_version += 1;
+
+            Ok(benchmark.clone())
+        })
+    }
+
+    fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError> {
+        let mut benchmarks = self.benchmarks.lock().unwrap();
+        let index = match benchmarks.iter().position(|benchmark| benchmark.id == id) {
+            Some(index) => index,
+            None if expected_version.is_some() => return Err(not_found()),
+            None => return Ok(0),
+        };
+        check_owner(benchmarks[index].creator_id == Some(user_id))?;
+        check_version(benchmarks[index].data_version, expected_version)?;
+
+        benchmarks.remove(index);
+
+        Ok(1)
+    }
+}
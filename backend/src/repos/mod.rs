@@ -0,0 +1,152 @@
+//! Storage behind the handlers. Handlers take a repository as
+//! `web::Data<dyn UserRepo>` (and so on) rather than calling the models
+//! directly, so tests can hand them the in-memory versions instead of Postgres.
+//!
+//! The methods block, so handlers call them through `db::run`.
+
+#[cfg(test)]
+pub mod memory;
+mod postgres;
+
+pub use postgres::*;
+
+use crate::api_error::ApiError;
+use crate::models::{
+    Benchmark, BenchmarkInput, ContestUpdate, FileUpdate, PasswordChange, ProfileUpdate,
+    Submission, SubmissionFile, SubmissionInput, User, UserMessage,
+};
+use actix_web::web;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub trait UserRepo: Send + Sync {
+    fn find_all(&self) -> Result<Vec<User>, ApiError>;
+
+    fn find(&self, id: Uuid) -> Result<User, ApiError>;
+
+    /// An account by email address or username, as accepted on login.
+    fn find_by_identifier(&self, identifier: &str) -> Result<Option<User>, ApiError>;
+
+    /// Validates the registration and stores the account with its password hashed.
+    fn create(&self, user: UserMessage) -> Result<User, ApiError>;
+
+    /// A changed email address is only kept as pending until it is verified,
+    /// with the token to send to it.
+    fn update(
+        &self,
+        id: Uuid,
+        profile: ProfileUpdate,
+        expected_version: Option<i32>,
+    ) -> Result<(User, Option<String>), ApiError>;
+
+    fn verify_email(&self, id: Uuid, token: &str) -> Result<User, ApiError>;
+
+    /// `None` when the current password does not match.
+    fn change_password(
+        &self,
+        user: &User,
+        change: &PasswordChange,
+    ) -> Result<Option<User>, ApiError>;
+
+    fn delete(&self, id: Uuid, expected_version: Option<i32>) -> Result<usize, ApiError>;
+
+    fn set_role(&self, id: Uuid, role: &str) -> Result<User, ApiError>;
+}
+
+pub trait SubmissionRepo: Send + Sync {
+    fn find_all(&self) -> Result<Vec<Submission>, ApiError>;
+
+    fn find(&self, id: Uuid) -> Result<Submission, ApiError>;
+
+    fn find_by_user(&self, user_id: Uuid) -> Result<Vec<Submission>, ApiError>;
+
+    fn create(&self, submission: SubmissionInput, user_id: Uuid) -> Result<Submission, ApiError>;
+
+    fn update(
+        &self,
+        id: Uuid,
+        submission: SubmissionInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Submission, ApiError>;
+
+    /// Only the submission's owner may delete it.
+    fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError>;
+
+    /// Marks a run as handed to the worker.
+    fn mark_queued(&self, id: Uuid) -> Result<Submission, ApiError>;
+
+    /// Cancels the run in progress, see `Submission::cancel`.
+    fn cancel(&self, id: Uuid) -> Result<Option<Submission>, ApiError>;
+
+    /// The user's runs that have yet to finish.
+    fn count_in_flight(&self, user_id: Uuid) -> Result<i64, ApiError>;
+
+    /// A project's files, ordered by path; none for other submissions.
+    fn find_files(&self, id: Uuid) -> Result<Vec<SubmissionFile>, ApiError>;
+
+    fn find_file(&self, id: Uuid, path: &str) -> Result<SubmissionFile, ApiError>;
+
+    /// Adds a file to a project or replaces one, see `SubmissionFile::put`.
+    fn put_file(&self, id: Uuid, path: &str, file: FileUpdate) -> Result<SubmissionFile, ApiError>;
+
+    /// Refuses to delete the entrypoint.
+    fn delete_file(&self, id: Uuid, path: &str) -> Result<usize, ApiError>;
+}
+
+pub trait BenchmarkRepo: Send + Sync {
+    fn find_all(&self) -> Result<Vec<Benchmark>, ApiError>;
+
+    fn find(&self, id: Uuid) -> Result<Benchmark, ApiError>;
+
+    fn create(&self, benchmark: BenchmarkInput, user_id: Uuid) -> Result<Benchmark, ApiError>;
+
+    /// Only the benchmark's creator may update it.
+    fn update(
+        &self,
+        id: Uuid,
+        benchmark: BenchmarkInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Benchmark, ApiError>;
+
+    fn set_contest(&self, id: Uuid, update: ContestUpdate) -> Result<Benchmark, ApiError>;
+
+    /// Only the benchmark's creator may delete it.
+    fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError>;
+}
+
+/// One of each repository, for registering with the app in one go.
+#[derive(Clone)]
+pub struct Repos {
+    pub users: Arc<dyn UserRepo>,
+    pub submissions: Arc<dyn SubmissionRepo>,
+    pub benchmarks: Arc<dyn BenchmarkRepo>,
+}
+
+impl Repos {
+    pub fn postgres() -> Self {
+        Repos {
+            users: Arc::new(PgUserRepo),
+            submissions: Arc::new(PgSubmissionRepo),
+            benchmarks: Arc::new(PgBenchmarkRepo),
+        }
+    }
+
+    /// Makes each repository available to handlers as `web::Data<dyn ...>`.
+    pub fn register(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::from(self.users.clone()))
+            .app_data(web::Data::from(self.submissions.clone()))
+            .app_data(web::Data::from(self.benchmarks.clone()));
+    }
+}
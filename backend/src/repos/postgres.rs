@@ -0,0 +1,164 @@
+use super::{BenchmarkRepo, SubmissionRepo, UserRepo};
+use crate::api_error::ApiError;
+use crate::models::{
+    Benchmark, BenchmarkInput, ContestUpdate, FileUpdate, PasswordChange, ProfileUpdate,
+    Submission, SubmissionFile, SubmissionInput, User, UserMessage,
+};
+use uuid::Uuid;
+
+/// The models already talk to Postgres, so these only pass calls through.
+pub struct PgUserRepo;
+
+impl UserRepo for PgUserRepo {
+    fn find_all(&self) -> Result<Vec<User>, ApiError> {
+        User::find_all()
+    }
+
+    fn find(&self, id: Uuid) -> Result<User, ApiError> {
+        User::find(id)
+    }
+
+    fn find_by_identifier(&self, identifier: &str) -> Result<Option<User>, ApiError> {
+        User::find_by_identifier(identifier)
+    }
+
+    fn create(&self, user: UserMessage) -> Result<User, ApiError> {
+        User::create(user)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        profile: ProfileUpdate,
+        expected_version: Option<i32>,
+    ) -> Result<(User, Option<String>), ApiError> {
+        User::update(id, profile, expected_version)
+    }
+
+    fn verify_email(&self, id: Uuid, token: &str) -> Result<User, ApiError> {
+        User::verify_email(id, token)
+    }
+
+    fn change_password(
+        &self,
+        user: &User,
+        change: &PasswordChange,
+    ) -> Result<Option<User>, ApiError> {
+        user.change_password(change)
+    }
+
+    fn delete(&self, id: Uuid, expected_version: Option<i32>) -> Result<usize, ApiError> {
+        User::delete(id, expected_version)
+    }
+
+    fn set_role(&self, id: Uuid, role: &str) -> Result<User, ApiError> {
+        User::set_role(id, role)
+    }
+}
+
+pub struct PgSubmissionRepo;
+
+impl SubmissionRepo for PgSubmissionRepo {
+    fn find_all(&self) -> Result<Vec<Submission>, ApiError> {
+        Submission::find_all()
+    }
+
+    fn find(&self, id: Uuid) -> Result<Submission, ApiError> {
+        Submission::find(id)
+    }
+
+    fn find_by_user(&self, user_id: Uuid) -> Result<Vec<Submission>, ApiError> {
+        Submission::find_user_submissions(user_id)
+    }
+
+    fn create(&self, submission: SubmissionInput, user_id: Uuid) -> Result<Submission, ApiError> {
+        Submission::create(submission, user_id)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        submission: SubmissionInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Submission, ApiError> {
+        Submission::update(id, submission, user_id, expected_version)
+    }
+
+    fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError> {
+        Submission::delete(id, user_id, expected_version)
+    }
+
+    fn mark_queued(&self, id: Uuid) -> Result<Submission, ApiError> {
+        Submission::mark_queued(id)
+    }
+
+    fn cancel(&self, id: Uuid) -> Result<Option<Submission>, ApiError> {
+        Submission::cancel(id)
+    }
+
+    fn count_in_flight(&self, user_id: Uuid) -> Result<i64, ApiError> {
+        Submission::count_in_flight(user_id)
+    }
+
+    fn find_files(&self, id: Uuid) -> Result<Vec<SubmissionFile>, ApiError> {
+        Submission::find(id)?;
+        SubmissionFile::find_by_submission(id)
+    }
+
+    fn find_file(&self, id: Uuid, path: &str) -> Result<SubmissionFile, ApiError> {
+        SubmissionFile::find(id, path)
+    }
+
+    fn put_file(&self, id: Uuid, path: &str, file: FileUpdate) -> Result<SubmissionFile, ApiError> {
+        SubmissionFile::put(id, path, file)
+    }
+
+    fn delete_file(&self, id: Uuid, path: &str) -> Result<usize, ApiError> {
+        SubmissionFile::delete(id, path)
+    }
+}
+
+pub struct PgBenchmarkRepo;
+
+impl BenchmarkRepo for PgBenchmarkRepo {
+    fn find_all(&self) -> Result<Vec<Benchmark>, ApiError> {
+        Benchmark::find_all()
+    }
+
+    fn find(&self, id: Uuid) -> Result<Benchmark, ApiError> {
+        Benchmark::find(id)
+    }
+
+    fn create(&self, benchmark: BenchmarkInput, user_id: Uuid) -> Result<Benchmark, ApiError> {
+        Benchmark::create(benchmark, user_id)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        benchmark: BenchmarkInput,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<Benchmark, ApiError> {
+        Benchmark::update(id, benchmark, user_id, expected_version)
+    }
+
+    fn set_contest(&self, id: Uuid, update: ContestUpdate) -> Result<Benchmark, ApiError> {
+        Benchmark::set_contest(id, update)
+    }
+
+    fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        expected_version: Option<i32>,
+    ) -> Result<usize, ApiError> {
+        Benchmark::delete(id, user_id, expected_version)
+    }
+}
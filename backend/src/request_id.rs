@@ -0,0 +1,139 @@
+use crate::client_ip::client_ip;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use std::rc::Rc;
+use std::time::Instant;
+use uuid::Uuid;
+
+pub const HEADER: &str = "x-request-id";
+
+// Long enough for a UUID or a load balancer's trace ID, short enough not to
+// bloat every log line
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// ID of the request being handled on this task, if any. Blocking closures run
+/// on another thread, so read it before handing work to `web::block`.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Tags an outgoing message with the current request's ID, so the worker can
+/// log it and hand it back on its status updates.
+pub fn inject_amqp(headers: &mut FieldTable) {
+    if let Some(id) = current() {
+        headers.insert(HEADER.into(), AMQPValue::LongString(LongString::from(id)));
+    }
+}
+
+/// The request ID an incoming message was tagged with, if any.
+pub fn extract_amqp(headers: Option<&FieldTable>) -> Option<String> {
+    let id = match headers?.inner().get(HEADER)? {
+        AMQPValue::LongString(value) => String::from_utf8(value.as_bytes().to_vec()).ok()?,
+        AMQPValue::ShortString(value) => value.to_string(),
+        _ => return None,
+    };
+
+    Some(id).filter(|id| is_valid(id))
+}
+
+/// Accepts only IDs that are safe to echo into headers and log lines.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Gives every request an ID, reusing the caller's `X-Request-Id` when it sent a
+/// sensible one. The ID is returned in the response header, available through
+/// `current()` while the request is handled, and logged with each request.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let remote_addr = client_ip(req.request());
+
+        // Inner middleware does some of its work in `call` itself
+        let fut = REQUEST_ID.sync_scope(id.clone(), || self.service.call(req));
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let mut res = fut.await?;
+
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(HEADER), value);
+            }
+
+            tracing::info!(
+                request_id = %id,
+                http.request.method = %method,
+                http.route = %route,
+                url.path = %path,
+                http.response.status_code = res.status().as_u16(),
+                client.address = %remote_addr,
+                duration_ms = started.elapsed().as_secs_f64() * 1000.0,
+                "{} {} {}",
+                method,
+                path,
+                res.status().as_u16(),
+            );
+
+            Ok(res)
+        }))
+    }
+}
@@ -9,6 +9,104 @@ table! {
         max_cyclomatic_complex -> Int4,
         created_at -> Timestamp,
         updated_at -> Nullable<Timestamp>,
+        data_version -> Int4,
+        contest -> Bool,
+        contest_opens_at -> Nullable<Timestamp>,
+        contest_closes_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    dead_letter (id) {
+        id -> Uuid,
+        message_id -> Text,
+        submission_id -> Nullable<Uuid>,
+        attempt -> Nullable<Int4>,
+        payload -> Text,
+        reason -> Text,
+        retries -> Int4,
+        dead_at -> Timestamp,
+    }
+}
+
+table! {
+    job_attempt (id) {
+        id -> Uuid,
+        submission_id -> Uuid,
+        attempt -> Int4,
+        status -> Text,
+        dispatched_at -> Timestamp,
+        deadline -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+        run_class -> Text,
+        priority -> Int2,
+        stdin -> Text,
+        args -> Array<Text>,
+        env -> Array<Text>,
+    }
+}
+
+table! {
+    login_attempt (id) {
+        id -> Uuid,
+        identifier -> Text,
+        user_id -> Nullable<Uuid>,
+        ip_address -> Text,
+        outcome -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    oauth_state (state) {
+        state -> Text,
+        provider -> Text,
+        code_verifier -> Text,
+        redirect_uri -> Text,
+        user_id -> Nullable<Uuid>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    recovery_code (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    run_usage (user_id, day) {
+        user_id -> Uuid,
+        day -> Date,
+        run_count -> Int4,
+        cpu_ms -> Int8,
+    }
+}
+
+table! {
+    scratch_run (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        language -> Text,
+        code -> Text,
+        stdin -> Text,
+        status -> Text,
+        stdout -> Nullable<Text>,
+        stderr -> Nullable<Text>,
+        message -> Nullable<Text>,
+        error -> Nullable<Text>,
+        exec_duration -> Int4,
+        mem_usage -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        deadline -> Timestamp,
+        expires_at -> Timestamp,
+        args -> Array<Text>,
+        env -> Array<Text>,
     }
 }
 
@@ -32,6 +130,31 @@ table! {
         mem_usage -> Int4,
         code_hash -> Nullable<Text>,
         cyclomatic_complexity -> Int4,
+        data_version -> Int4,
+        queued_at -> Nullable<Timestamp>,
+        stdin -> Text,
+        args -> Array<Text>,
+        env -> Array<Text>,
+        entrypoint -> Nullable<Text>,
+    }
+}
+
+table! {
+    submission_file (submission_id, path) {
+        submission_id -> Uuid,
+        path -> Text,
+        content -> Text,
+        executable -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    two_factor_challenge (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        failed_attempts -> Int4,
+        created_at -> Timestamp,
     }
 }
 
@@ -45,11 +168,48 @@ table! {
         data_version -> Int4,
         created_at -> Timestamp,
         updated_at -> Nullable<Timestamp>,
+        pending_email -> Nullable<Text>,
+        email_verification_token -> Nullable<Text>,
+        email_verification_sent_at -> Nullable<Timestamp>,
+        role -> Text,
+        totp_secret -> Nullable<Text>,
+        totp_enabled -> Bool,
+        totp_last_used_step -> Nullable<Int8>,
     }
 }
 
+table! {
+    user_identity (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        provider -> Text,
+        subject -> Text,
+        email -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+joinable!(job_attempt -> submission (submission_id));
+joinable!(oauth_state -> user (user_id));
+joinable!(recovery_code -> user (user_id));
+joinable!(run_usage -> user (user_id));
+joinable!(scratch_run -> user (user_id));
+joinable!(submission_file -> submission (submission_id));
+joinable!(two_factor_challenge -> user (user_id));
+joinable!(user_identity -> user (user_id));
+
 allow_tables_to_appear_in_same_query!(
     benchmark,
+    dead_letter,
+    job_attempt,
+    login_attempt,
+    oauth_state,
+    recovery_code,
+    run_usage,
+    scratch_run,
     submission,
+    submission_file,
+    two_factor_challenge,
     user,
+    user_identity,
 );
@@ -0,0 +1,223 @@
+use crate::config::{LogFormat, TelemetryConfig};
+use crate::request_id;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderMap;
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use std::rc::Rc;
+use tracing::field::Empty;
+use tracing::{Instrument, Level, Span};
+use tracing_log::LogTracer;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+
+/// Installs the process-wide subscriber: log lines go to stdout, filtered by
+/// `RUST_LOG` (default `info`), and spans are also exported to the configured
+/// OTLP collector. Returns the tracer provider so that `main` can flush it on
+/// shutdown, or `None` when exporting is off.
+pub fn init(config: &TelemetryConfig) -> Option<TracerProvider> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    // Dependencies (and most of this crate) still use the `log` macros
+    if let Err(e) = LogTracer::init() {
+        eprintln!("Failed to forward log records to tracing: {}", e);
+    }
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let output = match config.log_format {
+        // The innermost span carries the request ID, so every line logged while
+        // handling a request can be tied back to it
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+    };
+
+    let exporter = config
+        .otlp_endpoint
+        .as_ref()
+        .map(|endpoint| {
+            SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()
+        })
+        .transpose();
+    let (exporter, exporter_error) = match exporter {
+        Ok(exporter) => (exporter, None),
+        Err(e) => (None, Some(e)),
+    };
+    let provider = exporter.map(|exporter| {
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )]))
+            .build()
+    });
+
+    // Only our own spans: the exporter's HTTP/2 stack is instrumented too, and
+    // tracing it would feed every export back into the next one.
+    let export = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("dune-backend"))
+            .with_filter(Targets::new().with_target("backend", Level::INFO))
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(export);
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("Failed to install the tracing subscriber: {}", e);
+        return None;
+    }
+
+    if let Some(e) = exporter_error {
+        error!("Failed to set up the OTLP exporter, tracing is off: {}", e);
+    } else if let (Some(endpoint), Some(_)) = (&config.otlp_endpoint, &provider) {
+        info!("Exporting traces to {}", endpoint);
+    }
+
+    provider
+}
+
+/// Adds the current trace context to an outgoing message's headers.
+pub fn inject_amqp(span: &Span, headers: &mut FieldTable) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut AmqpHeaders(headers))
+    });
+}
+
+/// The trace context an incoming message was published under, if any.
+pub fn extract_amqp(headers: Option<&FieldTable>) -> Context {
+    let empty = FieldTable::default();
+    let headers = headers.unwrap_or(&empty);
+
+    global::get_text_map_propagator(|propagator| propagator.extract(&AmqpHeaderReader(headers)))
+}
+
+struct AmqpHeaders<'a>(&'a mut FieldTable);
+
+impl Injector for AmqpHeaders<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0
+            .insert(key.into(), AMQPValue::LongString(LongString::from(value)));
+    }
+}
+
+struct AmqpHeaderReader<'a>(&'a FieldTable);
+
+impl Extractor for AmqpHeaderReader<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0.inner().get(key)? {
+            AMQPValue::LongString(value) => std::str::from_utf8(value.as_bytes()).ok(),
+            AMQPValue::ShortString(value) => Some(value.as_str()),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.inner().keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Opens a server span for every request, continuing the caller's trace when it
+/// sent a `traceparent` header.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let span = tracing::info_span!(
+            "http.request",
+            otel.name = %format!("{} {}", req.method(), route),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.request.method = %req.method(),
+            http.route = %route,
+            url.path = %req.path(),
+            http.response.status_code = Empty,
+            request_id = %request_id::current().unwrap_or_default(),
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&RequestHeaders(req.headers()))
+        });
+        span.set_parent(parent);
+
+        let fut = span.in_scope(|| self.service.call(req));
+        Box::pin(
+            async move {
+                let res = fut.await?;
+                let span = Span::current();
+                span.record("http.response.status_code", res.status().as_u16());
+                if res.status().is_server_error() {
+                    span.record("otel.status_code", "ERROR");
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
@@ -0,0 +1,89 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// RFC 6238 defaults, which is what authenticator apps assume when the URI is silent
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_BYTES: usize = 20;
+// Accept the neighbouring steps to tolerate clock drift between phone and server
+const ALLOWED_SKEW_STEPS: i64 = 1;
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> String {
+    let secret: [u8; SECRET_BYTES] = rand::thread_rng().gen();
+    base32::encode(ALPHABET, &secret)
+}
+
+/// The `otpauth://` URI authenticator apps import, usually rendered as a QR code.
+pub fn provisioning_uri(secret: &str, account: &str, issuer: &str) -> String {
+    let query = serde_urlencoded::to_string([
+        ("secret", secret),
+        ("issuer", issuer),
+        ("algorithm", "SHA1"),
+        ("digits", &DIGITS.to_string()),
+        ("period", &STEP_SECONDS.to_string()),
+    ])
+    .unwrap_or_default();
+
+    format!(
+        "otpauth://totp/{}:{}?{}",
+        percent_encode(issuer),
+        percent_encode(account),
+        query
+    )
+}
+
+/// Checks `code` against the steps around now and returns the matching step.
+/// Steps at or before `last_used_step` are rejected so a code cannot be replayed.
+pub fn verify(secret: &str, code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let key = base32::decode(ALPHABET, secret)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    let current_step = now / STEP_SECONDS as i64;
+
+    (-ALLOWED_SKEW_STEPS..=ALLOWED_SKEW_STEPS)
+        .map(|offset| current_step + offset)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| {
+            format!(
+                "{:0width$}",
+                hotp(&key, *step as u64),
+                width = DIGITS as usize
+            ) == code
+        })
+}
+
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    binary % 10u32.pow(DIGITS)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
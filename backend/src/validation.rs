@@ -0,0 +1,230 @@
+use crate::api_error::ApiError;
+use std::collections::BTreeMap;
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+pub const PASSWORD_MIN_LENGTH: usize = 10;
+// argon2 cost grows with the input, so cap what we are willing to hash
+pub const PASSWORD_MAX_LENGTH: usize = 128;
+pub const NAME_MAX_LENGTH: usize = 100;
+pub const EMAIL_MAX_LENGTH: usize = 254;
+
+/// Field-level validation failures, keyed by the name of the offending field.
+#[derive(Debug, Default)]
+pub struct ValidationErrors {
+    errors: BTreeMap<String, Vec<String>>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        ValidationErrors::default()
+    }
+
+    pub fn add(&mut self, field: &str, message: &str) {
+        self.errors
+            .entry(field.to_string())
+            .or_default()
+            .push(message.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), ApiError> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(ApiError::validation(self.errors)),
+        }
+    }
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub fn validate_email(errors: &mut ValidationErrors, field: &str, email: &str) {
+    if email.is_empty() {
+        errors.add(field, "is required");
+        return;
+    }
+    if email.len() > EMAIL_MAX_LENGTH {
+        errors.add(field, "is too long");
+        return;
+    }
+
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+                && domain.contains('.')
+                && domain.split('.').all(|label| {
+                    !label.is_empty() && !label.starts_with('-') && !label.ends_with('-')
+                })
+        }
+        None => false,
+    };
+
+    if !valid {
+        errors.add(field, "is not a valid email address");
+    }
+}
+
+pub fn validate_username(errors: &mut ValidationErrors, field: &str, username: &str) {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        errors.add(
+            field,
+            &format!(
+                "must be between {} and {} characters long",
+                USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+            ),
+        );
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        errors.add(
+            field,
+            "may only contain letters, digits, underscores, hyphens and dots",
+        );
+    }
+}
+
+pub fn validate_name(errors: &mut ValidationErrors, field: &str, name: &str) {
+    if name.trim().is_empty() {
+        errors.add(field, "is required");
+    } else if name.chars().count() > NAME_MAX_LENGTH {
+        errors.add(field, "is too long");
+    }
+}
+
+/// Password policy: a minimum length, a cap on what we hash, a mix of letters and
+/// digits, and no reuse of the account's own identifiers.
+pub fn validate_password(
+    errors: &mut ValidationErrors,
+    field: &str,
+    password: &str,
+    identifiers: &[&str],
+) {
+    let length = password.chars().count();
+    if length < PASSWORD_MIN_LENGTH {
+        errors.add(
+            field,
+            &format!("must be at least {} characters long", PASSWORD_MIN_LENGTH),
+        );
+    }
+    if length > PASSWORD_MAX_LENGTH {
+        errors.add(
+            field,
+            &format!("must be at most {} characters long", PASSWORD_MAX_LENGTH),
+        );
+    }
+    if !password.chars().any(|c| c.is_alphabetic()) || !password.chars().any(|c| c.is_numeric()) {
+        errors.add(field, "must contain both letters and digits");
+    }
+
+    let lowered = password.to_lowercase();
+    if identifiers
+        .iter()
+        .any(|identifier| !identifier.is_empty() && lowered == identifier.to_lowercase())
+    {
+        errors.add(field, "must not match your email or username");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(validate: impl Fn(&mut ValidationErrors)) -> bool {
+        let mut errors = ValidationErrors::new();
+        validate(&mut errors);
+        errors.is_empty()
+    }
+
+    #[test]
+    fn emails_are_checked() {
+        let long = format!("{}@example.com", "a".repeat(EMAIL_MAX_LENGTH));
+        for invalid in [
+            "",
+            "ada",
+            "@example.com",
+            "ada@",
+            "ada@example",
+            "ada@@example.com",
+            "ada@example..com",
+            "ada@-example.com",
+            "ada@example-.com",
+            "ada lovelace@example.com",
+            "ada@example.com\n",
+            long.as_str(),
+        ] {
+            assert!(
+                !check(|errors| validate_email(errors, "email", invalid)),
+                "{:?}",
+                invalid
+            );
+        }
+
+        for valid in ["ada@example.com", "ada.l+tag@mail.example.co.uk"] {
+            assert!(check(|errors| validate_email(errors, "email", valid)));
+        }
+        assert_eq!(normalize_email("  Ada@Example.COM "), "ada@example.com");
+    }
+
+    #[test]
+    fn usernames_are_checked() {
+        let long = "a".repeat(USERNAME_MAX_LENGTH + 1);
+        for invalid in ["", "ab", "ada lovelace", "ada/..", "ädä", long.as_str()] {
+            assert!(
+                !check(|errors| validate_username(errors, "username", invalid)),
+                "{:?}",
+                invalid
+            );
+        }
+
+        let longest = "a".repeat(USERNAME_MAX_LENGTH);
+        for valid in ["ada", "ada_l-1.0", longest.as_str()] {
+            assert!(check(|errors| validate_username(errors, "username", valid)));
+        }
+    }
+
+    #[test]
+    fn passwords_are_checked() {
+        let identifiers = ["ada@example.com", "ada2024xyz"];
+        let long = format!("1{}", "a".repeat(PASSWORD_MAX_LENGTH));
+        for invalid in [
+            "short1",
+            "onlyletters",
+            "1234567890",
+            "ADA2024XYZ",
+            long.as_str(),
+        ] {
+            assert!(
+                !check(|errors| validate_password(errors, "password", invalid, &identifiers)),
+                "{:?}",
+                invalid
+            );
+        }
+
+        let longest = format!("1{}", "a".repeat(PASSWORD_MAX_LENGTH - 1));
+        for valid in ["correct1horse", "pässwörd12", longest.as_str()] {
+            assert!(check(|errors| validate_password(
+                errors,
+                "password",
+                valid,
+                &identifiers
+            )));
+        }
+        // An empty identifier, e.g. an account without an email, matches nothing
+        assert!(check(|errors| validate_password(
+            errors,
+            "password",
+            "correct1horse",
+            &[""]
+        )));
+    }
+}
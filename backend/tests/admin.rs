@@ -0,0 +1,158 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use backend::jobs;
+use backend::models::DeadLetter;
+use chrono::Utc;
+use common::{promote, register, session, unique};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+fn parked(submission_id: Uuid, attempt: i32) -> DeadLetter {
+    DeadLetter {
+        id: Uuid::new_v4(),
+        message_id: format!("{}.{}", submission_id, attempt),
+        submission_id: Some(submission_id),
+        attempt: Some(attempt),
+        payload: "{}".to_string(),
+        reason: "rejected".to_string(),
+        retries: 3,
+        dead_at: Utc::now().naive_utc(),
+    }
+}
+
+#[actix_web::test]
+async fn dead_letters_can_be_listed_and_discarded() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let res = test::call_service(&app, register(&unique("leo")).to_request()).await;
+    let cookie = session(&res);
+    let admin: Value = test::read_body_json(res).await;
+
+    let req = TestRequest::get()
+        .uri("/api/admin/dead-letters/")
+        .cookie(cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    promote(admin["id"].as_str().unwrap());
+    let letter = parked(Uuid::new_v4(), 1);
+    let id = letter.id;
+    DeadLetter::record(letter).unwrap();
+
+    let req = TestRequest::get()
+        .uri("/api/admin/dead-letters/")
+        .cookie(cookie.clone())
+        .to_request();
+    let letters: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(letters
+        .iter()
+        .any(|letter| letter["id"] == id.to_string() && letter["reason"] == "rejected"));
+
+    // Its run never started, so there is nothing to requeue it for
+    let req = TestRequest::post()
+        .uri(&format!("/api/admin/dead-letters/{}/requeue/", id))
+        .cookie(cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let req = TestRequest::delete()
+        .uri(&format!("/api/admin/dead-letters/{}/", id))
+        .cookie(cookie)
+        .to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(deleted["deleted"], 1);
+}
+
+#[actix_web::test]
+async fn dead_letters_are_requeued_as_a_new_attempt() {
+    let Some(harness) = common::setup_with(|config| {
+        config.jobs.timeout_seconds = 0;
+        config.jobs.max_attempts = 1;
+    }) else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let res = test::call_service(&app, register(&unique("mia")).to_request()).await;
+    let cookie = session(&res);
+    let admin: Value = test::read_body_json(res).await;
+    promote(admin["id"].as_str().unwrap());
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(json!({
+            "language": "python",
+            "code": "print(4)",
+            "status": "draft",
+            "benchmark_id": null,
+            "stdout": null,
+            "stderr": null,
+            "exec_duration": 0,
+            "message": null,
+            "error": null,
+            "lint_score": null,
+            "quality_score": null,
+            "mem_usage": 0,
+            "cyclomatic_complexity": 0,
+        }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let submission_id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", submission_id))
+        .cookie(cookie.clone())
+        .to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(harness.jobs.take().unwrap().attempt, 1);
+
+    // Parked, then given up on by the reaper before anyone got to it
+    let letter = parked(submission_id, 1);
+    let id = letter.id;
+    DeadLetter::record(letter).unwrap();
+    jobs::reap(harness.jobs.as_ref(), &harness.state.config.jobs)
+        .await
+        .unwrap();
+    let uri = format!("/api/submissions/{}/", submission_id);
+    let timed_out: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(timed_out["status"], "timed_out");
+
+    let requeue = || {
+        TestRequest::post()
+            .uri(&format!("/api/admin/dead-letters/{}/requeue/", id))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let res = test::call_service(&app, requeue()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let job = harness
+        .jobs
+        .take()
+        .expect("the job was not dispatched again");
+    assert_eq!(job.id, submission_id);
+    assert_eq!(job.attempt, 2);
+    let queued: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(queued["status"], "queued");
+
+    // The letter is gone, and a stale one for the old attempt is refused
+    let res = test::call_service(&app, requeue()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let stale = parked(submission_id, 1);
+    let stale_id = stale.id;
+    DeadLetter::record(stale).unwrap();
+    let req = TestRequest::post()
+        .uri(&format!("/api/admin/dead-letters/{}/requeue/", stale_id))
+        .cookie(cookie)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+}
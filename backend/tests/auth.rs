@@ -0,0 +1,209 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use common::{login, register, session, unique, PASSWORD};
+use serde_json::{json, Value};
+use std::net::{Ipv4Addr, SocketAddr};
+use uuid::Uuid;
+
+/// An address of its own, so failures left by earlier runs do not count.
+fn unique_peer() -> SocketAddr {
+    let [_, a, b, c] = Uuid::new_v4().as_bytes()[..4] else {
+        unreachable!();
+    };
+    SocketAddr::new(Ipv4Addr::new(10, a, b, c).into(), 4000)
+}
+
+#[actix_web::test]
+async fn registered_users_can_sign_in() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let username = unique("alice");
+
+    let res = test::call_service(&app, register(&username).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let registered: Value = test::read_body_json(res).await;
+
+    for identifier in [username.clone(), format!("{}@EXAMPLE.com", username)] {
+        let res = test::call_service(&app, login(&identifier, PASSWORD).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = session(&res);
+        let signed_in: Value = test::read_body_json(res).await;
+        assert_eq!(signed_in["id"], registered["id"]);
+
+        let req = TestRequest::get()
+            .uri("/api/user/submissions/")
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
+
+#[actix_web::test]
+async fn wrong_password_is_rejected() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let username = unique("bob");
+    test::call_service(&app, register(&username).to_request()).await;
+
+    let res = test::call_service(&app, login(&username, "wrong password 1").to_request()).await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(res
+        .headers()
+        .get("content-type")
+        .is_some_and(|value| value == "application/problem+json"));
+    let problem: Value = test::read_body_json(res).await;
+    assert_eq!(problem["code"], "invalid_credentials");
+}
+
+#[actix_web::test]
+async fn parallel_wrong_passwords_are_throttled_like_sequential_ones() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let username = unique("lea");
+    test::call_service(&app, register(&username).to_request()).await;
+    let peer = unique_peer();
+
+    let attempts = (0..8).map(|i| {
+        let req = login(&username, &format!("wrong password {}", i)).peer_addr(peer);
+        test::call_service(&app, req.to_request())
+    });
+    let statuses: Vec<StatusCode> = futures::future::join_all(attempts)
+        .await
+        .iter()
+        .map(|res| res.status())
+        .collect();
+
+    // Only as many get to try a password as would one after another, before the
+    // account backs off
+    let count = |status| statuses.iter().filter(|&&s| s == status).count();
+    assert_eq!(count(StatusCode::UNAUTHORIZED), 3, "{:?}", statuses);
+    assert_eq!(count(StatusCode::TOO_MANY_REQUESTS), 5, "{:?}", statuses);
+}
+
+#[actix_web::test]
+async fn usernames_are_unique() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let username = unique("carol");
+    test::call_service(&app, register(&username).to_request()).await;
+
+    let res = test::call_service(&app, register(&username).to_request()).await;
+
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let problem: Value = test::read_body_json(res).await;
+    assert_eq!(problem["errors"]["username"][0], "is already taken");
+    assert_eq!(problem["errors"]["email"][0], "is already registered");
+}
+
+#[actix_web::test]
+async fn deleted_accounts_cannot_sign_in() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let username = unique("dave");
+    let cookie = session(&test::call_service(&app, register(&username).to_request()).await);
+
+    let req = TestRequest::delete()
+        .uri("/api/users/")
+        .cookie(cookie)
+        .to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(deleted["deleted"], 1);
+
+    let res = test::call_service(&app, login(&username, PASSWORD).to_request()).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn email_changes_are_confirmed_with_the_mailed_token() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let username = unique("erin");
+    let cookie = session(&test::call_service(&app, register(&username).to_request()).await);
+    let new_email = format!("{}@new.example.com", username);
+
+    let req = TestRequest::put()
+        .uri("/api/users/")
+        .cookie(cookie.clone())
+        .set_json(json!({
+            "email": new_email,
+            "name": username,
+            "username": username,
+        }))
+        .to_request();
+    let pending: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(pending["pending_email"], new_email.as_str());
+
+    let mail = harness.mailer.take().expect("no verification was sent");
+    assert_eq!(mail.to, new_email);
+    let token = mail.text.trim().lines().last().unwrap().trim().to_string();
+
+    let verify = |token: &str| {
+        TestRequest::post()
+            .uri("/api/users/email/verify/")
+            .cookie(cookie.clone())
+            .set_json(json!({ "token": token }))
+            .to_request()
+    };
+    let res = test::call_service(&app, verify("not the token")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = test::call_service(&app, verify(&token)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let user: Value = test::read_body_json(res).await;
+    assert_eq!(user["email"], new_email.as_str());
+    assert!(user["pending_email"].is_null());
+
+    let res = test::call_service(&app, login(&new_email, PASSWORD).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn two_factor_codes_are_throttled_like_logins() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("kim")).to_request()).await);
+    let peer = unique_peer();
+
+    let req = TestRequest::post()
+        .uri("/api/users/2fa/enroll/")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let confirm = || {
+        TestRequest::post()
+            .uri("/api/users/2fa/confirm/")
+            .cookie(cookie.clone())
+            .peer_addr(peer)
+            .set_json(json!({ "code": "not-a-code" }))
+            .to_request()
+    };
+    let mut statuses = Vec::new();
+    while statuses.len() < 6 && statuses.last() != Some(&StatusCode::TOO_MANY_REQUESTS) {
+        statuses.push(test::call_service(&app, confirm()).await.status());
+    }
+
+    // The account backs off after three failures
+    assert_eq!(statuses[..3], [StatusCode::UNAUTHORIZED; 3]);
+    assert_eq!(statuses.last(), Some(&StatusCode::TOO_MANY_REQUESTS));
+    let res = test::call_service(&app, confirm()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key("retry-after"));
+}
@@ -0,0 +1,155 @@
+//! Harness for the integration tests: the real app and Postgres, with the job
+//! bus kept in memory in place of RabbitMQ, and mail in place of a relay.
+//!
+//! Set `TEST_DATABASE_URL` to a server the tests may create databases on, e.g.
+//! `postgres://postgres@localhost/postgres`; without it every test is skipped,
+//! with a warning, except under CI (`CI` set), where every test fails instead.
+//! Each test binary migrates a database of its own, so tests within a binary
+//! share it and keep out of each other's way with unique account names.
+
+#![allow(dead_code)]
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::Cookie;
+use actix_web::dev::ServiceResponse;
+use actix_web::test::TestRequest;
+use backend::config::{Config, DatabaseConfig};
+use backend::jobs::MemoryJobBus;
+use backend::mailer::MemoryMailer;
+use backend::{db, AppState};
+use diesel::connection::SimpleConnection;
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::{self, Text};
+use diesel::PgConnection;
+use serde_json::json;
+use std::env;
+use std::sync::{Arc, OnceLock};
+use url::Url;
+use uuid::Uuid;
+
+pub const PASSWORD: &str = "correct horse 42";
+
+const DATABASE_PREFIX: &str = "dune_test_";
+
+static DATABASE: OnceLock<Option<String>> = OnceLock::new();
+
+pub struct TestApp {
+    pub state: AppState,
+    pub jobs: Arc<MemoryJobBus>,
+    pub mailer: Arc<MemoryMailer>,
+}
+
+/// A fresh app on the test database, or `None` when there is no database to
+/// test against.
+pub fn setup() -> Option<TestApp> {
+    setup_with(|_| {})
+}
+
+/// Like `setup`, with the configuration adjusted first.
+pub fn setup_with(configure: impl FnOnce(&mut Config)) -> Option<TestApp> {
+    let url = DATABASE.get_or_init(create_database).clone()?;
+
+    let mut config = Config::default();
+    config.server.secret_token = "integration-test-secret-0123456789abcdef".to_string();
+    config.database.url = url;
+    configure(&mut config);
+
+    let jobs = Arc::new(MemoryJobBus::default());
+    let mailer = Arc::new(MemoryMailer::default());
+    let state = AppState::new(config, jobs.clone(), mailer.clone());
+
+    Some(TestApp {
+        state,
+        jobs,
+        mailer,
+    })
+}
+
+fn create_database() -> Option<String> {
+    let server_url = match env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        // A CI run that skipped them all would still look green
+        Err(_) if env::var_os("CI").is_some_and(|ci| !ci.is_empty()) => {
+            panic!("TEST_DATABASE_URL must be set to run the integration tests under CI")
+        }
+        Err(_) => {
+            eprintln!("warning: TEST_DATABASE_URL is not set, skipping integration tests");
+            return None;
+        }
+    };
+
+    let conn =
+        PgConnection::establish(&server_url).expect("Failed to connect to TEST_DATABASE_URL");
+    drop_stale_databases(&conn);
+
+    let name = format!("{}{}", DATABASE_PREFIX, Uuid::new_v4().to_simple());
+    conn.batch_execute(&format!("CREATE DATABASE {}", name))
+        .expect("Failed to create test database");
+
+    let mut url = Url::parse(&server_url).expect("TEST_DATABASE_URL is not a URL");
+    url.set_path(&name);
+
+    db::init(&DatabaseConfig {
+        url: url.to_string(),
+        max_connections: 4,
+        ..DatabaseConfig::default()
+    });
+
+    Some(url.to_string())
+}
+
+/// Databases left behind by earlier runs. Ones still in use, e.g. by a run in
+/// progress elsewhere, refuse to be dropped and are left alone.
+fn drop_stale_databases(conn: &PgConnection) {
+    let stale = sql::<Text>(&format!(
+        "SELECT datname FROM pg_database WHERE datname LIKE '{}%'",
+        DATABASE_PREFIX
+    ))
+    .load::<String>(conn)
+    .unwrap_or_default();
+
+    for name in stale {
+        let _ = conn.batch_execute(&format!("DROP DATABASE {}", name));
+    }
+}
+
+/// Makes the user an administrator with two-factor authentication on, which
+/// the API has no way to do for a fresh account.
+pub fn promote(id: &str) {
+    let conn = db::connection().unwrap();
+    diesel::sql_query("UPDATE \"user\" SET role = 'admin', totp_enabled = true WHERE id = $1")
+        .bind::<sql_types::Uuid, _>(id.parse::<Uuid>().unwrap())
+        .execute(&conn)
+        .unwrap();
+}
+
+/// A name no other test uses, within the username length limit.
+pub fn unique(name: &str) -> String {
+    format!("{}_{}", name, &Uuid::new_v4().to_simple().to_string()[..8])
+}
+
+pub fn register(username: &str) -> TestRequest {
+    TestRequest::post().uri("/api/register/").set_json(json!({
+        "email": format!("{}@example.com", username),
+        "password": PASSWORD,
+        "name": username,
+        "username": username,
+    }))
+}
+
+pub fn login(identifier: &str, password: &str) -> TestRequest {
+    TestRequest::post().uri("/api/login/").set_json(json!({
+        "identifier": identifier,
+        "password": password,
+    }))
+}
+
+/// The session cookie a sign-in response set.
+pub fn session<B: MessageBody>(res: &ServiceResponse<B>) -> Cookie<'static> {
+    res.response()
+        .cookies()
+        .find(|cookie| cookie.name() == "auth")
+        .expect("response did not set a session")
+        .into_owned()
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{self, TestRequest};
+use common::{register, session, unique};
+use serde_json::{json, Value};
+
+fn benchmark(title: &str) -> Value {
+    json!({
+        "title": title,
+        "subject": "Sort a list of integers",
+        "difficulty": "easy",
+        "git_url": null,
+        "max_cyclomatic_complex": 10,
+    })
+}
+
+fn submission(code: &str, benchmark_id: Option<&str>) -> Value {
+    json!({
+        "language": "python",
+        "code": code,
+        "status": "draft",
+        "benchmark_id": benchmark_id,
+        "stdout": null,
+        "stderr": null,
+        "exec_duration": 0,
+        "message": null,
+        "error": null,
+        "lint_score": null,
+        "quality_score": null,
+        "mem_usage": 0,
+        "cyclomatic_complexity": 0,
+    })
+}
+
+#[actix_web::test]
+async fn benchmarks_are_versioned() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("erin")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/benchmarks/")
+        .cookie(cookie.clone())
+        .set_json(benchmark("Sorting"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/benchmarks/{}/", created["id"].as_str().unwrap());
+
+    let update = |title: &str, version: &str| {
+        TestRequest::put()
+            .uri(&uri)
+            .cookie(cookie.clone())
+            .insert_header((header::IF_MATCH, format!("\"{}\"", version)))
+            .set_json(benchmark(title))
+            .to_request()
+    };
+    let res = test::call_service(&app, update("Sorting, faster", "1")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"2\"");
+
+    let res = test::call_service(&app, update("Sorting, slower", "1")).await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+    let other = session(&test::call_service(&app, register(&unique("mallory")).to_request()).await);
+    let req = TestRequest::put()
+        .uri(&uri)
+        .cookie(other)
+        .insert_header((header::IF_MATCH, "\"2\""))
+        .set_json(benchmark("Sorting, mine now"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let fetched: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(fetched["title"], "Sorting, faster");
+    assert_eq!(fetched["creator_id"], created["creator_id"]);
+
+    let req = TestRequest::delete()
+        .uri(&uri)
+        .cookie(cookie)
+        .insert_header((header::IF_MATCH, "\"2\""))
+        .to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(deleted["deleted"], 1);
+
+    let res = test::call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn submissions_belong_to_their_author() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("frank")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/benchmarks/")
+        .cookie(cookie.clone())
+        .set_json(benchmark("Reversing"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let benchmark_id = created["id"].as_str().unwrap();
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("print(1)", Some(benchmark_id)))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["benchmark_id"], benchmark_id);
+    assert_eq!(created["data_version"], 1);
+
+    let req = TestRequest::get()
+        .uri("/api/user/submissions/")
+        .cookie(cookie)
+        .to_request();
+    let own: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(own.len(), 1);
+    assert_eq!(own[0]["id"], created["id"]);
+}
+
+#[actix_web::test]
+async fn stale_submission_edits_are_refused() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("grace")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("print(1)", None))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/submissions/{}/", created["id"].as_str().unwrap());
+
+    let req = TestRequest::put()
+        .uri(&uri)
+        .cookie(cookie.clone())
+        .insert_header((header::IF_MATCH, "\"1\""))
+        .set_json(submission("print(2)", None))
+        .to_request();
+    let updated: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(updated["code"], "print(2)");
+    assert_eq!(updated["data_version"], 2);
+
+    let req = TestRequest::delete()
+        .uri(&uri)
+        .cookie(cookie.clone())
+        .insert_header((header::IF_MATCH, "\"1\""))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+    let req = TestRequest::delete().uri(&uri).cookie(cookie).to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(deleted["deleted"], 1);
+
+    let res = test::call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn only_the_author_changes_a_submission() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let owner = session(&test::call_service(&app, register(&unique("heidi")).to_request()).await);
+    let other = session(&test::call_service(&app, register(&unique("ivan")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(owner.clone())
+        .set_json(submission("print(1)", None))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/submissions/{}/", created["id"].as_str().unwrap());
+
+    let req = TestRequest::put()
+        .uri(&uri)
+        .cookie(other.clone())
+        .insert_header((header::IF_MATCH, "\"1\""))
+        .set_json(submission("print(2)", None))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = test::call_service(&app, TestRequest::delete().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let req = TestRequest::delete().uri(&uri).cookie(other).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let req = TestRequest::get().uri(&uri).cookie(owner).to_request();
+    let fetched: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(fetched["code"], "print(1)");
+    assert_eq!(fetched["user_id"], created["user_id"]);
+    assert_eq!(fetched["data_version"], 1);
+}
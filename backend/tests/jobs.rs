@@ -0,0 +1,612 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use backend::jobs;
+use backend::models::JobStatus;
+use chrono::{Duration, Utc};
+use common::{promote, register, session, unique};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+fn submission(code: &str) -> Value {
+    json!({
+        "language": "python",
+        "code": code,
+        "status": "draft",
+        "benchmark_id": null,
+        "stdout": null,
+        "stderr": null,
+        "exec_duration": 0,
+        "message": null,
+        "error": null,
+        "lint_score": null,
+        "quality_score": null,
+        "mem_usage": 0,
+        "cyclomatic_complexity": 0,
+    })
+}
+
+#[actix_web::test]
+async fn runs_are_dispatched_and_report_back() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("heidi")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("print('hello')"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let uri = format!("/api/submissions/{}/", id);
+
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", id))
+        .cookie(cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("x-quota-runs-remaining").unwrap(), "199");
+    let queued: Value = test::read_body_json(res).await;
+    assert_eq!(queued["status"], "queued");
+
+    let job = harness.jobs.take().expect("no job was dispatched");
+    assert_eq!(job.id, id);
+    assert_eq!(job.code, "print('hello')");
+    assert!(harness.jobs.take().is_none());
+
+    harness
+        .jobs
+        .report(JobStatus::new(id, "running"))
+        .await
+        .unwrap();
+    let running: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(running["status"], "running");
+
+    harness
+        .jobs
+        .report(JobStatus {
+            stdout: "hello\n".to_string(),
+            exec_duration: 2500,
+            ..JobStatus::new(id, "done")
+        })
+        .await
+        .unwrap();
+    // A redelivered update for a finished run changes nothing
+    harness
+        .jobs
+        .report(JobStatus::new(id, "running"))
+        .await
+        .unwrap();
+
+    let done: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(done["status"], "done");
+    assert_eq!(done["stdout"], "hello\n");
+    assert_eq!(done["exec_duration"], 2500);
+
+    let req = TestRequest::get()
+        .uri("/api/user/quota/")
+        .cookie(cookie)
+        .to_request();
+    let quota: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(quota["runs_remaining"], 199);
+    assert!(
+        quota["cpu_seconds_remaining"].as_i64().unwrap()
+            < quota["cpu_seconds_limit"].as_i64().unwrap()
+    );
+
+    let attempts_uri = format!("/api/submissions/{}/attempts/", id);
+    let attempts: Vec<Value> =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&attempts_uri).to_request())
+            .await;
+    assert_eq!(attempts.len(), 1);
+    assert_eq!(attempts[0]["status"], "done");
+}
+
+#[actix_web::test]
+async fn a_used_up_quota_refuses_the_first_run_of_the_day() {
+    let Some(harness) = common::setup_with(|config| {
+        config.limits.daily_runs = 0;
+    }) else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("ivy")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("print(0)"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id = created["id"].as_str().unwrap();
+
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", id))
+        .cookie(cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(harness.jobs.take().is_none());
+
+    let req = TestRequest::get()
+        .uri("/api/user/quota/")
+        .cookie(cookie)
+        .to_request();
+    let quota: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(quota["runs_limit"], 0);
+    assert_eq!(quota["runs_remaining"], 0);
+}
+
+#[actix_web::test]
+async fn lost_runs_are_retried_then_timed_out() {
+    let Some(harness) = common::setup_with(|config| {
+        config.jobs.timeout_seconds = 0;
+        config.jobs.max_attempts = 2;
+    }) else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("kate")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("print(3)"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let uri = format!("/api/submissions/{}/", id);
+
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", id))
+        .cookie(cookie)
+        .to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(harness.jobs.take().unwrap().attempt, 1);
+
+    let config = &harness.state.config.jobs;
+    jobs::reap(harness.jobs.as_ref(), config).await.unwrap();
+    let retried = harness
+        .jobs
+        .take()
+        .expect("the run was not dispatched again");
+    assert_eq!(retried.id, id);
+    assert_eq!(retried.attempt, 2);
+
+    jobs::reap(harness.jobs.as_ref(), config).await.unwrap();
+    assert!(harness.jobs.take().is_none());
+
+    // Too late: the run has been given up on
+    harness
+        .jobs
+        .report(JobStatus::new(id, "done"))
+        .await
+        .unwrap();
+
+    let timed_out: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(timed_out["status"], "timed_out");
+    assert_eq!(timed_out["error"], "No result after 2 attempts");
+
+    let attempts: Vec<Value> = test::call_and_read_body_json(
+        &app,
+        TestRequest::get()
+            .uri(&format!("{}attempts/", uri))
+            .to_request(),
+    )
+    .await;
+    let statuses: Vec<_> = attempts.iter().map(|attempt| &attempt["status"]).collect();
+    assert_eq!(statuses, ["timed_out", "timed_out"]);
+}
+
+#[actix_web::test]
+async fn runs_carry_their_input_and_retries_keep_it() {
+    let Some(harness) = common::setup_with(|config| {
+        config.jobs.timeout_seconds = 0;
+        config.jobs.max_attempts = 2;
+    }) else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("lena")).to_request()).await);
+
+    let mut input = submission("import sys; print(sys.argv[1], input())");
+    input["stdin"] = json!("42\n");
+    input["args"] = json!(["--answer"]);
+    input["env"] = json!(["TZ=UTC"]);
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(&input)
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["args"], json!(["--answer"]));
+    let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let uri = format!("/api/submissions/{}/", id);
+
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", id))
+        .cookie(cookie.clone())
+        .to_request();
+    test::call_service(&app, req).await;
+    let job = harness.jobs.take().expect("no job was dispatched");
+    assert_eq!(job.stdin, "42\n");
+    assert_eq!(job.args, ["--answer"]);
+    assert_eq!(job.env, ["TZ=UTC"]);
+
+    // An edit while the run is out does not change what it is retried with
+    input["args"] = json!(["--other"]);
+    let req = TestRequest::put()
+        .uri(&uri)
+        .cookie(cookie.clone())
+        .set_json(&input)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    jobs::reap(harness.jobs.as_ref(), &harness.state.config.jobs)
+        .await
+        .unwrap();
+    let retried = harness
+        .jobs
+        .take()
+        .expect("the run was not dispatched again");
+    assert_eq!(retried.args, ["--answer"]);
+
+    let attempts: Vec<Value> = test::call_and_read_body_json(
+        &app,
+        TestRequest::get()
+            .uri(&format!("{}attempts/", uri))
+            .to_request(),
+    )
+    .await;
+    let args: Vec<_> = attempts
+        .iter()
+        .map(|attempt| attempt["args"].clone())
+        .collect();
+    assert_eq!(args, [json!(["--answer"]), json!(["--answer"])]);
+
+    for env in ["LD_PRELOAD=/tmp/evil.so", "TZ"] {
+        input["env"] = json!([env]);
+        let req = TestRequest::put()
+            .uri(&uri)
+            .cookie(cookie.clone())
+            .set_json(&input)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+}
+
+#[actix_web::test]
+async fn only_the_author_can_run_a_submission() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let author = session(&test::call_service(&app, register(&unique("ivan")).to_request()).await);
+    let other = session(&test::call_service(&app, register(&unique("judy")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(author)
+        .set_json(submission("print(2)"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+
+    let req = TestRequest::post()
+        .uri(&format!(
+            "/api/submissions/run/{}/",
+            created["id"].as_str().unwrap()
+        ))
+        .cookie(other)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert!(harness.jobs.take().is_none());
+}
+
+#[actix_web::test]
+async fn queued_runs_are_cancelled_outright() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("liam")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("while True: pass"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let cancel_uri = format!("/api/submissions/{}/cancel/", id);
+
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", id))
+        .cookie(cookie.clone())
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let req = TestRequest::post()
+        .uri(&cancel_uri)
+        .cookie(cookie.clone())
+        .to_request();
+    let cancelled: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(cancelled["status"], "cancelled");
+    assert!(harness.jobs.take().is_none());
+    assert_eq!(harness.jobs.cancelled(), [(id, 1)]);
+
+    let req = TestRequest::post()
+        .uri(&cancel_uri)
+        .cookie(cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let attempts: Vec<Value> = test::call_and_read_body_json(
+        &app,
+        TestRequest::get()
+            .uri(&format!("/api/submissions/{}/attempts/", id))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(attempts[0]["status"], "cancelled");
+}
+
+#[actix_web::test]
+async fn started_runs_are_cancelled_once_the_worker_confirms() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("mona")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("while True: pass"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let uri = format!("/api/submissions/{}/", id);
+
+    let req = TestRequest::post()
+        .uri(&format!("/api/submissions/run/{}/", id))
+        .cookie(cookie.clone())
+        .to_request();
+    test::call_service(&app, req).await;
+    harness.jobs.take().unwrap();
+    harness
+        .jobs
+        .report(JobStatus::new(id, "running"))
+        .await
+        .unwrap();
+
+    let req = TestRequest::post()
+        .uri(&format!("{}cancel/", uri))
+        .cookie(cookie)
+        .to_request();
+    let cancelling: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(cancelling["status"], "cancelling");
+    assert_eq!(harness.jobs.cancelled(), [(id, 1)]);
+
+    // Progress the worker reported before it heard of the cancellation
+    harness
+        .jobs
+        .report(JobStatus::new(id, "running"))
+        .await
+        .unwrap();
+    let still: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(still["status"], "cancelling");
+
+    harness
+        .jobs
+        .report(JobStatus::new(id, "cancelled"))
+        .await
+        .unwrap();
+    let cancelled: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(cancelled["status"], "cancelled");
+}
+
+#[actix_web::test]
+async fn runs_again_after_a_cancel_are_left_alone_by_it() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("nell")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(submission("print(1)"))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+    let uri = format!("/api/submissions/{}/", id);
+    let run = || {
+        TestRequest::post()
+            .uri(&format!("/api/submissions/run/{}/", id))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    test::call_service(&app, run()).await;
+    let req = TestRequest::post()
+        .uri(&format!("{}cancel/", uri))
+        .cookie(cookie.clone())
+        .to_request();
+    let cancelled: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(cancelled["status"], "cancelled");
+
+    assert_eq!(
+        test::call_service(&app, run()).await.status(),
+        StatusCode::OK
+    );
+    let job = harness.jobs.take().unwrap();
+    assert_eq!(job.attempt, 2);
+
+    // A worker that remembered the cancellation drops the first attempt's job
+    // only now, and says so
+    harness
+        .jobs
+        .report(JobStatus {
+            attempt: Some(1),
+            ..JobStatus::new(id, "cancelled")
+        })
+        .await
+        .unwrap();
+    let queued: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(queued["status"], "queued");
+
+    harness
+        .jobs
+        .report(JobStatus {
+            attempt: Some(2),
+            ..JobStatus::new(id, "done")
+        })
+        .await
+        .unwrap();
+    let done: Value =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(done["status"], "done");
+}
+
+#[actix_web::test]
+async fn contest_runs_go_first_and_bulk_runs_step_back() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let author = session(&test::call_service(&app, register(&unique("nina")).to_request()).await);
+    let contestant =
+        session(&test::call_service(&app, register(&unique("omar")).to_request()).await);
+    let bulk = session(&test::call_service(&app, register(&unique("pia")).to_request()).await);
+    let casual = session(&test::call_service(&app, register(&unique("quin")).to_request()).await);
+    let res = test::call_service(&app, register(&unique("rhea")).to_request()).await;
+    let admin = session(&res);
+    let admin_id: Value = test::read_body_json(res).await;
+    promote(admin_id["id"].as_str().unwrap());
+
+    // Authors cannot make their own benchmark a contest
+    let req = TestRequest::post()
+        .uri("/api/benchmarks/")
+        .cookie(author.clone())
+        .set_json(json!({
+            "title": "Sorting",
+            "subject": "Sort a list of integers",
+            "difficulty": "easy",
+            "git_url": null,
+            "max_cyclomatic_complex": 10,
+            "contest": true,
+        }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["contest"], false);
+    let contest_uri = format!(
+        "/api/admin/benchmarks/{}/contest/",
+        created["id"].as_str().unwrap()
+    );
+    let now = Utc::now().naive_utc();
+    let open = json!({
+        "contest": true,
+        "opens_at": now - Duration::hours(1),
+        "closes_at": now + Duration::hours(1),
+    });
+    let req = TestRequest::put()
+        .uri(&contest_uri)
+        .cookie(author.clone())
+        .set_json(&open)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    // A contest nobody scheduled would be a fast lane for good
+    let req = TestRequest::put()
+        .uri(&contest_uri)
+        .cookie(admin.clone())
+        .set_json(json!({ "contest": true }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    let req = TestRequest::put()
+        .uri(&contest_uri)
+        .cookie(admin)
+        .set_json(&open)
+        .to_request();
+    let marked: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(marked["contest"], true);
+    let benchmark_id = created["id"].clone();
+
+    let mut queued = Vec::new();
+    let runs = [
+        (&bulk, Value::Null),
+        (&bulk, Value::Null),
+        (&bulk, Value::Null),
+        (&casual, Value::Null),
+        (&author, benchmark_id.clone()),
+        (&contestant, benchmark_id),
+    ];
+    for (cookie, benchmark_id) in runs {
+        let mut body = submission("print(1)");
+        body["benchmark_id"] = benchmark_id;
+        let req = TestRequest::post()
+            .uri("/api/submissions/")
+            .cookie(cookie.clone())
+            .set_json(body)
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+
+        let req = TestRequest::post()
+            .uri(&format!("/api/submissions/run/{}/", id))
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        queued.push(id);
+    }
+
+    let taken: Vec<_> = std::iter::from_fn(|| harness.jobs.take())
+        .map(|job| (job.id, job.priority))
+        .collect();
+    let [bulk1, bulk2, bulk3, casual, grading, contest] = queued[..] else {
+        unreachable!();
+    };
+    assert_eq!(
+        taken,
+        [
+            (contest, 9),
+            (grading, 6),
+            (bulk1, 3),
+            (casual, 3),
+            (bulk2, 2),
+            (bulk3, 1),
+        ]
+    );
+
+    let attempts: Vec<Value> = test::call_and_read_body_json(
+        &app,
+        TestRequest::get()
+            .uri(&format!("/api/submissions/{}/attempts/", grading))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(attempts[0]["run_class"], "grading");
+    assert_eq!(attempts[0]["priority"], 6);
+}
@@ -0,0 +1,297 @@
+mod common;
+
+use actix_web::cookie::Cookie;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{self, TestRequest};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use backend::config::OAuthProviderConfig;
+use common::{register, session, unique, TestApp};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+
+/// An app with the mock issuer configured as `mock`, and as `trusted`, which
+/// takes emails as verified.
+fn setup() -> Option<TestApp> {
+    let issuer = mock_issuer();
+
+    common::setup_with(|config| {
+        for (name, trust_email) in [("mock", false), ("trusted", true)] {
+            let provider = OAuthProviderConfig {
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                authorization_url: Some(format!("{}/authorize", issuer)),
+                token_url: Some(format!("{}/token", issuer)),
+                userinfo_url: Some(format!("{}/userinfo", issuer)),
+                trust_email,
+                ..OAuthProviderConfig::default()
+            };
+            config.oauth.providers.insert(name.to_string(), provider);
+        }
+    })
+}
+
+/// An OAuth provider on a port of its own. It hands the authorization code back
+/// as the access token, and the code is the profile to answer userinfo with, as
+/// a query string: `sub=..&email=..&email_verified=true`.
+fn mock_issuer() -> &'static str {
+    static ISSUER: OnceLock<String> = OnceLock::new();
+
+    ISSUER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            actix_rt::System::new().block_on(async move {
+                let server = HttpServer::new(|| {
+                    App::new()
+                        .route("/token", web::post().to(token))
+                        .route("/userinfo", web::get().to(userinfo))
+                })
+                .workers(1)
+                .bind("127.0.0.1:0")
+                .expect("failed to bind the mock issuer");
+                tx.send(server.addrs()[0]).unwrap();
+                server.run().await
+            })
+        });
+        format!("http://{}", rx.recv().unwrap())
+    })
+}
+
+async fn token(form: web::Form<HashMap<String, String>>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "access_token": form["code"] }))
+}
+
+async fn userinfo(req: HttpRequest) -> HttpResponse {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    let profile: HashMap<String, String> = serde_urlencoded::from_str(token).unwrap();
+
+    let claims: Map<String, Value> = profile
+        .into_iter()
+        .map(|(claim, value)| match value.parse::<bool>() {
+            Ok(flag) => (claim, Value::Bool(flag)),
+            Err(_) => (claim, Value::String(value)),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(claims)
+}
+
+/// The state the login redirect sent to the provider, and the cookie bound to it.
+fn started<B>(res: &ServiceResponse<B>) -> (String, Cookie<'static>) {
+    assert_eq!(res.status(), StatusCode::FOUND);
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let query = location.split_once('?').unwrap().1;
+    let params: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+    let cookie = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "oauth_state")
+        .expect("login did not set the state cookie")
+        .into_owned();
+
+    (params["state"].clone(), cookie)
+}
+
+fn callback(state: &str, profile: &[(&str, &str)]) -> TestRequest {
+    callback_from("mock", state, profile)
+}
+
+fn callback_from(provider: &str, state: &str, profile: &[(&str, &str)]) -> TestRequest {
+    let code = serde_urlencoded::to_string(profile).unwrap();
+    let query = serde_urlencoded::to_string([("code", code.as_str()), ("state", state)]).unwrap();
+
+    TestRequest::get().uri(&format!("/api/auth/{}/callback/?{}", provider, query))
+}
+
+#[actix_web::test]
+async fn callbacks_only_complete_in_the_browser_that_started_them() {
+    let Some(harness) = setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let name = unique("yuri");
+    let email = format!("{}@example.com", name);
+    let profile = [
+        ("sub", name.as_str()),
+        ("email", email.as_str()),
+        ("email_verified", "true"),
+    ];
+
+    let res = test::call_service(
+        &app,
+        TestRequest::get().uri("/api/auth/mock/login/").to_request(),
+    )
+    .await;
+    let (state, cookie) = started(&res);
+    assert!(cookie.http_only().unwrap_or(false));
+
+    // A callback URL handed to someone else, or with a cookie for another state
+    let res = test::call_service(&app, callback(&state, &profile).to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = test::call_service(
+        &app,
+        TestRequest::get().uri("/api/auth/mock/login/").to_request(),
+    )
+    .await;
+    let (_, other_cookie) = started(&res);
+    let res = test::call_service(
+        &app,
+        callback(&state, &profile).cookie(other_cookie).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let mut forged = cookie.clone();
+    forged.set_value(state.clone());
+    let res =
+        test::call_service(&app, callback(&state, &profile).cookie(forged).to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = test::call_service(
+        &app,
+        callback(&state, &profile)
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    session(&res);
+    let user: Value = test::read_body_json(res).await;
+    assert_eq!(user["email"], email.as_str());
+
+    // and each state completes once
+    let res =
+        test::call_service(&app, callback(&state, &profile).cookie(cookie).to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn accounts_are_linked_only_from_the_session_that_asked() {
+    let Some(harness) = setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let name = unique("zoe");
+    let owner = session(&test::call_service(&app, register(&name).to_request()).await);
+    let other = session(&test::call_service(&app, register(&unique("abe")).to_request()).await);
+    let subject = unique("zoe-ext");
+    let profile = [("sub", subject.as_str())];
+
+    let link = || {
+        TestRequest::get()
+            .uri("/api/auth/mock/login/")
+            .cookie(owner.clone())
+            .to_request()
+    };
+
+    // Without the session, the holder of the URL would be signed in as the owner
+    let (state, cookie) = started(&test::call_service(&app, link()).await);
+    let res =
+        test::call_service(&app, callback(&state, &profile).cookie(cookie).to_request()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let (state, cookie) = started(&test::call_service(&app, link()).await);
+    let res = test::call_service(
+        &app,
+        callback(&state, &profile)
+            .cookie(cookie)
+            .cookie(other)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let (state, cookie) = started(&test::call_service(&app, link()).await);
+    let res = test::call_service(
+        &app,
+        callback(&state, &profile)
+            .cookie(cookie)
+            .cookie(owner.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let user: Value = test::read_body_json(res).await;
+    assert_eq!(user["username"], name.as_str());
+
+    let identities: Vec<Value> = test::call_and_read_body_json(
+        &app,
+        TestRequest::get()
+            .uri("/api/users/identities/")
+            .cookie(owner)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0]["subject"], subject.as_str());
+}
+
+#[actix_web::test]
+async fn emails_are_only_taken_when_verified_or_trusted() {
+    let Some(harness) = setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+
+    for (provider, status) in [
+        ("mock", StatusCode::UNPROCESSABLE_ENTITY),
+        ("trusted", StatusCode::OK),
+    ] {
+        let name = unique("bea");
+        let email = format!("{}@example.com", name);
+        let profile = [("sub", name.as_str()), ("email", email.as_str())];
+
+        let res = test::call_service(
+            &app,
+            TestRequest::get()
+                .uri(&format!("/api/auth/{}/login/", provider))
+                .to_request(),
+        )
+        .await;
+        let (state, cookie) = started(&res);
+        let res = test::call_service(
+            &app,
+            callback_from(provider, &state, &profile)
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), status, "{}", provider);
+    }
+
+    // An explicit `false` holds even for a trusted provider
+    let name = unique("cal");
+    let email = format!("{}@example.com", name);
+    let profile = [
+        ("sub", name.as_str()),
+        ("email", email.as_str()),
+        ("email_verified", "false"),
+    ];
+    let res = test::call_service(
+        &app,
+        TestRequest::get()
+            .uri("/api/auth/trusted/login/")
+            .to_request(),
+    )
+    .await;
+    let (state, cookie) = started(&res);
+    let res = test::call_service(
+        &app,
+        callback_from("trusted", &state, &profile)
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
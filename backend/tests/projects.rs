@@ -0,0 +1,198 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use common::{register, session, unique};
+use serde_json::{json, Value};
+
+fn project(entrypoint: &str, files: Value) -> Value {
+    json!({
+        "language": "python",
+        "code": "",
+        "status": "draft",
+        "benchmark_id": null,
+        "stdout": null,
+        "stderr": null,
+        "exec_duration": 0,
+        "message": null,
+        "error": null,
+        "lint_score": null,
+        "quality_score": null,
+        "mem_usage": 0,
+        "cyclomatic_complexity": 0,
+        "project": { "entrypoint": entrypoint, "files": files },
+    })
+}
+
+#[actix_web::test]
+async fn projects_are_edited_file_by_file_and_run_as_a_tree() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let owner = session(&test::call_service(&app, register(&unique("vera")).to_request()).await);
+    let other = session(&test::call_service(&app, register(&unique("walt")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(owner.clone())
+        .set_json(project(
+            "main.py",
+            json!([
+                { "path": "main.py", "content": "from pkg import util\nutil.hello()\n" },
+                { "path": "pkg/util.py", "content": "def hello(): print('hi')\n" },
+            ]),
+        ))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["entrypoint"], "main.py");
+    let files_uri = format!(
+        "/api/submissions/{}/files/",
+        created["id"].as_str().unwrap()
+    );
+
+    let files: Vec<Value> =
+        test::call_and_read_body_json(&app, TestRequest::get().uri(&files_uri).to_request()).await;
+    let paths: Vec<_> = files.iter().map(|file| &file["path"]).collect();
+    assert_eq!(paths, ["main.py", "pkg/util.py"]);
+
+    let req = TestRequest::put()
+        .uri(&format!("{}pkg/__init__.py", files_uri))
+        .cookie(owner.clone())
+        .set_json(json!({ "content": "" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = TestRequest::put()
+        .uri(&format!("{}pkg/util.py", files_uri))
+        .cookie(owner.clone())
+        .set_json(json!({ "content": "def hello(): print('hello')\n" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let util: Value = test::call_and_read_body_json(
+        &app,
+        TestRequest::get()
+            .uri(&format!("{}pkg/util.py", files_uri))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(util["content"], "def hello(): print('hello')\n");
+
+    // Only the owner may change the files, and the entrypoint has to stay
+    let req = TestRequest::put()
+        .uri(&format!("{}evil.py", files_uri))
+        .cookie(other)
+        .set_json(json!({ "content": "" }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    let req = TestRequest::delete()
+        .uri(&format!("{}main.py", files_uri))
+        .cookie(owner.clone())
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+    let req = TestRequest::delete()
+        .uri(&format!("{}pkg/__init__.py", files_uri))
+        .cookie(owner.clone())
+        .to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(deleted["deleted"], 1);
+
+    let req = TestRequest::post()
+        .uri(&format!(
+            "/api/submissions/run/{}/",
+            created["id"].as_str().unwrap()
+        ))
+        .cookie(owner)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let job = harness.jobs.take().expect("no job was dispatched");
+    assert_eq!(job.entrypoint.as_deref(), Some("main.py"));
+    let tree: Vec<_> = job
+        .files
+        .iter()
+        .map(|file| (file.path.as_str(), file.content.as_str()))
+        .collect();
+    assert_eq!(
+        tree,
+        [
+            ("main.py", "from pkg import util\nutil.hello()\n"),
+            ("pkg/util.py", "def hello(): print('hello')\n"),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn project_trees_are_checked() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("xena")).to_request()).await);
+
+    let invalid = [
+        project("main.py", json!([{ "path": "../main.py", "content": "" }])),
+        project(
+            "/etc/passwd",
+            json!([{ "path": "/etc/passwd", "content": "" }]),
+        ),
+        project("main.py", json!([{ "path": "util.py", "content": "" }])),
+        project(
+            "main.py",
+            json!([
+                { "path": "main.py", "content": "" },
+                { "path": "main.py", "content": "" },
+            ]),
+        ),
+        project(
+            "main.py",
+            json!([{ "path": "main.py", "content": "x".repeat(128 * 1024 + 1) }]),
+        ),
+        project(
+            "main.py",
+            json!([
+                { "path": "main.py", "content": "" },
+                { "path": "lib", "content": "" },
+                { "path": "lib/util.py", "content": "" },
+            ]),
+        ),
+    ];
+    for submission in invalid {
+        let req = TestRequest::post()
+            .uri("/api/submissions/")
+            .cookie(cookie.clone())
+            .set_json(submission)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    // A submission saved without a project has no tree to add to
+    let mut single = project("main.py", json!([]));
+    single["project"] = Value::Null;
+    single["code"] = json!("print(1)");
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(single)
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let req = TestRequest::put()
+        .uri(&format!(
+            "/api/submissions/{}/files/util.py",
+            created["id"].as_str().unwrap()
+        ))
+        .cookie(cookie)
+        .set_json(json!({ "content": "" }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+}
@@ -0,0 +1,228 @@
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{self, TestRequest};
+use backend::config::RouteLimit;
+use backend::jobs;
+use backend::models::JobStatus;
+use common::{register, session, unique};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[actix_web::test]
+async fn scratch_runs_can_be_polled_by_their_owner() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let owner = session(&test::call_service(&app, register(&unique("rita")).to_request()).await);
+    let other = session(&test::call_service(&app, register(&unique("sam")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/run/")
+        .cookie(owner.clone())
+        .set_json(json!({
+            "language": "python",
+            "code": "print(input())",
+            "stdin": "hi\n",
+            "args": ["--loud"],
+            "env": ["TZ=UTC"],
+            "wait_seconds": 0,
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let queued: Value = test::read_body_json(res).await;
+    assert_eq!(queued["status"], "queued");
+    assert_eq!(
+        location,
+        format!("/api/run/{}/", queued["id"].as_str().unwrap())
+    );
+
+    let job = harness.jobs.take().expect("no job was dispatched");
+    assert_eq!(job.code, "print(input())");
+    assert_eq!(job.stdin, "hi\n");
+    assert_eq!(job.args, ["--loud"]);
+    assert_eq!(job.env, ["TZ=UTC"]);
+    harness
+        .jobs
+        .report(JobStatus {
+            stdout: "hi\n".to_string(),
+            exec_duration: 40,
+            ..JobStatus::new(job.id, "done")
+        })
+        .await
+        .unwrap();
+
+    let req = TestRequest::get()
+        .uri(&location)
+        .cookie(owner.clone())
+        .to_request();
+    let done: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(done["status"], "done");
+    assert_eq!(done["stdout"], "hi\n");
+
+    let req = TestRequest::get().uri(&location).cookie(other).to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    // Nothing was added to the submission history
+    let req = TestRequest::get()
+        .uri("/api/user/submissions/")
+        .cookie(owner)
+        .to_request();
+    let submissions: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(submissions.is_empty());
+}
+
+#[actix_web::test]
+async fn scratch_runs_answer_with_the_result_once_it_is_in() {
+    let Some(harness) = common::setup() else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("tara")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/run/")
+        .cookie(cookie)
+        .set_json(json!({ "language": "python", "code": "print(4)", "wait_seconds": 10 }))
+        .to_request();
+    // Plays the worker while the request waits
+    let worker = async {
+        let job = loop {
+            match harness.jobs.take() {
+                Some(job) => break job,
+                None => actix_rt::time::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        harness
+            .jobs
+            .report(JobStatus {
+                stdout: "4\n".to_string(),
+                ..JobStatus::new(job.id, "done")
+            })
+            .await
+            .unwrap();
+    };
+    let (res, ()) = futures::join!(test::call_service(&app, req), worker);
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let done: Value = test::read_body_json(res).await;
+    assert_eq!(done["status"], "done");
+    assert_eq!(done["stdout"], "4\n");
+}
+
+#[actix_web::test]
+async fn lost_scratch_runs_time_out() {
+    let Some(harness) = common::setup_with(|config| {
+        config.jobs.timeout_seconds = 0;
+    }) else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("uma")).to_request()).await);
+
+    let req = TestRequest::post()
+        .uri("/api/run/")
+        .cookie(cookie.clone())
+        .set_json(json!({ "language": "python", "code": "print(5)", "wait_seconds": 0 }))
+        .to_request();
+    let queued: Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/api/run/{}/", queued["id"].as_str().unwrap());
+    harness.jobs.take().unwrap();
+
+    jobs::reap(harness.jobs.as_ref(), &harness.state.config.jobs)
+        .await
+        .unwrap();
+    let req = TestRequest::get()
+        .uri(&uri)
+        .cookie(cookie.clone())
+        .to_request();
+    let timed_out: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(timed_out["status"], "timed_out");
+
+    // Refused before it could count against the quota
+    let req = TestRequest::post()
+        .uri("/api/run/")
+        .cookie(cookie)
+        .set_json(json!({ "language": "python", "code": "" }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+}
+
+#[actix_web::test]
+async fn each_run_route_has_its_own_rate_limit() {
+    let Some(harness) = common::setup_with(|config| {
+        config.limits.routes.insert(
+            "POST /api/run/".to_string(),
+            RouteLimit {
+                burst: 1,
+                refill_seconds: 3600,
+            },
+        );
+    }) else {
+        return;
+    };
+    let app = test::init_service(backend::app(&harness.state)).await;
+    let cookie = session(&test::call_service(&app, register(&unique("vic")).to_request()).await);
+
+    let scratch = || {
+        TestRequest::post()
+            .uri("/api/run/")
+            .cookie(cookie.clone())
+            .set_json(json!({ "language": "python", "code": "print(6)", "wait_seconds": 0 }))
+            .to_request()
+    };
+    assert_eq!(
+        test::call_service(&app, scratch()).await.status(),
+        StatusCode::ACCEPTED
+    );
+    let res = test::call_service(&app, scratch()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+
+    // Saved submissions keep the default burst
+    let req = TestRequest::post()
+        .uri("/api/submissions/")
+        .cookie(cookie.clone())
+        .set_json(json!({
+            "language": "python",
+            "code": "print(7)",
+            "status": "draft",
+            "benchmark_id": null,
+            "stdout": null,
+            "stderr": null,
+            "exec_duration": 0,
+            "message": null,
+            "error": null,
+            "lint_score": null,
+            "quality_score": null,
+            "mem_usage": 0,
+            "cyclomatic_complexity": 0,
+        }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    let req = TestRequest::post()
+        .uri(&format!(
+            "/api/submissions/run/{}/",
+            created["id"].as_str().unwrap()
+        ))
+        .cookie(cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "5");
+}
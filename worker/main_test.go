@@ -0,0 +1,89 @@
+package main
+
+import (
+	"errors"
+	"fmt"
+	"testing"
+
+	"github.com/streadway/amqp"
+)
+
+// Records how a delivery was settled
+type acknowledger struct {
+	acked   bool
+	nacked  bool
+	requeue bool
+}
+
+func (a *acknowledger) Ack(tag uint64, multiple bool) error {
+	a.acked = true
+	return nil
+}
+
+func (a *acknowledger) Nack(tag uint64, multiple bool, requeue bool) error {
+	a.nacked = true
+	a.requeue = requeue
+	return nil
+}
+
+func (a *acknowledger) Reject(tag uint64, requeue bool) error {
+	return a.Nack(tag, false, requeue)
+}
+
+func settled(err error) *acknowledger {
+	ack := &acknowledger{}
+	settle(amqp.Delivery{Acknowledger: ack, DeliveryTag: 1}, err)
+	return ack
+}
+
+func TestTransientFailuresAreDeadLetteredForRetry(t *testing.T) {
+	unreachable := transient(errors.New("connection refused"))
+
+	for _, err := range []error{unreachable, fmt.Errorf("running job: %w", unreachable)} {
+		ack := settled(err)
+		// Requeued, the job would go straight back to the queue's head; rejected,
+		// it goes through the dead-letter exchange to the delayed retries
+		if !ack.nacked || ack.requeue || ack.acked {
+			t.Errorf("%v: got %+v, want a nack without requeue", err, ack)
+		}
+	}
+}
+
+func TestRunJobsAreAcked(t *testing.T) {
+	for _, err := range []error{nil, errors.New("not worth a retry")} {
+		ack := settled(err)
+		if !ack.acked || ack.nacked {
+			t.Errorf("%v: got %+v, want an ack", err, ack)
+		}
+	}
+}
+
+func TestAmqpConfigMatchesTheBackendsDefaults(t *testing.T) {
+	config, err := loadAmqpConfig()
+	if err != nil {
+		t.Fatal(err)
+	}
+	if config.jobsQueue != "jobs_q" || config.deadLetterExchange != "jobs_dlx" || config.maxPriority != 9 {
+		t.Errorf("got %+v", config)
+	}
+}
+
+func TestAmqpConfigComesFromTheEnvironment(t *testing.T) {
+	t.Setenv("AMQP_DEAD_LETTER_EXCHANGE", "staging_dlx")
+	t.Setenv("AMQP_MAX_PRIORITY", "5")
+
+	config, err := loadAmqpConfig()
+	if err != nil {
+		t.Fatal(err)
+	}
+	if config.deadLetterExchange != "staging_dlx" || config.maxPriority != 5 {
+		t.Errorf("got %+v", config)
+	}
+
+	for _, priority := range []string{"0", "256", "high"} {
+		t.Setenv("AMQP_MAX_PRIORITY", priority)
+		if _, err := loadAmqpConfig(); err == nil {
+			t.Errorf("AMQP_MAX_PRIORITY=%s was accepted", priority)
+		}
+	}
+}